@@ -0,0 +1,63 @@
+// Access-unit assembly throughput over a recorded Annex-B stream.
+//
+// Runs against the checked-in sample under benches/data by default. Point
+// ANNEXB_BENCH_FILE at a capture (e.g. `ffmpeg ... -bsf:v
+// h264_metadata=aud=insert -f h264 capture.h264`) to measure full-size
+// encoder output instead.
+
+use std::{env, fs, path::Path, time::Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[allow(dead_code)]
+#[path = "../src/h264.rs"]
+mod h264;
+
+use h264::AccessUnitAssembler;
+
+// Two 15-frame GOPs of 160x90 constrained baseline at 30 fps, with AUDs and
+// SPS/PPS ahead of each IDR like the capture pump's ffmpeg output. Keyframes
+// are I_PCM and P frames skip all but a moving block, so it stays small while
+// still carrying emulation-prevention bytes.
+const SAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/data/pcm_160x90.h264");
+const SAMPLE_ACCESS_UNITS: usize = 30;
+
+fn load_stream() -> (String, Vec<u8>) {
+    let path = env::var("ANNEXB_BENCH_FILE").unwrap_or_else(|_| SAMPLE.to_owned());
+    let stream = fs::read(&path).unwrap_or_else(|err| panic!("read {path}: {err}"));
+    (path, stream)
+}
+
+fn assemble(stream: &[u8], chunk_size: usize) -> usize {
+    let mut assembler = AccessUnitAssembler::default();
+    let mut access_units = 0;
+    for chunk in stream.chunks(chunk_size) {
+        access_units += assembler.push(chunk, Instant::now()).len();
+    }
+    access_units + usize::from(assembler.finish(Instant::now()).is_some())
+}
+
+fn bench_assembler(c: &mut Criterion) {
+    let (path, stream) = load_stream();
+    if path == SAMPLE {
+        assert_eq!(assemble(&stream, 8192), SAMPLE_ACCESS_UNITS);
+    }
+    let name = Path::new(&path)
+        .file_name()
+        .map_or(path.clone(), |name| name.to_string_lossy().into_owned());
+
+    let mut group = c.benchmark_group(format!("annexb_assemble/{name}"));
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    // 8 KiB matches the capture pump's read size.
+    for chunk_size in [8192, 65_536] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_size),
+            &chunk_size,
+            |b, &chunk_size| b.iter(|| assemble(&stream, chunk_size)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_assembler);
+criterion_main!(benches);
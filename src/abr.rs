@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::config::AbrConfig;
+
+// Feedback older than this belongs to a viewer that left or reattached.
+const FEEDBACK_TTL: Duration = Duration::from_secs(10);
+// Feedback settles for a while before the target moves again.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+// Relative moves smaller than this keep the current bitrate.
+const MIN_CHANGE_RATIO: f64 = 0.1;
+// Loss thresholds (fraction of packets) of the loss-based controller.
+const LOSS_DECREASE: f64 = 0.1;
+const LOSS_INCREASE: f64 = 0.02;
+const INCREASE_FACTOR: f64 = 1.2;
+// Headroom kept below the receiver's REMB estimate.
+const REMB_HEADROOM: f64 = 0.9;
+
+struct ViewerFeedback {
+    remb_kbps: Option<f64>,
+    loss: f64,
+    updated: Instant,
+}
+
+// Picks the bitrate of one shared encoder from every viewer's REMB estimate
+// and reported loss, so the weakest viewer still gets a watchable stream.
+pub struct AbrController {
+    config: AbrConfig,
+    target_kbps: u32,
+    last_change: Instant,
+    viewers: HashMap<u64, ViewerFeedback>,
+}
+
+impl AbrController {
+    pub fn new(config: AbrConfig, initial_kbps: u32) -> Self {
+        let target_kbps = initial_kbps.clamp(config.min_kbps, config.max_kbps.max(config.min_kbps));
+        Self {
+            config,
+            target_kbps,
+            last_change: Instant::now(),
+            viewers: HashMap::new(),
+        }
+    }
+
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps
+    }
+
+    pub fn on_remb(&mut self, viewer: u64, bitrate_bps: f32) {
+        self.feedback(viewer).remb_kbps = Some(f64::from(bitrate_bps) / 1000.0);
+    }
+
+    pub fn on_loss(&mut self, viewer: u64, fraction_lost: u8) {
+        self.feedback(viewer).loss = f64::from(fraction_lost) / 256.0;
+    }
+
+    // Returns the new target once it moved far enough to matter.
+    pub fn poll(&mut self, now: Instant) -> Option<u32> {
+        if now.duration_since(self.last_change) < ADJUST_INTERVAL {
+            return None;
+        }
+        self.viewers
+            .retain(|_, feedback| now.duration_since(feedback.updated) < FEEDBACK_TTL);
+        if self.viewers.is_empty() {
+            return None;
+        }
+
+        let worst_loss = self
+            .viewers
+            .values()
+            .map(|feedback| feedback.loss)
+            .fold(0.0, f64::max);
+        let current = f64::from(self.target_kbps);
+        let mut candidate = if worst_loss > LOSS_DECREASE {
+            current * (1.0 - 0.5 * worst_loss)
+        } else if worst_loss < LOSS_INCREASE {
+            current * INCREASE_FACTOR
+        } else {
+            current
+        };
+        if let Some(remb_kbps) = self
+            .viewers
+            .values()
+            .filter_map(|feedback| feedback.remb_kbps)
+            .reduce(f64::min)
+        {
+            candidate = candidate.min(remb_kbps * REMB_HEADROOM);
+        }
+
+        let max_kbps = self.config.max_kbps.max(self.config.min_kbps);
+        let next = (candidate as u32).clamp(self.config.min_kbps, max_kbps);
+        if (f64::from(next) - current).abs() < current * MIN_CHANGE_RATIO {
+            return None;
+        }
+        self.target_kbps = next;
+        self.last_change = now;
+        Some(next)
+    }
+
+    fn feedback(&mut self, viewer: u64) -> &mut ViewerFeedback {
+        let feedback = self.viewers.entry(viewer).or_insert(ViewerFeedback {
+            remb_kbps: None,
+            loss: 0.0,
+            updated: Instant::now(),
+        });
+        feedback.updated = Instant::now();
+        feedback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(initial_kbps: u32) -> (AbrController, Instant) {
+        let config = AbrConfig {
+            enabled: true,
+            min_kbps: 500,
+            max_kbps: 6_000,
+        };
+        let controller = AbrController::new(config, initial_kbps);
+        let settled = controller.last_change + ADJUST_INTERVAL;
+        (controller, settled)
+    }
+
+    #[test]
+    fn new_clamps_the_initial_target() {
+        assert_eq!(controller(100).0.target_kbps(), 500);
+        assert_eq!(controller(9_000).0.target_kbps(), 6_000);
+    }
+
+    #[test]
+    fn waits_for_the_adjust_interval() {
+        let (mut abr, settled) = controller(4_000);
+        abr.on_loss(1, 0);
+        assert_eq!(abr.poll(settled - Duration::from_millis(1)), None);
+        assert_eq!(abr.poll(settled), Some(4_800));
+        assert_eq!(abr.poll(settled + Duration::from_secs(1)), None);
+        assert_eq!(abr.poll(settled + ADJUST_INTERVAL), Some(5_760));
+        // Clamped to the maximum, which is too small a step to take.
+        assert_eq!(abr.poll(settled + 2 * ADJUST_INTERVAL), None);
+    }
+
+    #[test]
+    fn backs_off_in_proportion_to_loss() {
+        let (mut abr, settled) = controller(4_000);
+        // 64/256 = 25% loss.
+        abr.on_loss(1, 64);
+        assert_eq!(abr.poll(settled), Some(3_500));
+    }
+
+    #[test]
+    fn holds_between_loss_thresholds() {
+        let (mut abr, settled) = controller(4_000);
+        // 13/256, about 5% loss.
+        abr.on_loss(1, 13);
+        assert_eq!(abr.poll(settled), None);
+        assert_eq!(abr.target_kbps(), 4_000);
+    }
+
+    #[test]
+    fn follows_the_weakest_viewers_remb() {
+        let (mut abr, settled) = controller(4_000);
+        abr.on_remb(1, 5_000_000.0);
+        abr.on_remb(2, 1_000_000.0);
+        assert_eq!(abr.poll(settled), Some(900));
+    }
+
+    #[test]
+    fn never_drops_below_the_minimum() {
+        let (mut abr, settled) = controller(1_000);
+        abr.on_remb(1, 100_000.0);
+        assert_eq!(abr.poll(settled), Some(500));
+    }
+
+    #[test]
+    fn ignores_stale_or_missing_feedback() {
+        let (mut abr, settled) = controller(4_000);
+        assert_eq!(abr.poll(settled), None);
+
+        abr.on_loss(1, 128);
+        assert_eq!(abr.poll(settled + FEEDBACK_TTL), None);
+        assert_eq!(abr.target_kbps(), 4_000);
+    }
+}
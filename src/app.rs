@@ -1,13 +1,17 @@
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{get, patch, post},
     Router,
 };
 use tower_http::{services::ServeDir, trace::TraceLayer};
 
 use crate::{
     handlers::{
-        answer_handler, health, ice_candidate_handler, join_handler, leave_handler, offer_handler,
-        poll_handler,
+        answer_handler, encoders_handler, end_of_candidates_handler, health, ice_candidate_handler,
+        join_handler, leave_handler, offer_handler, poll_handler, require_admin,
+        save_replay_handler, start_recording_handler, stop_recording_handler, stream_stats_handler,
+        streams_handler, whep_delete_handler, whep_offer_handler, whep_trickle_handler,
+        whip_delete_handler, whip_offer_handler, whip_trickle_handler,
     },
     state::AppState,
 };
@@ -15,13 +19,52 @@ use crate::{
 pub fn build_router(state: AppState) -> Router {
     Router::new()
         .route("/health", get(health))
+        .merge(admin_router(&state))
         .route("/signal/join", post(join_handler))
         .route("/signal/leave", post(leave_handler))
         .route("/signal/offer", post(offer_handler))
         .route("/signal/answer", post(answer_handler))
         .route("/signal/ice_candidate", post(ice_candidate_handler))
+        .route("/signal/end_of_candidates", post(end_of_candidates_handler))
         .route("/signal/poll", get(poll_handler))
+        .route("/whep/{source}", post(whep_offer_handler))
+        .route(
+            "/whep/{source}/{resource_id}",
+            patch(whep_trickle_handler).delete(whep_delete_handler),
+        )
+        .merge(whip_router(&state))
         .fallback_service(ServeDir::new("public"))
         .with_state(state)
         .layer(TraceLayer::new_for_http())
 }
+
+fn admin_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/encoders", get(encoders_handler))
+        .route("/admin/streams", get(streams_handler))
+        .route("/admin/streams/{id}/stats", get(stream_stats_handler))
+        .route(
+            "/admin/streams/{id}/recording/start",
+            post(start_recording_handler),
+        )
+        .route(
+            "/admin/streams/{id}/recording/stop",
+            post(stop_recording_handler),
+        )
+        .route("/admin/streams/{id}/replay", post(save_replay_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+}
+
+// Publishing replaces a room's capture for its viewers, so WHIP is only
+// served once WHIP_TOKEN says who may publish.
+fn whip_router(state: &AppState) -> Router<AppState> {
+    if !state.media_bridge.accepts_publishers() {
+        return Router::new();
+    }
+    Router::new()
+        .route("/whip/{room}", post(whip_offer_handler))
+        .route(
+            "/whip/{room}/{resource_id}",
+            patch(whip_trickle_handler).delete(whip_delete_handler),
+        )
+}
@@ -0,0 +1,376 @@
+use std::{process::Stdio, sync::Arc};
+
+use bytes::Bytes;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{broadcast, mpsc, Mutex},
+    time::Instant,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    capture::{self, CaptureStream},
+    config::{AudioConfig, SupervisorConfig},
+    loopback,
+    pipeline::RESTART_RESET_AFTER,
+};
+
+// Opus packets (20 ms each) a slow viewer may fall behind before dropping.
+const AUDIO_BUFFER: usize = 50;
+pub const OPUS_FRAME_MS: u64 = 20;
+// PCM chunks (about 10 ms each) queued between the loopback thread and
+// ffmpeg's stdin.
+const LOOPBACK_BUFFER: usize = 32;
+
+// System audio encoded to Opus once and shared by every viewer. Starts with
+// the first subscriber and stops once the last one is gone.
+#[derive(Default)]
+pub struct AudioPipeline {
+    running: Mutex<Option<broadcast::Sender<Bytes>>>,
+}
+
+impl AudioPipeline {
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        config: &AudioConfig,
+        supervisor: &SupervisorConfig,
+    ) -> Result<broadcast::Receiver<Bytes>, String> {
+        let mut running = self.running.lock().await;
+        if let Some(packets) = running.as_ref() {
+            return Ok(packets.subscribe());
+        }
+
+        let capture_stream = start_capture(config).await?;
+        let (packets, receiver) = broadcast::channel(AUDIO_BUFFER);
+        *running = Some(packets.clone());
+
+        let pipeline = self.clone();
+        let source = config.source.clone();
+        let config = config.clone();
+        let supervisor = supervisor.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pipeline
+                .supervise(&config, &supervisor, capture_stream, &packets)
+                .await
+            {
+                error!("ffmpeg_audio failed source={} error={err}", config.source);
+            }
+            pipeline.retire(&packets).await;
+            info!("ffmpeg_audio closed source={}", config.source);
+        });
+
+        info!("ffmpeg_audio spawned source={source}");
+        Ok(receiver)
+    }
+
+    async fn retire(&self, packets: &broadcast::Sender<Bytes>) {
+        let mut running = self.running.lock().await;
+        if running
+            .as_ref()
+            .is_some_and(|current| current.same_channel(packets))
+        {
+            *running = None;
+        }
+    }
+
+    // Drops the capture once nobody listens. Checked under the lock so a
+    // viewer subscribing concurrently keeps it alive.
+    async fn release_if_idle(&self, packets: &broadcast::Sender<Bytes>) -> bool {
+        let mut running = self.running.lock().await;
+        if packets.receiver_count() > 0 {
+            return false;
+        }
+        if running
+            .as_ref()
+            .is_some_and(|current| current.same_channel(packets))
+        {
+            *running = None;
+        }
+        true
+    }
+
+    // Same policy as the video pipelines: a crashed, stalled or vanished
+    // capture (e.g. the loopback endpoint was unplugged) is restarted with
+    // exponential backoff behind the same broadcast channel, so viewers'
+    // audio tracks stay up. Fails once the retry budget is spent.
+    async fn supervise(
+        &self,
+        config: &AudioConfig,
+        supervisor: &SupervisorConfig,
+        mut capture_stream: CaptureStream,
+        packets: &broadcast::Sender<Bytes>,
+    ) -> Result<(), String> {
+        let mut restarts = 0_u32;
+        loop {
+            let started_at = Instant::now();
+            let mut reason = match self.pump(config, supervisor, capture_stream, packets).await {
+                Ok(()) => return Ok(()),
+                Err(reason) => reason,
+            };
+            if started_at.elapsed() >= RESTART_RESET_AFTER {
+                restarts = 0;
+            }
+            capture_stream = loop {
+                if restarts >= supervisor.restart_attempts {
+                    return Err(format!("gave up after {restarts} restarts: {reason}"));
+                }
+                let delay = supervisor.backoff(restarts);
+                restarts += 1;
+                warn!(
+                    "ffmpeg_audio_restart source={} attempt={restarts} delay_ms={} reason={reason}",
+                    config.source,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                if self.release_if_idle(packets).await {
+                    info!("ffmpeg_audio idle");
+                    return Ok(());
+                }
+                match start_capture(config).await {
+                    Ok(next) => break next,
+                    Err(err) => reason = err,
+                }
+            };
+        }
+    }
+
+    async fn pump(
+        &self,
+        config: &AudioConfig,
+        supervisor: &SupervisorConfig,
+        mut capture_stream: CaptureStream,
+        packets: &broadcast::Sender<Bytes>,
+    ) -> Result<(), String> {
+        let result = self
+            .pump_stream(config, supervisor, &mut capture_stream, packets)
+            .await;
+        capture_stream.stop().await;
+        result
+    }
+
+    async fn pump_stream(
+        &self,
+        config: &AudioConfig,
+        supervisor: &SupervisorConfig,
+        capture_stream: &mut CaptureStream,
+        packets: &broadcast::Sender<Bytes>,
+    ) -> Result<(), String> {
+        let mut buf = [0_u8; 4096];
+        let mut demuxer = OggDemuxer::default();
+        let stall_timeout = supervisor.stall_timeout;
+        let stall = tokio::time::sleep(stall_timeout);
+        tokio::pin!(stall);
+        loop {
+            let read = tokio::select! {
+                _ = &mut stall => {
+                    return Err(format!("no audio for {}s", stall_timeout.as_secs_f32()));
+                }
+                read = capture_stream.read(&mut buf) => {
+                    read.map_err(|err| format!("ffmpeg audio read failed: {err}"))?
+                }
+            };
+            if read == 0 {
+                capture_stream.wait_exit().await?;
+                // Only a file ends on its own; live capture stopping means
+                // the device or the loopback went away.
+                if config.source == "file" {
+                    return Ok(());
+                }
+                return Err("audio capture ended".to_owned());
+            }
+            for packet in demuxer.push(&buf[..read])? {
+                stall.as_mut().reset(Instant::now() + stall_timeout);
+                if packets.send(packet).is_err() && self.release_if_idle(packets).await {
+                    info!("ffmpeg_audio idle");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn start_capture(config: &AudioConfig) -> Result<CaptureStream, String> {
+    let mut cmd = capture::ffmpeg_command();
+    let mut loopback_pcm = None;
+    match config.source.as_str() {
+        "wasapi" => {
+            let (pcm, pcm_rx) = mpsc::channel(LOOPBACK_BUFFER);
+            loopback::start(config.device.clone(), pcm).await?;
+            loopback_pcm = Some(pcm_rx);
+            cmd.arg("-f")
+                .arg("wav")
+                .arg("-ignore_length")
+                .arg("1")
+                .arg("-i")
+                .arg("pipe:0")
+                .stdin(Stdio::piped());
+        }
+        "dshow" => {
+            let device = config.device.as_deref().ok_or_else(|| {
+                "AUDIO_SOURCE=dshow needs AUDIO_DEVICE; AUDIO_SOURCE=wasapi captures system \
+                 audio without a capture device"
+                    .to_owned()
+            })?;
+            ensure_dshow_device(device).await?;
+            cmd.arg("-f")
+                .arg("dshow")
+                .arg("-audio_buffer_size")
+                .arg("20")
+                .arg("-i")
+                .arg(format!("audio={device}"));
+        }
+        "pulse" => {
+            let device = config.device.as_deref().unwrap_or("@DEFAULT_MONITOR@");
+            cmd.arg("-f")
+                .arg("pulse")
+                .arg("-fragment_size")
+                .arg("3840")
+                .arg("-i")
+                .arg(device);
+        }
+        "tone" => {
+            cmd.arg("-re")
+                .arg("-f")
+                .arg("lavfi")
+                .arg("-i")
+                .arg("sine=frequency=440:sample_rate=48000");
+        }
+        "file" => {
+            let path = config
+                .file_path
+                .as_deref()
+                .ok_or_else(|| "CAPTURE_FILE is not set".to_owned())?;
+            cmd.arg("-re");
+            if config.file_loop {
+                cmd.arg("-stream_loop").arg("-1");
+            }
+            cmd.arg("-i").arg(path);
+        }
+        other => return Err(format!("unknown audio source: {other}")),
+    }
+
+    cmd.arg("-vn")
+        .arg("-c:a")
+        .arg("libopus")
+        .arg("-application")
+        .arg("lowdelay")
+        .arg("-frame_duration")
+        .arg(OPUS_FRAME_MS.to_string())
+        .arg("-b:a")
+        .arg(format!("{}k", config.bitrate_kbps))
+        .arg("-ar")
+        .arg("48000")
+        .arg("-ac")
+        .arg("2")
+        // One Opus packet per Ogg page so packets leave ffmpeg immediately.
+        .arg("-page_duration")
+        .arg((OPUS_FRAME_MS * 1000).to_string())
+        .arg("-flush_packets")
+        .arg("1")
+        .arg("-f")
+        .arg("ogg")
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|err| format!("ffmpeg spawn failed: {err}"))?;
+    if let (Some(mut pcm), Some(mut stdin)) = (loopback_pcm, child.stdin.take()) {
+        // Ends when ffmpeg exits (stopping the loopback thread) or the
+        // loopback ends (closing stdin, so ffmpeg exits in turn).
+        tokio::spawn(async move {
+            while let Some(chunk) = pcm.recv().await {
+                if stdin.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    CaptureStream::from_child(child, "audio")
+}
+
+// ffmpeg opens a missing dshow device only to fail right away; naming the
+// device and what is there instead beats a stream of restarts.
+async fn ensure_dshow_device(device: &str) -> Result<(), String> {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-hide_banner")
+        .arg("-list_devices")
+        .arg("true")
+        .arg("-f")
+        .arg("dshow")
+        .arg("-i")
+        .arg("dummy")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+    let output = cmd
+        .output()
+        .await
+        .map_err(|err| format!("ffmpeg dshow device listing failed: {err}"))?;
+    let listing = String::from_utf8_lossy(&output.stderr);
+    if listing.contains(&format!("\"{device}\"")) {
+        return Ok(());
+    }
+    let available: Vec<&str> = listing
+        .lines()
+        .filter(|line| line.contains("(audio)"))
+        .filter_map(|line| line.split('"').nth(1))
+        .collect();
+    Err(format!(
+        "dshow audio device \"{device}\" not found; available: [{}]",
+        available.join(", ")
+    ))
+}
+
+// Pulls Opus packets out of an Ogg stream, skipping the OpusHead and
+// OpusTags header packets.
+#[derive(Default)]
+struct OggDemuxer {
+    buffer: Vec<u8>,
+    packet: Vec<u8>,
+    packets_seen: u64,
+}
+
+impl OggDemuxer {
+    fn push(&mut self, data: &[u8]) -> Result<Vec<Bytes>, String> {
+        self.buffer.extend_from_slice(data);
+        let mut packets = Vec::new();
+        while self.buffer.len() >= 27 {
+            if &self.buffer[..4] != b"OggS" {
+                return Err("ogg capture pattern not found".to_owned());
+            }
+            let header_len = 27 + usize::from(self.buffer[26]);
+            if self.buffer.len() < header_len {
+                break;
+            }
+            let body_len: usize = self.buffer[27..header_len]
+                .iter()
+                .map(|&lacing| usize::from(lacing))
+                .sum();
+            if self.buffer.len() < header_len + body_len {
+                break;
+            }
+
+            let mut offset = header_len;
+            for index in 27..header_len {
+                let lacing = usize::from(self.buffer[index]);
+                self.packet
+                    .extend_from_slice(&self.buffer[offset..offset + lacing]);
+                offset += lacing;
+                // A lacing value below 255 ends the packet; 255 continues it.
+                if lacing < 255 {
+                    let packet = std::mem::take(&mut self.packet);
+                    if self.packets_seen >= 2 {
+                        packets.push(packet.into());
+                    }
+                    self.packets_seen += 1;
+                }
+            }
+            self.buffer.drain(..header_len + body_len);
+        }
+        Ok(packets)
+    }
+}
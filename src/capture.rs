@@ -0,0 +1,497 @@
+use std::{process::Stdio, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, Command},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    codec_config,
+    config::CaptureConfig,
+    encoder::{self, EncoderProfile, FrameMemory, VideoCodec},
+};
+
+// A video source that yields the profile's codec: an Annex-B elementary stream
+// with an AUD NAL in front of every access unit for H264/H265, IVF for
+// VP8/VP9/AV1.
+pub trait CaptureSource: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String>;
+
+    // Identifies what the source captures when viewers share pipelines;
+    // sources that exist once per room or device extend their name.
+    fn key(&self) -> String {
+        self.name().to_owned()
+    }
+
+    // False for sources that pass an already encoded stream through, which
+    // can neither force a keyframe nor change bitrate on request.
+    fn encodes(&self) -> bool {
+        true
+    }
+
+    // Asks whoever encodes a pass-through stream for a keyframe. False when
+    // nobody can be asked, e.g. a file.
+    fn request_keyframe(&self) -> bool {
+        false
+    }
+
+    // The one codec a pass-through source sends, which viewers have to
+    // negotiate. None for sources encoding whatever was negotiated.
+    fn codec(&self) -> Option<VideoCodec> {
+        None
+    }
+
+    // Where captured frames are when they reach the encoder, which is what
+    // the startup probe has to test.
+    fn frame_memory(&self) -> FrameMemory {
+        FrameMemory::System
+    }
+}
+
+// How long a process whose stdout closed gets to report its exit status.
+const EXIT_WAIT: Duration = Duration::from_secs(2);
+// Start of a raw H264 file searched for the SPS declaring its frame rate.
+const REPLAY_SPS_SEARCH: u64 = 1 << 20;
+
+// Running capture: the byte stream plus the process producing it, if any.
+pub struct CaptureStream {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    child: Option<Child>,
+    stderr: Option<JoinHandle<Option<String>>>,
+    control: Option<EncoderControl>,
+}
+
+// Keyframe requests for a running encode started with keyframe control,
+// written to its ffmpeg's stdin. Clones share the process.
+#[derive(Clone)]
+pub struct EncoderControl {
+    keyframes: mpsc::Sender<()>,
+}
+
+impl EncoderControl {
+    fn spawn(mut stdin: ChildStdin, source: &'static str) -> Self {
+        // A request already queued covers any that arrive before it is
+        // written.
+        let (keyframes, mut requests) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while requests.recv().await.is_some() {
+                let command = encoder::FORCE_KEYFRAME_COMMAND.as_bytes();
+                if let Err(err) = stdin.write_all(command).await {
+                    debug!("ffmpeg_control closed source={source} error={err}");
+                    return;
+                }
+                if let Err(err) = stdin.flush().await {
+                    debug!("ffmpeg_control closed source={source} error={err}");
+                    return;
+                }
+            }
+        });
+        Self { keyframes }
+    }
+
+    // Makes the next frame the encoder sees an IDR. False once the process
+    // is gone.
+    pub fn force_keyframe(&self) -> bool {
+        match self.keyframes.try_send(()) {
+            Ok(()) | Err(TrySendError::Full(())) => true,
+            Err(TrySendError::Closed(())) => false,
+        }
+    }
+}
+
+impl CaptureStream {
+    // `source` labels the process's stderr lines in the log.
+    pub fn from_child(mut child: Child, source: &'static str) -> Result<Self, String> {
+        let Some(stdout) = child.stdout.take() else {
+            return Err("ffmpeg stdout not piped".to_owned());
+        };
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| tokio::spawn(drain_stderr(stderr, source)));
+        let control = child
+            .stdin
+            .take()
+            .map(|stdin| EncoderControl::spawn(stdin, source));
+        Ok(Self {
+            reader: Box::new(stdout),
+            child: Some(child),
+            stderr,
+            control,
+        })
+    }
+
+    // A stream produced in-process; it ends cleanly once the reader hits EOF.
+    pub fn from_reader(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self {
+            reader: Box::new(reader),
+            child: None,
+            stderr: None,
+            control: None,
+        }
+    }
+
+    // How to force keyframes on this encode, if it was started with
+    // keyframe control.
+    pub fn control(&self) -> Option<EncoderControl> {
+        self.control.clone()
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf).await
+    }
+
+    pub async fn stop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill().await;
+        }
+    }
+
+    // Called once stdout reached EOF. A clean exit is the end of a finite
+    // source; anything else is reported with the last line ffmpeg printed.
+    pub async fn wait_exit(&mut self) -> Result<(), String> {
+        let Some(child) = &mut self.child else {
+            return Ok(());
+        };
+        let status = match tokio::time::timeout(EXIT_WAIT, child.wait()).await {
+            Ok(Ok(status)) if status.success() => return Ok(()),
+            Ok(Ok(status)) => status.to_string(),
+            Ok(Err(err)) => format!("wait failed: {err}"),
+            Err(_) => "stdout closed".to_owned(),
+        };
+        let last_stderr = match self.stderr.take() {
+            Some(stderr) => tokio::time::timeout(EXIT_WAIT, stderr)
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .flatten(),
+            None => None,
+        };
+        Err(match last_stderr {
+            Some(line) => format!("ffmpeg exited ({status}): {line}"),
+            None => format!("ffmpeg exited ({status})"),
+        })
+    }
+}
+
+// Reading stderr keeps ffmpeg from blocking on a full pipe. At the warning
+// log level everything it prints is worth surfacing, except its echo of
+// commands that succeeded. Returns the last line, which usually says why the
+// process exited.
+async fn drain_stderr(stderr: ChildStderr, source: &'static str) -> Option<String> {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut last_line = None;
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with("Enter command:")
+            || line.starts_with("Command reply") && line.contains(" ret:0 ")
+        {
+            continue;
+        }
+        warn!("ffmpeg_stderr source={source} line={line}");
+        last_line = Some(line.to_owned());
+    }
+    last_line
+}
+
+// Resolves a source by name, falling back to the configured default.
+pub async fn select_source(
+    config: &CaptureConfig,
+    requested: Option<&str>,
+) -> Result<Arc<dyn CaptureSource>, String> {
+    let name = requested.unwrap_or(config.source.as_str());
+    match name {
+        "dda" => Ok(Arc::new(DesktopDuplication {
+            output_idx: config.dda_output,
+        })),
+        "x11" => Ok(Arc::new(X11Grab {
+            display: config.x11_display.clone(),
+        })),
+        "testsrc" => Ok(Arc::new(TestPattern)),
+        "file" => {
+            let path = config
+                .file_path
+                .clone()
+                .ok_or_else(|| "CAPTURE_FILE is not set".to_owned())?;
+            Ok(Arc::new(FileSource {
+                path,
+                looping: config.file_loop,
+            }))
+        }
+        "replay" => {
+            let path = config
+                .file_path
+                .clone()
+                .ok_or_else(|| "CAPTURE_FILE is not set".to_owned())?;
+            Ok(Arc::new(FileReplay::open(path, config.file_loop).await?))
+        }
+        other => Err(format!("unknown capture source: {other}")),
+    }
+}
+
+// Windows Desktop Duplication via ddagrab; frames stay on the GPU as D3D11
+// textures unless the encoder needs them in system memory.
+struct DesktopDuplication {
+    output_idx: u32,
+}
+
+impl CaptureSource for DesktopDuplication {
+    fn name(&self) -> &'static str {
+        "dda"
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        let mut cmd = ffmpeg_command();
+        encoder::device_args(&mut cmd, profile.encoder, FrameMemory::D3d11);
+        cmd.arg("-f").arg("lavfi").arg("-i").arg(format!(
+            "ddagrab=framerate={}:output_idx={}:draw_mouse=1",
+            profile.framerate, self.output_idx
+        ));
+        encoder::encode_args(&mut cmd, profile, FrameMemory::D3d11, None)?;
+        spawn_video_output(cmd, self.name(), profile.codec, profile.keyframe_control)
+    }
+
+    fn frame_memory(&self) -> FrameMemory {
+        FrameMemory::D3d11
+    }
+}
+
+// Linux X11 screen grab.
+struct X11Grab {
+    display: String,
+}
+
+impl CaptureSource for X11Grab {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        let mut cmd = ffmpeg_command();
+        encoder::device_args(&mut cmd, profile.encoder, FrameMemory::System);
+        cmd.arg("-f")
+            .arg("x11grab")
+            .arg("-draw_mouse")
+            .arg("1")
+            .arg("-framerate")
+            .arg(profile.framerate.to_string())
+            .arg("-i")
+            .arg(&self.display);
+        encoder::encode_args(&mut cmd, profile, FrameMemory::System, None)?;
+        spawn_video_output(cmd, self.name(), profile.codec, profile.keyframe_control)
+    }
+}
+
+// Synthetic lavfi pattern paced in real time, usable on headless CI.
+struct TestPattern;
+
+impl CaptureSource for TestPattern {
+    fn name(&self) -> &'static str {
+        "testsrc"
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        let mut cmd = ffmpeg_command();
+        encoder::device_args(&mut cmd, profile.encoder, FrameMemory::System);
+        cmd.arg("-re")
+            .arg("-f")
+            .arg("lavfi")
+            .arg("-i")
+            .arg(format!("testsrc2=size=1280x720:rate={}", profile.framerate));
+        encoder::encode_args(&mut cmd, profile, FrameMemory::System, None)?;
+        spawn_video_output(cmd, self.name(), profile.codec, profile.keyframe_control)
+    }
+}
+
+// Any media file ffmpeg can decode, re-encoded and paced at native speed.
+struct FileSource {
+    path: String,
+    looping: bool,
+}
+
+impl CaptureSource for FileSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        let mut cmd = ffmpeg_command();
+        encoder::device_args(&mut cmd, profile.encoder, FrameMemory::System);
+        cmd.arg("-re");
+        if self.looping {
+            cmd.arg("-stream_loop").arg("-1");
+        }
+        cmd.arg("-i").arg(&self.path);
+        let fps = format!("fps={}", profile.framerate);
+        encoder::encode_args(&mut cmd, profile, FrameMemory::System, Some(&fps))?;
+        spawn_video_output(cmd, self.name(), profile.codec, profile.keyframe_control)
+    }
+}
+
+// An already encoded file (raw `.h264`/`.h265` Annex-B, MP4, WebM, IVF) sent
+// bit-for-bit as recorded, so a bug can be replayed against the same input.
+// Its codec is probed when the source is built and is the only one viewers
+// can negotiate. Raw elementary streams carry no timestamps and are paced at
+// the frame rate an H264 SPS declares, else at the profile's; containers
+// play at their own.
+struct FileReplay {
+    path: String,
+    looping: bool,
+    codec: VideoCodec,
+    raw_format: Option<&'static str>,
+    framerate: Option<(u32, u32)>,
+}
+
+impl FileReplay {
+    async fn open(path: String, looping: bool) -> Result<Self, String> {
+        let extension = std::path::Path::new(&path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let (codec, raw_format) = match extension.as_deref() {
+            Some("h264" | "264") => (VideoCodec::H264, Some("h264")),
+            Some("h265" | "265" | "hevc") => (VideoCodec::H265, Some("hevc")),
+            _ => (probe_video_codec(&path).await?, None),
+        };
+        let framerate = match raw_format {
+            Some("h264") => {
+                let mut head = Vec::new();
+                tokio::fs::File::open(&path)
+                    .await
+                    .map_err(|err| format!("replay open failed path={path}: {err}"))?
+                    .take(REPLAY_SPS_SEARCH)
+                    .read_to_end(&mut head)
+                    .await
+                    .map_err(|err| format!("replay read failed path={path}: {err}"))?;
+                codec_config::avc_frame_rate(&head)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            path,
+            looping,
+            codec,
+            raw_format,
+            framerate,
+        })
+    }
+}
+
+impl CaptureSource for FileReplay {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        let mut cmd = ffmpeg_command();
+        cmd.arg("-re");
+        if self.looping {
+            cmd.arg("-stream_loop").arg("-1");
+        }
+        if let Some(format) = self.raw_format {
+            let framerate = self.framerate.map_or_else(
+                || profile.framerate.to_string(),
+                |(num, den)| format!("{num}/{den}"),
+            );
+            cmd.arg("-f").arg(format).arg("-framerate").arg(framerate);
+        }
+        cmd.arg("-i")
+            .arg(&self.path)
+            .arg("-an")
+            .arg("-c:v")
+            .arg("copy");
+        spawn_video_output(cmd, self.name(), self.codec, false)
+    }
+
+    fn encodes(&self) -> bool {
+        false
+    }
+
+    fn codec(&self) -> Option<VideoCodec> {
+        Some(self.codec)
+    }
+}
+
+// The codec of a container's first video stream, as ffprobe names it.
+async fn probe_video_codec(path: &str) -> Result<VideoCodec, String> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("v:0")
+        .arg("-show_entries")
+        .arg("stream=codec_name")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| format!("ffprobe spawn failed: {err}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let name = stdout.trim();
+    if !output.status.success() || name.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "replay probe failed path={path}: {}",
+            stderr.lines().last().unwrap_or("no video stream").trim()
+        ));
+    }
+    VideoCodec::from_name(name).ok_or_else(|| format!("replay codec {name} cannot be streamed"))
+}
+
+pub fn ffmpeg_command() -> Command {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-hide_banner").arg("-loglevel").arg("warning");
+    cmd
+}
+
+// Encodes with keyframe control get their stdin piped to take commands;
+// anything else must not read the server's.
+fn spawn_video_output(
+    mut cmd: Command,
+    source: &'static str,
+    codec: VideoCodec,
+    keyframe_control: bool,
+) -> Result<CaptureStream, String> {
+    match codec {
+        VideoCodec::H264 => {
+            cmd.arg("-bsf:v")
+                .arg("h264_metadata=aud=insert")
+                .arg("-f")
+                .arg("h264");
+        }
+        // Not every HEVC encoder repeats VPS/SPS/PPS on its IRAP pictures,
+        // and viewers joining mid-stream need them.
+        VideoCodec::H265 => {
+            cmd.arg("-bsf:v")
+                .arg("hevc_metadata=aud=insert,dump_extra=freq=keyframe")
+                .arg("-f")
+                .arg("hevc");
+        }
+        VideoCodec::Vp8 | VideoCodec::Vp9 | VideoCodec::Av1 => {
+            cmd.arg("-f").arg("ivf");
+        }
+    }
+    let stdin = if keyframe_control {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    cmd.arg("-")
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let child = cmd
+        .spawn()
+        .map_err(|err| format!("ffmpeg spawn failed: {err}"))?;
+    CaptureStream::from_child(child, source)
+}
@@ -0,0 +1,623 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{encoder::VideoCodec, h264, ivf};
+
+// Decoder configuration record (avcC, hvcC or av1C) taken from a keyframe,
+// which carries the parameter sets (H264/H265) or the sequence header (AV1).
+// VP8 and VP9 need none in Matroska; MP4 describes them with `vpcC`.
+pub fn decoder_configuration(
+    codec: VideoCodec,
+    keyframe: &[u8],
+) -> Result<Option<Vec<u8>>, String> {
+    match codec {
+        VideoCodec::H264 => avc_configuration(keyframe).map(Some),
+        VideoCodec::H265 => hevc_configuration(keyframe).map(Some),
+        VideoCodec::Av1 => av1_configuration(keyframe).map(Some),
+        VideoCodec::Vp8 | VideoCodec::Vp9 => Ok(None),
+    }
+}
+
+// Coded picture size announced by a keyframe's SPS (H264/H265), sequence
+// header (AV1) or uncompressed frame header (VP8/VP9), with the cropping
+// window applied. None when the bitstream cannot be parsed.
+pub fn frame_size(codec: VideoCodec, keyframe: &[u8]) -> Option<(u32, u32)> {
+    match codec {
+        VideoCodec::H264 => avc_frame_size(keyframe),
+        VideoCodec::H265 => hevc_frame_size(keyframe),
+        VideoCodec::Vp8 => vp8_frame_size(keyframe),
+        VideoCodec::Vp9 => vp9_frame_size(keyframe),
+        VideoCodec::Av1 => av1_frame_size(keyframe),
+    }
+}
+
+// Matroska and MP4 store H264/H265 with 4-byte length prefixes instead of
+// Annex-B start codes.
+pub fn length_prefixed(codec: VideoCodec, data: Bytes) -> Bytes {
+    if !matches!(codec, VideoCodec::H264 | VideoCodec::H265) {
+        return data;
+    }
+    let nals = h264::nal_units(&data);
+    let mut out = BytesMut::with_capacity(data.len() + nals.len());
+    for nal in nals {
+        out.put_u32(nal.len() as u32);
+        out.extend_from_slice(nal);
+    }
+    out.freeze()
+}
+
+// AVCDecoderConfigurationRecord (ISO/IEC 14496-15 5.3.3.1).
+fn avc_configuration(keyframe: &[u8]) -> Result<Vec<u8>, String> {
+    let nals = h264::nal_units(keyframe);
+    let find = |nal_type: u8| {
+        nals.iter()
+            .copied()
+            .find(|nal| nal.first().is_some_and(|header| header & 0x1F == nal_type))
+    };
+    let (Some(sps), Some(pps)) = (find(7), find(8)) else {
+        return Err("h264 keyframe without sps/pps".to_owned());
+    };
+    if sps.len() < 4 {
+        return Err("h264 sps too short".to_owned());
+    }
+    // Version 1, profile/compatibility/level, 4-byte lengths, one SPS.
+    let mut config = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+    push_u16_prefixed(&mut config, sps);
+    config.push(1);
+    push_u16_prefixed(&mut config, pps);
+    Ok(config)
+}
+
+// HEVCDecoderConfigurationRecord (ISO/IEC 14496-15 8.3.3.1). The profile,
+// tier and level come from the SPS; chroma and bit depth are the 8-bit 4:2:0
+// every configured encoder produces.
+fn hevc_configuration(keyframe: &[u8]) -> Result<Vec<u8>, String> {
+    let nals = h264::nal_units(keyframe);
+    let find = |nal_type: u8| {
+        nals.iter().copied().find(|nal| {
+            nal.first()
+                .is_some_and(|header| (header >> 1) & 0x3F == nal_type)
+        })
+    };
+    let (Some(vps), Some(sps), Some(pps)) = (find(32), find(33), find(34)) else {
+        return Err("h265 keyframe without vps/sps/pps".to_owned());
+    };
+    // sps_video_parameter_set_id(4) sps_max_sub_layers_minus1(3)
+    // sps_temporal_id_nesting_flag(1), then the 12-byte general
+    // profile_tier_level.
+    let rbsp = unescape_rbsp(sps.get(2..).unwrap_or_default(), 13);
+    if rbsp.len() < 13 {
+        return Err("h265 sps too short".to_owned());
+    }
+    let temporal_layers = ((rbsp[0] >> 1) & 0x07) + 1;
+    let temporal_id_nested = rbsp[0] & 0x01;
+
+    let mut config = vec![1];
+    config.extend_from_slice(&rbsp[1..13]);
+    config.extend_from_slice(&[0xF0, 0x00, 0xFC, 0xFD, 0xF8, 0xF8, 0x00, 0x00]);
+    config.push((temporal_layers << 3) | (temporal_id_nested << 2) | 0x03);
+    config.push(3);
+    for (nal_type, nal) in [(32, vps), (33, sps), (34, pps)] {
+        config.push(0x80 | nal_type);
+        config.extend_from_slice(&1_u16.to_be_bytes());
+        push_u16_prefixed(&mut config, nal);
+    }
+    Ok(config)
+}
+
+// AV1CodecConfigurationRecord followed by the sequence header OBU.
+fn av1_configuration(keyframe: &[u8]) -> Result<Vec<u8>, String> {
+    let Some((sequence_header, payload)) = ivf::av1_sequence_header(keyframe) else {
+        return Err("av1 keyframe without sequence header".to_owned());
+    };
+    let (profile, level, tier) = av1_profile_level(payload).unwrap_or((0, 31, 0));
+    // Marker and version, then 8-bit 4:2:0 with no presentation delay.
+    let mut config = vec![0x81, (profile << 5) | level, (tier << 7) | 0x0C, 0x00];
+    config.extend_from_slice(sequence_header);
+    Ok(config)
+}
+
+// seq_profile, seq_level_idx[0] and seq_tier[0] of a sequence header. Streams
+// with timing info are rare from a live encoder; they get the unconstrained
+// level instead of a full parse.
+fn av1_profile_level(payload: &[u8]) -> Option<(u8, u8, u8)> {
+    let mut bits = BitReader::new(payload);
+    let profile = bits.read(3)? as u8;
+    let _still_picture = bits.read(1)?;
+    if bits.read(1)? == 1 {
+        return Some((profile, bits.read(5)? as u8, 0));
+    }
+    if bits.read(1)? == 1 {
+        return None;
+    }
+    let _initial_display_delay_present = bits.read(1)?;
+    let _operating_points_cnt_minus_1 = bits.read(5)?;
+    let _operating_point_idc = bits.read(12)?;
+    let level = bits.read(5)? as u8;
+    let tier = if level > 7 { bits.read(1)? as u8 } else { 0 };
+    Some((profile, level, tier))
+}
+
+// Nominal frame rate an H264 stream's first SPS declares in its VUI timing
+// info, as a fraction.
+pub fn avc_frame_rate(stream: &[u8]) -> Option<(u32, u32)> {
+    avc_sps(stream)?.frame_rate
+}
+
+fn avc_frame_size(keyframe: &[u8]) -> Option<(u32, u32)> {
+    let sps = avc_sps(keyframe)?;
+    Some((sps.width, sps.height))
+}
+
+// pic_width_in_mbs_minus1 and friends of a sequence parameter set (ITU-T
+// H.264 7.3.2.1.1), plus the VUI timing info when present.
+struct AvcSps {
+    width: u32,
+    height: u32,
+    frame_rate: Option<(u32, u32)>,
+}
+
+fn avc_sps(stream: &[u8]) -> Option<AvcSps> {
+    let sps = h264::nal_units(stream)
+        .into_iter()
+        .find(|nal| nal.first().is_some_and(|header| header & 0x1F == 7))?;
+    let rbsp = unescape_rbsp(sps.get(1..)?, sps.len());
+    let mut bits = BitReader::new(&rbsp);
+    let profile_idc = bits.read(8)?;
+    let _constraint_flags_and_level = bits.read(16)?;
+    let _seq_parameter_set_id = bits.read_ue()?;
+    let mut chroma_format_idc = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = bits.read_ue()?;
+        if chroma_format_idc == 3 && bits.read(1)? == 1 {
+            // separate_colour_plane_flag: each plane is coded as monochrome.
+            chroma_format_idc = 0;
+        }
+        let _bit_depth_luma_minus8 = bits.read_ue()?;
+        let _bit_depth_chroma_minus8 = bits.read_ue()?;
+        let _qpprime_y_zero_transform_bypass = bits.read(1)?;
+        if bits.read(1)? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for list in 0..lists {
+                if bits.read(1)? == 1 {
+                    skip_scaling_list(&mut bits, if list < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    let _log2_max_frame_num_minus4 = bits.read_ue()?;
+    match bits.read_ue()? {
+        0 => {
+            let _log2_max_pic_order_cnt_lsb_minus4 = bits.read_ue()?;
+        }
+        1 => {
+            let _delta_pic_order_always_zero = bits.read(1)?;
+            let _offset_for_non_ref_pic = bits.read_se()?;
+            let _offset_for_top_to_bottom_field = bits.read_se()?;
+            for _ in 0..bits.read_ue()? {
+                let _offset_for_ref_frame = bits.read_se()?;
+            }
+        }
+        _ => {}
+    }
+    let _max_num_ref_frames = bits.read_ue()?;
+    let _gaps_in_frame_num_allowed = bits.read(1)?;
+    let width_in_mbs = bits.read_ue()? + 1;
+    let height_in_map_units = bits.read_ue()? + 1;
+    let frame_mbs_only = bits.read(1)?;
+    if frame_mbs_only == 0 {
+        let _mb_adaptive_frame_field = bits.read(1)?;
+    }
+    let _direct_8x8_inference = bits.read(1)?;
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if bits.read(1)? == 1 {
+        crop_left = bits.read_ue()?;
+        crop_right = bits.read_ue()?;
+        crop_top = bits.read_ue()?;
+        crop_bottom = bits.read_ue()?;
+    }
+    let field_factor = 2 - frame_mbs_only;
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        1 => (2, 2 * field_factor),
+        2 => (2, field_factor),
+        _ => (1, field_factor),
+    };
+    let width = (width_in_mbs * 16).checked_sub(crop_unit_x * (crop_left + crop_right))?;
+    let height = (field_factor * height_in_map_units * 16)
+        .checked_sub(crop_unit_y * (crop_top + crop_bottom))?;
+    Some(AvcSps {
+        width,
+        height,
+        frame_rate: avc_vui_frame_rate(&mut bits),
+    })
+}
+
+// H264 counts timing ticks per field, so a frame takes two.
+fn avc_vui_frame_rate(bits: &mut BitReader<'_>) -> Option<(u32, u32)> {
+    if bits.read(1)? == 0 {
+        return None;
+    }
+    if bits.read(1)? == 1 && bits.read(8)? == 255 {
+        // Extended_SAR: sar_width and sar_height.
+        bits.skip(32)?;
+    }
+    if bits.read(1)? == 1 {
+        let _overscan_appropriate = bits.read(1)?;
+    }
+    if bits.read(1)? == 1 {
+        let _video_format_and_full_range = bits.read(4)?;
+        if bits.read(1)? == 1 {
+            let _colour_primaries_transfer_and_matrix = bits.read(24)?;
+        }
+    }
+    if bits.read(1)? == 1 {
+        let _chroma_sample_loc_type_top_field = bits.read_ue()?;
+        let _chroma_sample_loc_type_bottom_field = bits.read_ue()?;
+    }
+    if bits.read(1)? == 0 {
+        return None;
+    }
+    let num_units_in_tick = bits.read(32)?;
+    let time_scale = bits.read(32)?;
+    if num_units_in_tick == 0 || time_scale == 0 {
+        return None;
+    }
+    Some((time_scale, num_units_in_tick.checked_mul(2)?))
+}
+
+fn skip_scaling_list(bits: &mut BitReader<'_>, size: usize) -> Option<()> {
+    let mut last_scale = 8_i64;
+    let mut next_scale = 8_i64;
+    for _ in 0..size {
+        if next_scale != 0 {
+            next_scale = (last_scale + i64::from(bits.read_se()?)).rem_euclid(256);
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+// pic_width/height_in_luma_samples and the conformance window of a sequence
+// parameter set (ITU-T H.265 7.3.2.2.1).
+fn hevc_frame_size(keyframe: &[u8]) -> Option<(u32, u32)> {
+    let sps = h264::nal_units(keyframe)
+        .into_iter()
+        .find(|nal| nal.first().is_some_and(|header| (header >> 1) & 0x3F == 33))?;
+    let rbsp = unescape_rbsp(sps.get(2..)?, sps.len());
+    let mut bits = BitReader::new(&rbsp);
+    let _sps_video_parameter_set_id = bits.read(4)?;
+    let max_sub_layers_minus1 = bits.read(3)?;
+    let _temporal_id_nesting = bits.read(1)?;
+    // General profile_tier_level (88 bits) and general_level_idc.
+    bits.skip(96)?;
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        sub_layers.push((bits.read(1)?, bits.read(1)?));
+    }
+    if max_sub_layers_minus1 > 0 {
+        bits.skip(2 * (8 - max_sub_layers_minus1 as usize))?;
+    }
+    for (profile_present, level_present) in sub_layers {
+        bits.skip(88 * profile_present as usize + 8 * level_present as usize)?;
+    }
+    let _sps_seq_parameter_set_id = bits.read_ue()?;
+    let mut chroma_format_idc = bits.read_ue()?;
+    if chroma_format_idc == 3 && bits.read(1)? == 1 {
+        chroma_format_idc = 0;
+    }
+    let width = bits.read_ue()?;
+    let height = bits.read_ue()?;
+    if bits.read(1)? == 0 {
+        return Some((width, height));
+    }
+    let (left, right, top, bottom) = (
+        bits.read_ue()?,
+        bits.read_ue()?,
+        bits.read_ue()?,
+        bits.read_ue()?,
+    );
+    let (sub_width, sub_height) = match chroma_format_idc {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    Some((
+        width.checked_sub(sub_width * (left + right))?,
+        height.checked_sub(sub_height * (top + bottom))?,
+    ))
+}
+
+// Keyframe header of RFC 6386 9.1: frame tag, start code, then two 14-bit
+// little-endian dimensions.
+fn vp8_frame_size(keyframe: &[u8]) -> Option<(u32, u32)> {
+    let header = keyframe.get(..10)?;
+    if header[0] & 0x01 != 0 || header[3..6] != [0x9D, 0x01, 0x2A] {
+        return None;
+    }
+    let width = u16::from_le_bytes([header[6], header[7]]) & 0x3FFF;
+    let height = u16::from_le_bytes([header[8], header[9]]) & 0x3FFF;
+    Some((u32::from(width), u32::from(height)))
+}
+
+// frame_size() of a keyframe's uncompressed header (VP9 bitstream spec
+// 6.2). A superframe starts with its first frame, which is the keyframe.
+fn vp9_frame_size(keyframe: &[u8]) -> Option<(u32, u32)> {
+    let mut bits = BitReader::new(keyframe);
+    if bits.read(2)? != 2 {
+        return None;
+    }
+    let profile_low = bits.read(1)?;
+    let profile = (bits.read(1)? << 1) | profile_low;
+    if profile == 3 {
+        let _reserved_zero = bits.read(1)?;
+    }
+    let show_existing_frame = bits.read(1)?;
+    let frame_type = bits.read(1)?;
+    if show_existing_frame == 1 || frame_type != 0 {
+        return None;
+    }
+    let _show_frame = bits.read(1)?;
+    let _error_resilient = bits.read(1)?;
+    if bits.read(24)? != 0x49_83_42 {
+        return None;
+    }
+    if profile >= 2 {
+        let _ten_or_twelve_bit = bits.read(1)?;
+    }
+    // color_space CS_RGB has no range or subsampling fields.
+    if bits.read(3)? != 7 {
+        let _color_range = bits.read(1)?;
+        if profile == 1 || profile == 3 {
+            bits.skip(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        let _reserved_zero = bits.read(1)?;
+    }
+    let width = bits.read(16)? + 1;
+    let height = bits.read(16)? + 1;
+    Some((width, height))
+}
+
+// max_frame_width/height of the sequence header (AV1 spec 5.5.1); a live
+// encoder keeps every frame at that size.
+fn av1_frame_size(keyframe: &[u8]) -> Option<(u32, u32)> {
+    let (_, payload) = ivf::av1_sequence_header(keyframe)?;
+    let mut bits = BitReader::new(payload);
+    let _seq_profile = bits.read(3)?;
+    let _still_picture = bits.read(1)?;
+    if bits.read(1)? == 1 {
+        let _seq_level_idx = bits.read(5)?;
+    } else {
+        let mut buffer_delay_bits = 0;
+        let timing_info_present = bits.read(1)?;
+        let mut decoder_model_info_present = 0;
+        if timing_info_present == 1 {
+            let _num_units_in_display_tick = bits.read(32)?;
+            let _time_scale = bits.read(32)?;
+            // uvlc() is coded like Exp-Golomb ue(v).
+            if bits.read(1)? == 1 {
+                let _num_ticks_per_picture_minus_1 = bits.read_ue()?;
+            }
+            decoder_model_info_present = bits.read(1)?;
+            if decoder_model_info_present == 1 {
+                buffer_delay_bits = bits.read(5)? as usize + 1;
+                let _num_units_in_decoding_tick = bits.read(32)?;
+                let _buffer_removal_time_length_minus_1 = bits.read(5)?;
+                let _frame_presentation_time_length_minus_1 = bits.read(5)?;
+            }
+        }
+        let initial_display_delay_present = bits.read(1)?;
+        for _ in 0..=bits.read(5)? {
+            let _operating_point_idc = bits.read(12)?;
+            if bits.read(5)? > 7 {
+                let _seq_tier = bits.read(1)?;
+            }
+            if decoder_model_info_present == 1 && bits.read(1)? == 1 {
+                // decoder/encoder_buffer_delay and low_delay_mode_flag.
+                bits.skip(2 * buffer_delay_bits + 1)?;
+            }
+            if initial_display_delay_present == 1 && bits.read(1)? == 1 {
+                let _initial_display_delay_minus_1 = bits.read(4)?;
+            }
+        }
+    }
+    let width_bits = bits.read(4)? as usize + 1;
+    let height_bits = bits.read(4)? as usize + 1;
+    let width = bits.read(width_bits)? + 1;
+    let height = bits.read(height_bits)? + 1;
+    Some((width, height))
+}
+
+fn push_u16_prefixed(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+// First `limit` bytes of a NAL payload with emulation prevention removed.
+fn unescape_rbsp(data: &[u8], limit: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(limit);
+    let mut zeros = 0;
+    for &byte in data {
+        if out.len() == limit {
+            break;
+        }
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | u32::from(bit);
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        let end = self.position + count;
+        (end <= self.data.len() * 8).then(|| self.position = end)
+    }
+
+    // Exp-Golomb ue(v) of H264/H265.
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1_u32 << leading_zeros) - 1 + self.read(leading_zeros)?)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()?;
+        let magnitude = value.div_ceil(2) as i32;
+        Some(if value % 2 == 1 {
+            magnitude
+        } else {
+            -magnitude
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 160x90 constrained baseline cropped from 160x96, 30 fps VUI timing,
+    // with emulation prevention bytes.
+    const AVC_SPS: &[u8] = &[
+        0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0xDA, 0x0A, 0x37, 0xE4, 0x84, 0x00, 0x00, 0x03, 0x00,
+        0x04, 0x00, 0x00, 0x03, 0x00, 0xF2, 0x10,
+    ];
+    const AVC_PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80];
+    const AVC_IDR: &[u8] = &[0, 0, 1, 0x65, 0x88, 0x84];
+    // Main profile, level 3.1, 1920x1088 with a conformance window down to
+    // 1080 rows.
+    const HEVC_VPS: &[u8] = &[0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+    const HEVC_SPS: &[u8] = &[
+        0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03,
+        0x00, 0x00, 0x03, 0x00, 0x5D, 0xA0, 0x03, 0xC0, 0x80, 0x11, 0x07, 0xCB,
+    ];
+    const HEVC_PPS: &[u8] = &[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72];
+    const HEVC_IDR: &[u8] = &[0, 0, 1, 0x26, 0x01, 0xAF];
+
+    #[test]
+    fn avc_frame_size_applies_cropping() {
+        let keyframe = [AVC_SPS, AVC_PPS, AVC_IDR].concat();
+        assert_eq!(avc_frame_size(&keyframe), Some((160, 90)));
+        assert_eq!(avc_frame_size(AVC_IDR), None);
+    }
+
+    #[test]
+    fn avc_configuration_carries_profile_and_parameter_sets() {
+        let keyframe = [AVC_SPS, AVC_PPS, AVC_IDR].concat();
+        let config = avc_configuration(&keyframe).unwrap();
+        let sps = &AVC_SPS[4..];
+        let pps = &AVC_PPS[4..];
+
+        assert_eq!(config[..6], [1, 0x42, 0xC0, 0x1E, 0xFF, 0xE1]);
+        assert_eq!(config[6..8], (sps.len() as u16).to_be_bytes());
+        assert_eq!(&config[8..8 + sps.len()], sps);
+        let rest = &config[8 + sps.len()..];
+        assert_eq!(rest[0], 1);
+        assert_eq!(rest[1..3], (pps.len() as u16).to_be_bytes());
+        assert_eq!(&rest[3..], pps);
+
+        assert!(avc_configuration(AVC_IDR).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_replaces_start_codes() {
+        let data = Bytes::from([AVC_PPS, AVC_IDR].concat());
+        let prefixed = length_prefixed(VideoCodec::H264, data);
+        assert_eq!(
+            prefixed[..],
+            [
+                &[0, 0, 0, 4][..],
+                &AVC_PPS[4..],
+                &[0, 0, 0, 3],
+                &AVC_IDR[3..]
+            ]
+            .concat()
+        );
+
+        let vp8 = Bytes::from_static(&[0, 0, 1, 2]);
+        assert_eq!(length_prefixed(VideoCodec::Vp8, vp8.clone()), vp8);
+    }
+
+    #[test]
+    fn hevc_frame_size_applies_the_conformance_window() {
+        let keyframe = [HEVC_VPS, HEVC_SPS, HEVC_PPS, HEVC_IDR].concat();
+        assert_eq!(hevc_frame_size(&keyframe), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn hevc_configuration_copies_profile_tier_level_and_arrays() {
+        let keyframe = [HEVC_VPS, HEVC_SPS, HEVC_PPS, HEVC_IDR].concat();
+        let config = hevc_configuration(&keyframe).unwrap();
+
+        assert_eq!(config[0], 1);
+        // Unescaped general_profile_tier_level: Main, level 3.1.
+        assert_eq!(
+            config[1..13],
+            [0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5D]
+        );
+        // One temporal layer, nested, 4-byte lengths.
+        assert_eq!(config[21], 0x0F);
+        assert_eq!(config[22], 3);
+
+        let mut arrays = &config[23..];
+        for (nal_type, nal) in [(32, HEVC_VPS), (33, HEVC_SPS), (34, HEVC_PPS)] {
+            let nal = &nal[4..];
+            assert_eq!(arrays[0], 0x80 | nal_type);
+            assert_eq!(arrays[1..3], [0, 1]);
+            assert_eq!(arrays[3..5], (nal.len() as u16).to_be_bytes());
+            assert_eq!(&arrays[5..5 + nal.len()], nal);
+            arrays = &arrays[5 + nal.len()..];
+        }
+        assert!(arrays.is_empty());
+
+        let without_vps = [HEVC_SPS, HEVC_PPS, HEVC_IDR].concat();
+        assert!(hevc_configuration(&without_vps).is_err());
+    }
+
+    #[test]
+    fn avc_frame_rate_reads_vui_timing() {
+        let keyframe = [AVC_SPS, AVC_PPS, AVC_IDR].concat();
+        assert_eq!(avc_frame_rate(&keyframe), Some((60, 2)));
+    }
+
+    #[test]
+    fn unescape_rbsp_drops_emulation_prevention() {
+        assert_eq!(
+            unescape_rbsp(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03], 8),
+            [0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(unescape_rbsp(&[1, 2, 3, 4], 2), [1, 2]);
+    }
+}
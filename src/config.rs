@@ -0,0 +1,547 @@
+use std::{env, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::warn;
+
+use crate::{
+    encoder::{EncoderKind, EncoderProfile, H264Profile, VideoCodec},
+    models::StreamRequest,
+};
+
+// Floors for client-requested stream settings.
+const MIN_STREAM_WIDTH: u32 = 160;
+const MIN_STREAM_HEIGHT: u32 = 90;
+const MIN_STREAM_FPS: u32 = 5;
+const MIN_STREAM_BITRATE_KBPS: u32 = 200;
+
+// Media bridge tuning loaded from environment variables at startup.
+#[derive(Clone, Debug)]
+pub struct BridgeConfig {
+    pub resume_grace: Duration,
+    pub ice: IceConfig,
+    pub turn: Option<TurnConfig>,
+    pub capture: CaptureConfig,
+    pub recording: Option<RecordingConfig>,
+    pub replay: Option<ReplayConfig>,
+    // Bearer token WHIP publishers must present; WHIP is disabled without.
+    pub whip_token: Option<String>,
+    // Bearer token for the /admin API, which is closed without.
+    pub admin_token: Option<String>,
+}
+
+// Default capture source and encoder settings for bot streams.
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    pub source: String,
+    pub dda_output: u32,
+    pub x11_display: String,
+    pub file_path: Option<String>,
+    pub file_loop: bool,
+    pub profile: EncoderProfile,
+    pub video_codecs: Vec<VideoCodec>,
+    pub encoder_priority: Vec<EncoderKind>,
+    pub encoder_probe: bool,
+    pub abr: AbrConfig,
+    pub limits: StreamLimits,
+    pub supervisor: SupervisorConfig,
+    pub audio: Option<AudioConfig>,
+}
+
+// Upper bounds for the resolution, framerate and bitrate a client may ask for.
+#[derive(Clone, Debug)]
+pub struct StreamLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: u32,
+    pub max_bitrate_kbps: u32,
+}
+
+// When a capture process counts as crashed or stalled and how often it is
+// restarted before its viewers are told the stream failed.
+#[derive(Clone, Debug)]
+pub struct SupervisorConfig {
+    pub stall_timeout: Duration,
+    pub restart_attempts: u32,
+    pub backoff_initial: Duration,
+    pub backoff_max: Duration,
+}
+
+// Session recordings are opt-in: RECORDING_DIR enables them, and each one is
+// started per stream. Files roll over at whichever limit is hit first.
+#[derive(Clone, Debug)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub max_duration: Duration,
+}
+
+// Instant replay is opt-in too: REPLAY_DIR enables a rolling buffer of the
+// last `window` of every stream, saved to an MP4 clip on request.
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    pub dir: PathBuf,
+    pub window: Duration,
+}
+
+// System audio capture published as an Opus track; AUDIO_SOURCE=none disables it.
+#[derive(Clone, Debug)]
+pub struct AudioConfig {
+    pub source: String,
+    pub device: Option<String>,
+    pub bitrate_kbps: u32,
+    pub file_path: Option<String>,
+    pub file_loop: bool,
+}
+
+// Bounds for congestion-driven bitrate changes of a viewer's encoder.
+#[derive(Clone, Debug)]
+pub struct AbrConfig {
+    pub enabled: bool,
+    pub min_kbps: u32,
+    pub max_kbps: u32,
+}
+
+// Network settings applied to every bot peer connection.
+#[derive(Clone, Debug, Default)]
+pub struct IceConfig {
+    pub servers: Vec<IceServerConfig>,
+    pub lite: bool,
+    pub udp_port_range: Option<(u16, u16)>,
+    pub udp_mux_port: Option<u16>,
+    pub nat_1to1_ips: Vec<String>,
+    pub nat_1to1_srflx: bool,
+    pub interfaces: Vec<String>,
+}
+
+// Embedded TURN relay, enabled by setting TURN_PUBLIC_IP.
+#[derive(Clone, Debug)]
+pub struct TurnConfig {
+    pub public_ip: IpAddr,
+    pub port: u16,
+    pub realm: String,
+    pub shared_secret: String,
+    pub credential_ttl: Duration,
+    pub relay_port_range: Option<(u16, u16)>,
+    // Opt-in gate: when TURN_CLIENT_TOKEN is set, only joins presenting it
+    // as a bearer token are handed TURN credentials. Unset, every peer gets
+    // its own on join.
+    pub client_token: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+impl BridgeConfig {
+    pub fn from_env() -> Self {
+        Self {
+            resume_grace: Duration::from_secs(env_or("STREAM_RESUME_GRACE_SECS", 15)),
+            ice: IceConfig::from_env(),
+            turn: TurnConfig::from_env(),
+            capture: CaptureConfig::from_env(),
+            recording: RecordingConfig::from_env(),
+            replay: ReplayConfig::from_env(),
+            whip_token: env_opt::<String>("WHIP_TOKEN").filter(|token| !token.is_empty()),
+            admin_token: env_opt::<String>("ADMIN_TOKEN").filter(|token| !token.is_empty()),
+        }
+    }
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            resume_grace: Duration::from_secs(15),
+            ice: IceConfig::default(),
+            turn: None,
+            capture: CaptureConfig::default(),
+            recording: None,
+            replay: None,
+            whip_token: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl IceConfig {
+    // ICE_SERVERS is a comma separated URL list; ICE_USERNAME/ICE_CREDENTIAL
+    // apply to the turn:/turns: entries only.
+    fn from_env() -> Self {
+        let (turn_urls, stun_urls): (Vec<_>, Vec<_>) = env_list("ICE_SERVERS")
+            .into_iter()
+            .partition(|url| url.starts_with("turn:") || url.starts_with("turns:"));
+        let mut servers = Vec::new();
+        if !stun_urls.is_empty() {
+            servers.push(IceServerConfig {
+                urls: stun_urls,
+                username: String::new(),
+                credential: String::new(),
+            });
+        }
+        if !turn_urls.is_empty() {
+            servers.push(IceServerConfig {
+                urls: turn_urls,
+                username: env::var("ICE_USERNAME").unwrap_or_default(),
+                credential: env::var("ICE_CREDENTIAL").unwrap_or_default(),
+            });
+        }
+
+        Self {
+            servers,
+            lite: env_flag("ICE_LITE"),
+            udp_port_range: env_port_range("ICE_UDP_PORT_MIN", "ICE_UDP_PORT_MAX"),
+            udp_mux_port: env_opt("ICE_UDP_MUX_PORT"),
+            nat_1to1_ips: env_list("ICE_NAT_1TO1_IPS"),
+            nat_1to1_srflx: env::var("ICE_NAT_1TO1_CANDIDATE_TYPE")
+                .is_ok_and(|value| value.eq_ignore_ascii_case("srflx")),
+            interfaces: env_list("ICE_INTERFACES"),
+        }
+    }
+}
+
+impl CaptureConfig {
+    fn from_env() -> Self {
+        let defaults = Self::default();
+        let profile = EncoderProfile {
+            codec: defaults.profile.codec,
+            encoder: defaults.profile.encoder,
+            resolution: defaults.profile.resolution,
+            // Highest profile offered to clients; the offer may settle lower.
+            h264_profile: env::var("ENCODER_H264_PROFILE")
+                .ok()
+                .and_then(|name| H264Profile::from_name(name.trim()))
+                .unwrap_or(H264Profile::High),
+            framerate: env_or("ENCODER_FPS", defaults.profile.framerate),
+            bitrate_kbps: env_or("ENCODER_BITRATE_KBPS", defaults.profile.bitrate_kbps),
+            gop: env_or("ENCODER_GOP", defaults.profile.gop),
+            // Settled by the encoder probe.
+            keyframe_control: defaults.profile.keyframe_control,
+        };
+        Self {
+            source: env::var("CAPTURE_SOURCE").unwrap_or(defaults.source),
+            dda_output: env_or("CAPTURE_DDA_OUTPUT", defaults.dda_output),
+            x11_display: env::var("CAPTURE_X11_DISPLAY")
+                .or_else(|_| env::var("DISPLAY"))
+                .unwrap_or(defaults.x11_display),
+            file_path: env::var("CAPTURE_FILE").ok(),
+            file_loop: env_flag("CAPTURE_FILE_LOOP"),
+            video_codecs: video_codecs_from_env().unwrap_or(defaults.video_codecs),
+            encoder_priority: encoder_priority_from_env().unwrap_or(defaults.encoder_priority),
+            encoder_probe: env::var("ENCODER_PROBE").map_or(true, |_| env_flag("ENCODER_PROBE")),
+            abr: AbrConfig {
+                enabled: env::var("ABR_ENABLED").map_or(true, |_| env_flag("ABR_ENABLED")),
+                min_kbps: env_or("ABR_MIN_KBPS", defaults.abr.min_kbps),
+                max_kbps: env_or("ABR_MAX_KBPS", profile.bitrate_kbps),
+            },
+            limits: StreamLimits {
+                max_width: env_or("STREAM_MAX_WIDTH", defaults.limits.max_width),
+                max_height: env_or("STREAM_MAX_HEIGHT", defaults.limits.max_height),
+                max_fps: env_or("STREAM_MAX_FPS", profile.framerate),
+                max_bitrate_kbps: env_or("STREAM_MAX_BITRATE_KBPS", profile.bitrate_kbps),
+            },
+            supervisor: SupervisorConfig {
+                stall_timeout: env_opt("FFMPEG_STALL_TIMEOUT_SECS")
+                    .map_or(defaults.supervisor.stall_timeout, Duration::from_secs),
+                restart_attempts: env_or(
+                    "FFMPEG_RESTART_ATTEMPTS",
+                    defaults.supervisor.restart_attempts,
+                ),
+                backoff_initial: env_opt("FFMPEG_RESTART_BACKOFF_MS")
+                    .map_or(defaults.supervisor.backoff_initial, Duration::from_millis),
+                backoff_max: env_opt("FFMPEG_RESTART_BACKOFF_MAX_MS")
+                    .map_or(defaults.supervisor.backoff_max, Duration::from_millis),
+            },
+            audio: AudioConfig::from_env(),
+            profile,
+        }
+    }
+}
+
+impl StreamLimits {
+    // Applies a client's request to the default profile within the limits.
+    // Without an explicit bitrate the default one is scaled by the requested
+    // pixel rate, so a 720p30 phone does not get the 1080p60 budget.
+    pub fn apply(&self, request: &StreamRequest, profile: &mut EncoderProfile) {
+        let max_width = self.max_width.max(MIN_STREAM_WIDTH);
+        let max_height = self.max_height.max(MIN_STREAM_HEIGHT);
+        let max_fps = self.max_fps.max(MIN_STREAM_FPS);
+        let max_bitrate_kbps = self.max_bitrate_kbps.max(MIN_STREAM_BITRATE_KBPS);
+
+        if request.width.is_some() || request.height.is_some() {
+            profile.resolution = Some((
+                request
+                    .width
+                    .unwrap_or(max_width)
+                    .clamp(MIN_STREAM_WIDTH, max_width),
+                request
+                    .height
+                    .unwrap_or(max_height)
+                    .clamp(MIN_STREAM_HEIGHT, max_height),
+            ));
+        }
+        let default_fps = profile.framerate.max(1);
+        profile.framerate = request
+            .fps
+            .unwrap_or(profile.framerate)
+            .clamp(MIN_STREAM_FPS, max_fps);
+
+        let bitrate_kbps = request.bitrate_kbps.unwrap_or_else(|| {
+            let (width, height) = profile.resolution.unwrap_or((max_width, max_height));
+            let pixel_rate = f64::from(width) * f64::from(height) * f64::from(profile.framerate);
+            let reference = f64::from(max_width) * f64::from(max_height) * f64::from(default_fps);
+            (f64::from(profile.bitrate_kbps) * (pixel_rate / reference).min(1.0)) as u32
+        });
+        profile.bitrate_kbps = bitrate_kbps.clamp(MIN_STREAM_BITRATE_KBPS, max_bitrate_kbps);
+    }
+}
+
+impl SupervisorConfig {
+    // Delay before restart number `attempt` (0-based), doubling up to the cap.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff_initial
+            .saturating_mul(1_u32 << attempt.min(16))
+            .min(self.backoff_max)
+    }
+}
+
+impl RecordingConfig {
+    fn from_env() -> Option<Self> {
+        let dir = env::var("RECORDING_DIR").ok()?;
+        Some(Self {
+            dir: PathBuf::from(dir),
+            max_bytes: env_or("RECORDING_MAX_MB", 1024_u64) * 1024 * 1024,
+            max_duration: Duration::from_secs(env_or("RECORDING_MAX_SECS", 1800)),
+        })
+    }
+}
+
+impl ReplayConfig {
+    fn from_env() -> Option<Self> {
+        let dir = env::var("REPLAY_DIR").ok()?;
+        let window = Duration::from_secs(env_or("REPLAY_WINDOW_SECS", 30));
+        if window.is_zero() {
+            return None;
+        }
+        Some(Self {
+            dir: PathBuf::from(dir),
+            window,
+        })
+    }
+}
+
+impl AudioConfig {
+    fn from_env() -> Option<Self> {
+        let default_source = if cfg!(windows) { "wasapi" } else { "pulse" };
+        let source = env::var("AUDIO_SOURCE").unwrap_or_else(|_| default_source.to_owned());
+        if source == "none" {
+            return None;
+        }
+        Some(Self {
+            source,
+            device: env::var("AUDIO_DEVICE").ok(),
+            bitrate_kbps: env_or("AUDIO_BITRATE_KBPS", 128),
+            file_path: env::var("CAPTURE_FILE").ok(),
+            file_loop: env_flag("CAPTURE_FILE_LOOP"),
+        })
+    }
+}
+
+// VIDEO_CODECS is our preference order, e.g. `h264,vp9,av1`; a viewer gets
+// the first one its offer also carries.
+fn video_codecs_from_env() -> Option<Vec<VideoCodec>> {
+    let names = env_list("VIDEO_CODECS");
+    if names.is_empty() {
+        return None;
+    }
+    let mut codecs = Vec::new();
+    for name in names {
+        match VideoCodec::from_name(&name) {
+            Some(codec) => codecs.push(codec),
+            None => warn!("VIDEO_CODECS ignoring unknown codec {name}"),
+        }
+    }
+    Some(codecs)
+}
+
+// ENCODER_PRIORITY is a comma separated list such as `nvenc,qsv,libx264`.
+fn encoder_priority_from_env() -> Option<Vec<EncoderKind>> {
+    let names = env_list("ENCODER_PRIORITY");
+    if names.is_empty() {
+        return None;
+    }
+    let mut priority = Vec::new();
+    for name in names {
+        match EncoderKind::from_name(&name) {
+            Some(kind) => priority.push(kind),
+            None => warn!("ENCODER_PRIORITY ignoring unknown encoder {name}"),
+        }
+    }
+    Some(priority)
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        let source = if cfg!(windows) { "dda" } else { "x11" };
+        Self {
+            source: source.to_owned(),
+            dda_output: 0,
+            x11_display: ":0.0".to_owned(),
+            file_path: None,
+            file_loop: false,
+            profile: EncoderProfile::default(),
+            video_codecs: VideoCodec::ALL.to_vec(),
+            encoder_priority: EncoderKind::ALL.to_vec(),
+            encoder_probe: true,
+            abr: AbrConfig {
+                enabled: true,
+                min_kbps: 500,
+                max_kbps: EncoderProfile::default().bitrate_kbps,
+            },
+            limits: StreamLimits {
+                max_width: 1920,
+                max_height: 1080,
+                max_fps: EncoderProfile::default().framerate,
+                max_bitrate_kbps: EncoderProfile::default().bitrate_kbps,
+            },
+            supervisor: SupervisorConfig {
+                stall_timeout: Duration::from_secs(8),
+                restart_attempts: 5,
+                backoff_initial: Duration::from_millis(500),
+                backoff_max: Duration::from_secs(10),
+            },
+            audio: None,
+        }
+    }
+}
+
+impl TurnConfig {
+    fn from_env() -> Option<Self> {
+        let public_ip = env_opt("TURN_PUBLIC_IP")?;
+        let shared_secret = env::var("TURN_SECRET").unwrap_or_else(|_| {
+            warn!("TURN_SECRET unset, turn credentials will not survive a restart");
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+        });
+        Some(Self {
+            public_ip,
+            port: env_or("TURN_PORT", 3478),
+            realm: env::var("TURN_REALM").unwrap_or_else(|_| "game-streamer".to_owned()),
+            shared_secret,
+            credential_ttl: Duration::from_secs(env_or("TURN_CREDENTIAL_TTL_SECS", 3600)),
+            relay_port_range: env_port_range("TURN_RELAY_PORT_MIN", "TURN_RELAY_PORT_MAX"),
+            client_token: env_opt::<String>("TURN_CLIENT_TOKEN").filter(|token| !token.is_empty()),
+        })
+    }
+}
+
+// Parses one env var, falling back to the default when unset or malformed.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
+
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn env_port_range(min_key: &str, max_key: &str) -> Option<(u16, u16)> {
+    match (env_opt::<u16>(min_key), env_opt::<u16>(max_key)) {
+        (Some(min), Some(max)) => Some((min, max)),
+        (Some(min), None) => Some((min, u16::MAX)),
+        (None, Some(max)) => Some((1024, max)),
+        (None, None) => None,
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    env::var(key).is_ok_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"))
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: StreamLimits = StreamLimits {
+        max_width: 1920,
+        max_height: 1080,
+        max_fps: 60,
+        max_bitrate_kbps: 8_000,
+    };
+
+    fn applied(request: StreamRequest) -> EncoderProfile {
+        let mut profile = EncoderProfile::default();
+        LIMITS.apply(&request, &mut profile);
+        profile
+    }
+
+    #[test]
+    fn empty_request_keeps_the_defaults() {
+        let profile = applied(StreamRequest::default());
+        assert_eq!(profile.resolution, None);
+        assert_eq!(profile.framerate, 60);
+        assert_eq!(profile.bitrate_kbps, 5_000);
+    }
+
+    #[test]
+    fn scales_the_default_bitrate_by_pixel_rate() {
+        let profile = applied(StreamRequest {
+            width: Some(1280),
+            height: Some(720),
+            fps: Some(30),
+            ..StreamRequest::default()
+        });
+        assert_eq!(profile.resolution, Some((1280, 720)));
+        assert_eq!(profile.framerate, 30);
+        assert_eq!(profile.bitrate_kbps, 1_111);
+    }
+
+    #[test]
+    fn explicit_bitrate_is_not_scaled() {
+        let profile = applied(StreamRequest {
+            width: Some(1280),
+            height: Some(720),
+            bitrate_kbps: Some(3_000),
+            ..StreamRequest::default()
+        });
+        assert_eq!(profile.bitrate_kbps, 3_000);
+    }
+
+    #[test]
+    fn clamps_to_the_limits() {
+        let profile = applied(StreamRequest {
+            width: Some(3840),
+            height: Some(2160),
+            fps: Some(240),
+            bitrate_kbps: Some(50_000),
+            ..StreamRequest::default()
+        });
+        assert_eq!(profile.resolution, Some((1920, 1080)));
+        assert_eq!(profile.framerate, 60);
+        assert_eq!(profile.bitrate_kbps, 8_000);
+    }
+
+    #[test]
+    fn clamps_to_the_minimums() {
+        let profile = applied(StreamRequest {
+            width: Some(10),
+            fps: Some(1),
+            bitrate_kbps: Some(10),
+            ..StreamRequest::default()
+        });
+        assert_eq!(profile.resolution, Some((MIN_STREAM_WIDTH, 1080)));
+        assert_eq!(profile.framerate, MIN_STREAM_FPS);
+        assert_eq!(profile.bitrate_kbps, MIN_STREAM_BITRATE_KBPS);
+    }
+}
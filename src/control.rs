@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoder::{EncoderProfile, VideoCodec},
+    models::StreamRequest,
+};
+
+// Commands a viewer sends as JSON on its `control` data channel.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlCommand {
+    SetBitrate {
+        bitrate_kbps: u32,
+    },
+    SetFps {
+        fps: u32,
+    },
+    // Leaving both dimensions out returns to the native capture size.
+    SetResolution {
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+    },
+    RequestKeyframe,
+    PauseVideo,
+    ResumeVideo,
+    StartRecording,
+    StopRecording,
+    // Writes the instant-replay buffer to an MP4 clip.
+    SaveReplay,
+    GetSettings,
+}
+
+// Sent back for every command with the settings in effect afterwards.
+#[derive(Debug, Serialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<StreamSettings>,
+    // Path of the clip written by `save_replay`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clip: Option<String>,
+}
+
+// What the viewer's encoder is producing right now, after limits and ABR.
+#[derive(Debug, Serialize)]
+pub struct StreamSettings {
+    pub codec: VideoCodec,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    pub fps: u32,
+    pub bitrate_kbps: u32,
+    pub paused: bool,
+    pub recording: bool,
+}
+
+impl ControlCommand {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        serde_json::from_str(text).map_err(|err| format!("control json parse failed: {err}"))
+    }
+
+    // Folds a settings command into the session's stream request; the other
+    // commands leave it alone.
+    pub fn update_request(&self, request: &mut StreamRequest) {
+        match *self {
+            Self::SetBitrate { bitrate_kbps } => request.bitrate_kbps = Some(bitrate_kbps),
+            Self::SetFps { fps } => request.fps = Some(fps),
+            Self::SetResolution { width, height } => {
+                request.width = width;
+                request.height = height;
+            }
+            Self::RequestKeyframe
+            | Self::PauseVideo
+            | Self::ResumeVideo
+            | Self::StartRecording
+            | Self::StopRecording
+            | Self::SaveReplay
+            | Self::GetSettings => {}
+        }
+    }
+}
+
+impl ControlReply {
+    pub fn ok(settings: StreamSettings) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            settings: Some(settings),
+            clip: None,
+        }
+    }
+
+    pub fn error(error: String, settings: Option<StreamSettings>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            settings,
+            clip: None,
+        }
+    }
+}
+
+impl StreamSettings {
+    pub fn new(profile: &EncoderProfile, paused: bool, recording: bool) -> Self {
+        Self {
+            codec: profile.codec,
+            width: profile.resolution.map(|(width, _)| width),
+            height: profile.resolution.map(|(_, height)| height),
+            fps: profile.framerate,
+            bitrate_kbps: profile.bitrate_kbps,
+            paused,
+            recording,
+        }
+    }
+}
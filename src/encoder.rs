@@ -0,0 +1,661 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{process::Command, task::JoinSet};
+use tracing::{info, warn};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+// Bound on the whole startup probe, however many encoders are configured.
+const PROBE_BUDGET: Duration = Duration::from_secs(20);
+
+// A timeline-enabled no-op at the end of the filter chain. Enabled for one
+// frame, it tags that frame the way scene detection would, and
+// `-force_key_frames scd_metadata` makes the encoder start a new GOP there.
+const KEYFRAME_FILTER: &str = "metadata@keyframe=mode=add:key=lavfi.scd.time:value=1:enable=0";
+// Written to the stdin of an encode started with keyframe control, which
+// ffmpeg reads as an interactive command: a fresh `enable` expression whose
+// register starts at zero is true for exactly the next frame.
+pub const FORCE_KEYFRAME_COMMAND: &str = "c metadata@keyframe -1 enable eq(st(0,ld(0)+1),1)\n";
+
+// Video codecs a viewer can negotiate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+// Encoder families ffmpeg can drive, with a low-latency argument set each.
+// Hardware families cover several codecs; software ones a single codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderKind {
+    Nvenc,
+    Qsv,
+    Amf,
+    Vaapi,
+    Libx264,
+    Openh264,
+    Libx265,
+    Libvpx,
+    Libsvtav1,
+}
+
+// Where captured frames live when they reach the encoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameMemory {
+    System,
+    D3d11,
+}
+
+// H264 profiles the encoders are driven at, lowest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum H264Profile {
+    ConstrainedBaseline,
+    Main,
+    High,
+}
+
+// Encoder knobs every capture source has to honour.
+#[derive(Clone, Debug)]
+pub struct EncoderProfile {
+    pub codec: VideoCodec,
+    pub encoder: EncoderKind,
+    pub h264_profile: H264Profile,
+    // Bounding box the capture is scaled into; None keeps the native size.
+    pub resolution: Option<(u32, u32)>,
+    pub framerate: u32,
+    pub bitrate_kbps: u32,
+    pub gop: u32,
+    // Whether the encode takes forced keyframes over ffmpeg's stdin, see
+    // FORCE_KEYFRAME_COMMAND. Set from the startup probe.
+    pub keyframe_control: bool,
+}
+
+impl Default for EncoderProfile {
+    fn default() -> Self {
+        Self {
+            codec: VideoCodec::H264,
+            encoder: EncoderKind::Libx264,
+            h264_profile: H264Profile::ConstrainedBaseline,
+            resolution: None,
+            framerate: 60,
+            bitrate_kbps: 5_000,
+            gop: 60,
+            keyframe_control: false,
+        }
+    }
+}
+
+impl VideoCodec {
+    pub const ALL: [VideoCodec; 5] = [
+        VideoCodec::H264,
+        VideoCodec::Vp8,
+        VideoCodec::Vp9,
+        VideoCodec::Av1,
+        VideoCodec::H265,
+    ];
+
+    // Accepts our names as well as SDP encoding names, in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "h264" | "avc" => Some(Self::H264),
+            "h265" | "hevc" => Some(Self::H265),
+            "vp8" => Some(Self::Vp8),
+            "vp9" => Some(Self::Vp9),
+            "av1" => Some(Self::Av1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::H265 => "h265",
+            Self::Vp8 => "vp8",
+            Self::Vp9 => "vp9",
+            Self::Av1 => "av1",
+        }
+    }
+
+    // Encoding name in SDP rtpmap lines.
+    pub fn sdp_name(self) -> &'static str {
+        match self {
+            Self::H264 => "H264",
+            Self::H265 => "H265",
+            Self::Vp8 => "VP8",
+            Self::Vp9 => "VP9",
+            Self::Av1 => "AV1",
+        }
+    }
+}
+
+impl EncoderKind {
+    pub const ALL: [EncoderKind; 9] = [
+        EncoderKind::Nvenc,
+        EncoderKind::Qsv,
+        EncoderKind::Amf,
+        EncoderKind::Vaapi,
+        EncoderKind::Libx264,
+        EncoderKind::Openh264,
+        EncoderKind::Libx265,
+        EncoderKind::Libvpx,
+        EncoderKind::Libsvtav1,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nvenc" | "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => Some(Self::Nvenc),
+            "qsv" | "h264_qsv" | "hevc_qsv" | "vp9_qsv" | "av1_qsv" => Some(Self::Qsv),
+            "amf" | "h264_amf" | "hevc_amf" | "av1_amf" => Some(Self::Amf),
+            "vaapi" | "h264_vaapi" | "hevc_vaapi" | "vp8_vaapi" | "vp9_vaapi" | "av1_vaapi" => {
+                Some(Self::Vaapi)
+            }
+            "libx264" | "x264" => Some(Self::Libx264),
+            "openh264" | "libopenh264" => Some(Self::Openh264),
+            "libx265" | "x265" => Some(Self::Libx265),
+            "libvpx" | "vpx" | "libvpx-vp9" => Some(Self::Libvpx),
+            "libsvtav1" | "svtav1" => Some(Self::Libsvtav1),
+            _ => None,
+        }
+    }
+
+    // The ffmpeg encoder of this family for `codec`, if it has one.
+    pub fn codec_name(self, codec: VideoCodec) -> Option<&'static str> {
+        let name = match (self, codec) {
+            (Self::Nvenc, VideoCodec::H264) => "h264_nvenc",
+            (Self::Nvenc, VideoCodec::H265) => "hevc_nvenc",
+            (Self::Nvenc, VideoCodec::Av1) => "av1_nvenc",
+            (Self::Qsv, VideoCodec::H264) => "h264_qsv",
+            (Self::Qsv, VideoCodec::H265) => "hevc_qsv",
+            (Self::Qsv, VideoCodec::Vp9) => "vp9_qsv",
+            (Self::Qsv, VideoCodec::Av1) => "av1_qsv",
+            (Self::Amf, VideoCodec::H264) => "h264_amf",
+            (Self::Amf, VideoCodec::H265) => "hevc_amf",
+            (Self::Amf, VideoCodec::Av1) => "av1_amf",
+            (Self::Vaapi, VideoCodec::H264) => "h264_vaapi",
+            (Self::Vaapi, VideoCodec::H265) => "hevc_vaapi",
+            (Self::Vaapi, VideoCodec::Vp8) => "vp8_vaapi",
+            (Self::Vaapi, VideoCodec::Vp9) => "vp9_vaapi",
+            (Self::Vaapi, VideoCodec::Av1) => "av1_vaapi",
+            (Self::Libx264, VideoCodec::H264) => "libx264",
+            (Self::Openh264, VideoCodec::H264) => "libopenh264",
+            (Self::Libx265, VideoCodec::H265) => "libx265",
+            (Self::Libvpx, VideoCodec::Vp8) => "libvpx",
+            (Self::Libvpx, VideoCodec::Vp9) => "libvpx-vp9",
+            (Self::Libsvtav1, VideoCodec::Av1) => "libsvtav1",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    fn codec_args(self, codec: VideoCodec) -> &'static [&'static str] {
+        match self {
+            Self::Nvenc => &[
+                "-preset",
+                "p1",
+                "-tune",
+                "ull",
+                "-rc",
+                "cbr",
+                "-zerolatency",
+                "1",
+                "-delay",
+                "0",
+            ],
+            Self::Qsv if codec == VideoCodec::H264 => &[
+                "-preset",
+                "veryfast",
+                "-look_ahead",
+                "0",
+                "-async_depth",
+                "1",
+            ],
+            Self::Qsv => &["-preset", "veryfast", "-async_depth", "1"],
+            Self::Amf => &[
+                "-usage",
+                "ultralowlatency",
+                "-quality",
+                "speed",
+                "-rc",
+                "cbr",
+            ],
+            Self::Vaapi => &["-rc_mode", "CBR"],
+            Self::Libx264 => &["-preset", "ultrafast", "-tune", "zerolatency"],
+            Self::Openh264 => &["-allow_skip_frames", "1"],
+            Self::Libx265 => &["-preset", "ultrafast", "-tune", "zerolatency"],
+            Self::Libvpx if codec == VideoCodec::Vp9 => &[
+                "-deadline",
+                "realtime",
+                "-cpu-used",
+                "8",
+                "-lag-in-frames",
+                "0",
+                "-error-resilient",
+                "1",
+                "-row-mt",
+                "1",
+            ],
+            Self::Libvpx => &[
+                "-deadline",
+                "realtime",
+                "-cpu-used",
+                "8",
+                "-lag-in-frames",
+                "0",
+                "-error-resilient",
+                "1",
+            ],
+            Self::Libsvtav1 => &["-preset", "12", "-svtav1-params", "pred-struct=1"],
+        }
+    }
+
+    // Makes forced keyframes IDRs where the encoder would otherwise settle
+    // for a plain I frame, which a joining viewer cannot start from.
+    fn forced_idr_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Nvenc | Self::Libx264 | Self::Libx265 => &["-forced-idr", "1"],
+            Self::Qsv => &["-forced_idr", "1"],
+            _ => &[],
+        }
+    }
+}
+
+impl H264Profile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "baseline" | "constrained_baseline" => Some(Self::ConstrainedBaseline),
+            "main" => Some(Self::Main),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ConstrainedBaseline => "constrained_baseline",
+            Self::Main => "main",
+            Self::High => "high",
+        }
+    }
+
+    // The profile a `profile-level-id` asks for. Plain baseline is served
+    // with constrained baseline, which every baseline decoder accepts.
+    pub fn from_profile_level_id(profile_level_id: [u8; 3]) -> Option<Self> {
+        match profile_level_id[0] {
+            0x42 => Some(Self::ConstrainedBaseline),
+            0x4d => Some(Self::Main),
+            0x64 => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    fn encoder_arg(self, encoder: EncoderKind) -> &'static str {
+        match (self, encoder) {
+            (
+                Self::ConstrainedBaseline,
+                EncoderKind::Nvenc | EncoderKind::Qsv | EncoderKind::Libx264,
+            ) => "baseline",
+            (Self::ConstrainedBaseline, _) => "constrained_baseline",
+            (Self::Main, _) => "main",
+            (Self::High, _) => "high",
+        }
+    }
+}
+
+// Hardware device setup that has to precede the ffmpeg input.
+pub fn device_args(cmd: &mut Command, encoder: EncoderKind, memory: FrameMemory) {
+    match (encoder, memory) {
+        (EncoderKind::Qsv, FrameMemory::D3d11) => {
+            cmd.arg("-init_hw_device")
+                .arg("d3d11va=dx")
+                .arg("-init_hw_device")
+                .arg("qsv=qs@dx")
+                .arg("-filter_hw_device")
+                .arg("dx");
+        }
+        (EncoderKind::Vaapi, _) => {
+            cmd.arg("-vaapi_device").arg("/dev/dri/renderD128");
+        }
+        _ => {}
+    }
+}
+
+// Filter chain, codec and rate control arguments for one encoder.
+pub fn encode_args(
+    cmd: &mut Command,
+    profile: &EncoderProfile,
+    memory: FrameMemory,
+    source_filters: Option<&str>,
+) -> Result<(), String> {
+    let mut filters: Vec<String> = source_filters.into_iter().map(str::to_owned).collect();
+    // Scaling keeps the aspect ratio and runs in system memory, so GPU frames
+    // are downloaded first.
+    let memory = match profile.resolution {
+        Some((width, height)) => {
+            if memory == FrameMemory::D3d11 {
+                filters.push("hwdownload,format=bgra".to_owned());
+            }
+            filters.push(format!(
+                "scale=w={width}:h={height}:force_original_aspect_ratio=decrease:force_divisible_by=2"
+            ));
+            FrameMemory::System
+        }
+        None => memory,
+    };
+    let upload = match (profile.encoder, memory) {
+        (EncoderKind::Qsv, FrameMemory::D3d11) => Some("hwmap=derive_device=qsv,format=qsv"),
+        (EncoderKind::Nvenc | EncoderKind::Amf, FrameMemory::D3d11) => None,
+        (EncoderKind::Vaapi, FrameMemory::D3d11) => {
+            return Err("vaapi cannot encode d3d11 frames".to_owned())
+        }
+        (
+            EncoderKind::Libx264
+            | EncoderKind::Openh264
+            | EncoderKind::Libx265
+            | EncoderKind::Libvpx
+            | EncoderKind::Libsvtav1,
+            FrameMemory::D3d11,
+        ) => Some("hwdownload,format=bgra,format=yuv420p"),
+        (EncoderKind::Vaapi, FrameMemory::System) => Some("format=nv12,hwupload"),
+        (EncoderKind::Qsv | EncoderKind::Amf, FrameMemory::System) => Some("format=nv12"),
+        (_, FrameMemory::System) => Some("format=yuv420p"),
+    };
+    filters.extend(upload.map(str::to_owned));
+    if profile.keyframe_control {
+        filters.push(KEYFRAME_FILTER.to_owned());
+    }
+    if !filters.is_empty() {
+        cmd.arg("-vf").arg(filters.join(","));
+    }
+
+    let codec_name = profile.encoder.codec_name(profile.codec).ok_or_else(|| {
+        format!(
+            "{:?} has no {} encoder",
+            profile.encoder,
+            profile.codec.name()
+        )
+    })?;
+    cmd.arg("-an")
+        .arg("-c:v")
+        .arg(codec_name)
+        .args(profile.encoder.codec_args(profile.codec));
+    if profile.codec == VideoCodec::H264 {
+        cmd.arg("-profile:v")
+            .arg(profile.h264_profile.encoder_arg(profile.encoder));
+    }
+
+    let bitrate = format!("{}k", profile.bitrate_kbps);
+    cmd.arg("-g")
+        .arg(profile.gop.to_string())
+        .arg("-keyint_min")
+        .arg(profile.gop.to_string())
+        .arg("-b:v")
+        .arg(&bitrate)
+        .arg("-maxrate")
+        .arg(&bitrate)
+        .arg("-bufsize")
+        .arg(&bitrate)
+        .arg("-bf")
+        .arg("0");
+    // ffmpeg checks stdin for commands once per stats period, half a second
+    // by default.
+    if profile.keyframe_control {
+        cmd.arg("-force_key_frames")
+            .arg("scd_metadata")
+            .args(profile.encoder.forced_idr_args())
+            .arg("-stats_period")
+            .arg("0.1");
+    }
+    Ok(())
+}
+
+// Outcome of the one-second test encode for one encoder and codec.
+#[derive(Clone, Debug, Serialize)]
+pub struct EncoderProbe {
+    pub video_codec: VideoCodec,
+    pub encoder: EncoderKind,
+    pub codec: &'static str,
+    pub available: bool,
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Startup probe results and the encoder new streams of each codec should use.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EncoderRegistry {
+    pub selected: HashMap<VideoCodec, EncoderKind>,
+    pub probes: Vec<EncoderProbe>,
+    // Whether the installed ffmpeg can force keyframes on a running encode.
+    pub keyframe_control: bool,
+}
+
+impl EncoderRegistry {
+    // Test encodes every encoder able to produce an enabled codec, fed the
+    // way the default capture source feeds it, and keeps the first working
+    // one in priority order per codec. Encoder families are probed side by
+    // side; a family's codecs go one after another since hardware encoders
+    // limit concurrent sessions.
+    pub async fn probe(
+        codecs: &[VideoCodec],
+        priority: &[EncoderKind],
+        memory: FrameMemory,
+    ) -> Self {
+        let deadline = Instant::now() + PROBE_BUDGET;
+        let keyframe_control = probe_keyframe_control().await;
+        let mut families = JoinSet::new();
+        for &encoder in priority {
+            let codecs = codecs.to_vec();
+            families.spawn(async move {
+                let mut probes = Vec::new();
+                for codec in codecs {
+                    probes.extend(
+                        probe_encoder(encoder, codec, memory, keyframe_control, deadline).await,
+                    );
+                }
+                probes
+            });
+        }
+        let mut probes = Vec::new();
+        while let Some(family) = families.join_next().await {
+            match family {
+                Ok(family) => probes.extend(family),
+                Err(err) => warn!("encoder_probe task failed error={err}"),
+            }
+        }
+
+        let mut registry = Self {
+            keyframe_control,
+            ..Self::default()
+        };
+        for &codec in codecs {
+            for &encoder in priority {
+                let Some(probe) = probes
+                    .iter()
+                    .find(|probe| probe.video_codec == codec && probe.encoder == encoder)
+                else {
+                    continue;
+                };
+                registry.probes.push(probe.clone());
+                if probe.available && !registry.selected.contains_key(&codec) {
+                    registry.selected.insert(codec, encoder);
+                }
+            }
+            match registry.selected.get(&codec) {
+                Some(encoder) => info!(
+                    "encoder_selected codec={} encoder={encoder:?}",
+                    codec.name()
+                ),
+                None => warn!(
+                    "encoder_probe no working encoder found codec={}",
+                    codec.name()
+                ),
+            }
+        }
+        registry
+    }
+
+    // Skips probing and trusts the first configured encoder of each codec,
+    // and ffmpeg to be recent enough for keyframe control.
+    pub fn unprobed(codecs: &[VideoCodec], priority: &[EncoderKind]) -> Self {
+        let selected = codecs
+            .iter()
+            .filter_map(|&codec| {
+                let encoder = priority
+                    .iter()
+                    .find(|encoder| encoder.codec_name(codec).is_some())?;
+                Some((codec, *encoder))
+            })
+            .collect();
+        Self {
+            selected,
+            probes: Vec::new(),
+            keyframe_control: true,
+        }
+    }
+
+    pub fn encoder_for(&self, codec: VideoCodec) -> Option<EncoderKind> {
+        self.selected.get(&codec).copied()
+    }
+}
+
+async fn probe_encoder(
+    encoder: EncoderKind,
+    codec: VideoCodec,
+    memory: FrameMemory,
+    keyframe_control: bool,
+    deadline: Instant,
+) -> Option<EncoderProbe> {
+    let codec_name = encoder.codec_name(codec)?;
+    let started = Instant::now();
+    let profile = EncoderProfile {
+        codec,
+        encoder,
+        keyframe_control,
+        ..EncoderProfile::default()
+    };
+    let timeout = deadline
+        .saturating_duration_since(started)
+        .min(PROBE_TIMEOUT);
+    let result = if timeout.is_zero() {
+        Err("probe budget exhausted".to_owned())
+    } else {
+        run_test_encode(&profile, memory, timeout).await
+    };
+    match &result {
+        Ok(()) => info!(
+            "encoder_probe encoder={codec_name} ok elapsed_ms={}",
+            started.elapsed().as_millis()
+        ),
+        Err(err) => warn!("encoder_probe encoder={codec_name} failed error={err}"),
+    }
+    Some(EncoderProbe {
+        video_codec: codec,
+        encoder,
+        codec: codec_name,
+        available: result.is_ok(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        error: result.err(),
+    })
+}
+
+// `-force_key_frames scd_metadata` needs ffmpeg 7.1; older builds encode
+// without keyframe control and leave viewers to the GOP.
+async fn probe_keyframe_control() -> bool {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg("testsrc2=size=64x64:rate=10")
+        .arg("-t")
+        .arg("0.2")
+        .arg("-vf")
+        .arg(KEYFRAME_FILTER)
+        .arg("-force_key_frames")
+        .arg("scd_metadata")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    match run_probe(cmd, PROBE_TIMEOUT).await {
+        Ok(()) => {
+            info!("keyframe_control_probe ok");
+            true
+        }
+        Err(err) => {
+            warn!("keyframe_control_probe failed error={err}");
+            false
+        }
+    }
+}
+
+// A second of test pattern through the capture's device setup and filter
+// chain. For D3D11 capture the pattern is uploaded to a D3D11 texture first,
+// so the encode sees the frames ddagrab hands it without needing a desktop.
+async fn run_test_encode(
+    profile: &EncoderProfile,
+    memory: FrameMemory,
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-hide_banner").arg("-loglevel").arg("error");
+    device_args(&mut cmd, profile.encoder, memory);
+    let upload = match memory {
+        FrameMemory::D3d11 => {
+            if profile.encoder != EncoderKind::Qsv {
+                cmd.arg("-init_hw_device")
+                    .arg("d3d11va=dx")
+                    .arg("-filter_hw_device")
+                    .arg("dx");
+            }
+            Some("format=bgra,hwupload")
+        }
+        FrameMemory::System => None,
+    };
+    cmd.arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg(format!("testsrc2=size=1280x720:rate={}", profile.framerate))
+        .arg("-t")
+        .arg("1");
+    encode_args(&mut cmd, profile, memory, upload)?;
+    cmd.arg("-f").arg("null").arg("-");
+    run_probe(cmd, timeout).await
+}
+
+// Runs a probe command to completion; failures carry ffmpeg's last line.
+async fn run_probe(mut cmd: Command, timeout: Duration) -> Result<(), String> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let child = cmd
+        .spawn()
+        .map_err(|err| format!("ffmpeg spawn failed: {err}"))?;
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| "probe timed out".to_owned())?
+        .map_err(|err| format!("probe failed: {err}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = stderr
+        .lines()
+        .last()
+        .unwrap_or("no output")
+        .trim()
+        .to_owned();
+    Err(format!("exit={} {reason}", output.status))
+}
@@ -0,0 +1,408 @@
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+const NAL_TYPE_AUD: u8 = 9;
+// HEVC NAL types: IRAP pictures (BLA, IDR, CRA) and the access unit delimiter.
+const HEVC_NAL_TYPE_BLA_W_LP: u8 = 16;
+const HEVC_NAL_TYPE_CRA: u8 = 21;
+const HEVC_NAL_TYPE_AUD: u8 = 35;
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+// Keeps consecutive frames from sharing an RTP timestamp when two arrive in
+// the same read before any duration was measured.
+const MIN_FRAME_DURATION: Duration = Duration::from_millis(1);
+
+// One encoded frame in Annex-B form, as published to subscribers.
+#[derive(Clone, Debug)]
+pub struct AccessUnit {
+    pub data: Bytes,
+    pub keyframe: bool,
+    pub duration: Duration,
+}
+
+// Splits an Annex-B byte stream into access units delimited by AUD NALs.
+// The buffer always starts at the current access unit, so a finished unit is
+// split off the front without copying and scanning resumes where it stopped.
+//
+// Each unit's duration is the time between its AUD and the next one reaching
+// us. The encoder writes frames as the capture produces them, so this follows
+// the real capture cadence, including a variable rate on a static desktop.
+// Units completed by the same read as the one before them have no measurable
+// duration and reuse the last measured one, so a burst after a stall keeps
+// its pacing rather than collapsing into 1 ms frames.
+//
+// The latest SPS/PPS are cached and prepended to any IDR that arrives without
+// them, so a decoder that missed them can still start from that IDR.
+//
+// HEVC streams split the same way; only their AUDs and IRAP pictures are
+// recognised, and the encoder is relied on to repeat the parameter sets.
+#[derive(Default)]
+pub struct AccessUnitAssembler {
+    hevc: bool,
+    buffer: BytesMut,
+    scan_pos: usize,
+    has_nal: bool,
+    keyframe: bool,
+    started_at: Option<Instant>,
+    last_duration: Option<Duration>,
+    nals: Vec<NalPosition>,
+    sps: Option<Bytes>,
+    pps: Option<Bytes>,
+}
+
+// Where a NAL of the current access unit sits in the buffer.
+struct NalPosition {
+    nal_type: u8,
+    offset: usize,
+    payload: usize,
+}
+
+impl AccessUnitAssembler {
+    pub fn hevc() -> Self {
+        Self {
+            hevc: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8], arrived_at: Instant) -> Vec<AccessUnit> {
+        self.buffer.extend_from_slice(data);
+        let mut completed = Vec::new();
+        while let Some(start_code) = find_start_code(&self.buffer, self.scan_pos) {
+            // The NAL header byte follows the start code; wait for it.
+            let Some(&header) = self.buffer.get(start_code.payload) else {
+                self.scan_pos = start_code.offset;
+                return completed;
+            };
+            let nal_type = self.nal_type(header);
+            let mut offset = start_code.offset;
+            let mut payload = start_code.payload;
+            if !self.has_nal {
+                // Bytes ahead of the first start code are not part of any NAL.
+                self.buffer.advance(offset);
+                payload -= offset;
+                offset = 0;
+                self.started_at = Some(arrived_at);
+            } else if nal_type == NAL_TYPE_AUD {
+                completed.push(self.split_access_unit(offset, arrived_at));
+                payload -= offset;
+                offset = 0;
+            }
+            self.nals.push(NalPosition {
+                nal_type,
+                offset,
+                payload,
+            });
+            self.has_nal = true;
+            self.keyframe |= nal_type == NAL_TYPE_IDR;
+            self.scan_pos = payload + 1;
+        }
+        // A start code may straddle the next read.
+        self.scan_pos = self.scan_pos.max(self.buffer.len().saturating_sub(3));
+        completed
+    }
+
+    pub fn finish(&mut self, ended_at: Instant) -> Option<AccessUnit> {
+        if !self.has_nal {
+            return None;
+        }
+        let access_unit = self.split_access_unit(self.buffer.len(), ended_at);
+        self.has_nal = false;
+        Some(access_unit)
+    }
+
+    // The cached SPS and PPS as an Annex-B prefix.
+    pub fn parameter_sets(&self) -> Option<Bytes> {
+        let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+            return None;
+        };
+        let mut sets = BytesMut::with_capacity(2 * START_CODE.len() + sps.len() + pps.len());
+        for nal in [sps, pps] {
+            sets.extend_from_slice(&START_CODE);
+            sets.extend_from_slice(nal);
+        }
+        Some(sets.freeze())
+    }
+
+    // The NAL type in H264 numbering; HEVC types without an H264 counterpart
+    // the assembler cares about map to 0.
+    fn nal_type(&self, header: u8) -> u8 {
+        if !self.hevc {
+            return header & 0x1F;
+        }
+        match (header >> 1) & 0x3F {
+            HEVC_NAL_TYPE_AUD => NAL_TYPE_AUD,
+            HEVC_NAL_TYPE_BLA_W_LP..=HEVC_NAL_TYPE_CRA => NAL_TYPE_IDR,
+            _ => 0,
+        }
+    }
+
+    // Closes the current unit at `end`; the next one starts at `now`.
+    fn split_access_unit(&mut self, end: usize, now: Instant) -> AccessUnit {
+        self.scan_pos = 0;
+        let measured = self
+            .started_at
+            .replace(now)
+            .map(|started_at| now.duration_since(started_at))
+            .filter(|elapsed| *elapsed >= MIN_FRAME_DURATION);
+        if measured.is_some() {
+            self.last_duration = measured;
+        }
+        let duration = self.last_duration.unwrap_or(MIN_FRAME_DURATION);
+        let data = self.buffer.split_to(end).freeze();
+        let nals = std::mem::take(&mut self.nals);
+        let keyframe = std::mem::take(&mut self.keyframe);
+        AccessUnit {
+            data: self.apply_parameter_sets(data, &nals, keyframe),
+            keyframe,
+            duration,
+        }
+    }
+
+    fn apply_parameter_sets(&mut self, data: Bytes, nals: &[NalPosition], keyframe: bool) -> Bytes {
+        let mut has_sps = false;
+        let mut has_pps = false;
+        for (index, nal) in nals.iter().enumerate() {
+            let end = nals.get(index + 1).map_or(data.len(), |next| next.offset);
+            // Copied so the cache does not pin the whole IDR in memory.
+            match nal.nal_type {
+                NAL_TYPE_SPS => {
+                    has_sps = true;
+                    self.sps = Some(Bytes::copy_from_slice(&data[nal.payload..end]));
+                }
+                NAL_TYPE_PPS => {
+                    has_pps = true;
+                    self.pps = Some(Bytes::copy_from_slice(&data[nal.payload..end]));
+                }
+                _ => {}
+            }
+        }
+        if !keyframe || (has_sps && has_pps) {
+            return data;
+        }
+        let Some(parameter_sets) = self.parameter_sets() else {
+            return data;
+        };
+
+        // The AUD has to stay the first NAL of the unit.
+        let insert_at = match nals {
+            [first, second, ..] if first.nal_type == NAL_TYPE_AUD => second.offset,
+            [first] if first.nal_type == NAL_TYPE_AUD => data.len(),
+            _ => 0,
+        };
+        let mut patched = BytesMut::with_capacity(data.len() + parameter_sets.len());
+        patched.extend_from_slice(&data[..insert_at]);
+        patched.extend_from_slice(&parameter_sets);
+        patched.extend_from_slice(&data[insert_at..]);
+        patched.freeze()
+    }
+}
+
+// profile_idc, constraint flags and level_idc of the SPS at the front of an
+// Annex-B parameter set prefix, i.e. its SDP `profile-level-id`.
+pub fn profile_level_id(parameter_sets: &[u8]) -> Option<[u8; 3]> {
+    let start_code = find_start_code(parameter_sets, 0)?;
+    let sps = parameter_sets.get(start_code.payload..start_code.payload + 4)?;
+    if sps[0] & 0x1F != NAL_TYPE_SPS {
+        return None;
+    }
+    Some([sps[1], sps[2], sps[3]])
+}
+
+// NAL units of an Annex-B buffer, without their start codes.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut next = find_start_code(data, 0);
+    while let Some(start_code) = next {
+        next = find_start_code(data, start_code.payload);
+        let end = next.as_ref().map_or(data.len(), |next| next.offset);
+        if end > start_code.payload {
+            nals.push(&data[start_code.payload..end]);
+        }
+    }
+    nals
+}
+
+struct StartCode {
+    // First byte of the start code, including the leading zero of a 4-byte one.
+    offset: usize,
+    // First byte after the start code, i.e. the NAL header.
+    payload: usize,
+}
+
+fn find_start_code(buffer: &[u8], from: usize) -> Option<StartCode> {
+    let mut i = from;
+    while i + 2 < buffer.len() {
+        // No 00 00 01 can start at i, i+1 or i+2 when buffer[i+2] exceeds 1.
+        if buffer[i + 2] > 1 {
+            i += 3;
+        } else if buffer[i + 2] == 1 && buffer[i + 1] == 0 && buffer[i] == 0 {
+            let offset = if i > 0 && buffer[i - 1] == 0 {
+                i - 1
+            } else {
+                i
+            };
+            return Some(StartCode {
+                offset,
+                payload: i + 3,
+            });
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUD: &[u8] = &[0, 0, 0, 1, 0x09, 0xF0];
+    const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0xDA];
+    const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80];
+    const IDR: &[u8] = &[0, 0, 1, 0x65, 0x88, 0x84, 0x21];
+    const P: &[u8] = &[0, 0, 1, 0x41, 0x9A, 0x02];
+
+    fn stream(units: &[&[&[u8]]]) -> Vec<u8> {
+        units.iter().flat_map(|unit| unit.concat()).collect()
+    }
+
+    fn assemble(stream: &[u8], chunk_size: usize) -> Vec<AccessUnit> {
+        let mut assembler = AccessUnitAssembler::default();
+        let now = Instant::now();
+        let mut units = Vec::new();
+        for chunk in stream.chunks(chunk_size) {
+            units.extend(assembler.push(chunk, now));
+        }
+        units.extend(assembler.finish(now));
+        units
+    }
+
+    #[test]
+    fn splits_on_aud_with_either_start_code_length() {
+        let short_aud = &[0, 0, 1, 0x09, 0xF0][..];
+        let data = stream(&[&[AUD, SPS, PPS, IDR], &[short_aud, P], &[AUD, P]]);
+        let units = assemble(&data, data.len());
+
+        assert_eq!(units.len(), 3);
+        assert_eq!(units[0].data, [AUD, SPS, PPS, IDR].concat());
+        assert!(units[0].keyframe);
+        assert_eq!(units[1].data, [short_aud, P].concat());
+        assert!(!units[1].keyframe);
+        assert_eq!(units[2].data, [AUD, P].concat());
+    }
+
+    #[test]
+    fn start_codes_split_across_reads() {
+        let data = stream(&[&[AUD, SPS, PPS, IDR], &[AUD, P], &[AUD, P]]);
+        let whole = assemble(&data, data.len());
+        for chunk_size in 1..8 {
+            let split = assemble(&data, chunk_size);
+            assert_eq!(split.len(), whole.len(), "chunk size {chunk_size}");
+            for (split, whole) in split.iter().zip(&whole) {
+                assert_eq!(split.data, whole.data, "chunk size {chunk_size}");
+                assert_eq!(split.keyframe, whole.keyframe);
+            }
+        }
+    }
+
+    #[test]
+    fn drops_bytes_before_the_first_start_code() {
+        let data = [&[0x12, 0x34][..], AUD, P].concat();
+        let units = assemble(&data, data.len());
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].data, [AUD, P].concat());
+    }
+
+    #[test]
+    fn injects_cached_parameter_sets_before_a_bare_idr() {
+        let data = stream(&[&[AUD, SPS, PPS, IDR], &[AUD, P], &[AUD, IDR]]);
+        let units = assemble(&data, data.len());
+
+        assert_eq!(units[2].data, [AUD, SPS, PPS, IDR].concat());
+        assert!(units[2].keyframe);
+        assert_eq!(units[1].data, [AUD, P].concat());
+    }
+
+    #[test]
+    fn injects_parameter_sets_at_the_front_without_an_aud() {
+        let mut assembler = AccessUnitAssembler::default();
+        let now = Instant::now();
+        assembler.push(&[SPS, PPS, IDR].concat(), now);
+        assembler.finish(now);
+
+        assembler.push(IDR, now);
+        let unit = assembler.finish(now).unwrap();
+        assert_eq!(unit.data, [SPS, PPS, IDR].concat());
+    }
+
+    #[test]
+    fn leaves_a_bare_idr_alone_until_parameter_sets_are_seen() {
+        let data = stream(&[&[AUD, IDR], &[AUD, P]]);
+        let units = assemble(&data, data.len());
+        assert_eq!(units[0].data, [AUD, IDR].concat());
+    }
+
+    #[test]
+    fn units_sharing_a_read_reuse_the_last_measured_duration() {
+        let mut assembler = AccessUnitAssembler::default();
+        let start = Instant::now();
+        assembler.push(&[AUD, P].concat(), start);
+        let first = assembler.push(&[AUD, P].concat(), start + Duration::from_millis(33));
+        assert_eq!(first[0].duration, Duration::from_millis(33));
+
+        let burst = assembler.push(
+            &[AUD, P, AUD, P, AUD, P].concat(),
+            start + Duration::from_millis(200),
+        );
+        let durations: Vec<_> = burst.iter().map(|unit| unit.duration).collect();
+        assert_eq!(
+            durations,
+            [
+                Duration::from_millis(167),
+                Duration::from_millis(167),
+                Duration::from_millis(167)
+            ]
+        );
+    }
+
+    #[test]
+    fn first_units_without_a_measurement_get_the_minimum_duration() {
+        let data = stream(&[&[AUD, P], &[AUD, P]]);
+        let units = assemble(&data, data.len());
+        assert_eq!(units[0].duration, MIN_FRAME_DURATION);
+    }
+
+    #[test]
+    fn hevc_irap_pictures_are_keyframes() {
+        let aud = &[0, 0, 0, 1, 0x46, 0x01, 0x50][..];
+        let idr_w_radl = &[0, 0, 1, 0x26, 0x01, 0xAF][..];
+        let trail = &[0, 0, 1, 0x02, 0x01, 0xD0][..];
+        let mut assembler = AccessUnitAssembler::hevc();
+        let now = Instant::now();
+        let mut units = assembler.push(&[aud, idr_w_radl, aud, trail].concat(), now);
+        units.extend(assembler.finish(now));
+
+        assert_eq!(units.len(), 2);
+        assert!(units[0].keyframe);
+        assert!(!units[1].keyframe);
+    }
+
+    #[test]
+    fn profile_level_id_reads_the_leading_sps() {
+        assert_eq!(
+            profile_level_id(&[SPS, PPS].concat()),
+            Some([0x42, 0xC0, 0x1E])
+        );
+        assert_eq!(profile_level_id(&[PPS, SPS].concat()), None);
+    }
+
+    #[test]
+    fn nal_units_strip_start_codes() {
+        let data = [AUD, IDR].concat();
+        assert_eq!(nal_units(&data), [&AUD[4..], &IDR[3..]]);
+    }
+}
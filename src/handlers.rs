@@ -1,22 +1,167 @@
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     Json,
 };
 
 use crate::{
+    control::{ControlCommand, ControlReply},
+    http_util::bearer_token,
     models::{
-        ApiResponse, IceCandidatePayload, PollQuery, SdpPayload, SessionPeerQuery, SignalMessage,
+        ApiResponse, EndOfCandidatesPayload, IceCandidatePayload, OfferQuery, PollQuery,
+        SdpPayload, SessionPeerQuery, SignalMessage,
     },
-    service::{join_session, leave_session, route_signal_message},
+    service::{join_session, leave_session, route_offer, route_signal_message},
     state::AppState,
+    whep, whip,
 };
 
 pub async fn health() -> &'static str {
     "ok"
 }
 
+// Admin routes take `Authorization: Bearer <ADMIN_TOKEN>` and stay closed
+// while ADMIN_TOKEN is unset.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state
+        .media_bridge
+        .authorizes_admin(bearer_token(request.headers()))
+    {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Reports the encoder probe results and the encoder new streams use.
+pub async fn encoders_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.media_bridge.encoders().clone())
+}
+
+// Lists the ids of the streams currently served to viewers.
+pub async fn streams_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.media_bridge.stream_ids().await)
+}
+
+// Reports one stream's send rates, frame sizes and receiver feedback.
+pub async fn stream_stats_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    state
+        .media_bridge
+        .stream_stats(&id)
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Starts recording a stream to RECORDING_DIR; replies like the control
+// channel does.
+pub async fn start_recording_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    control_stream(&state, &id, ControlCommand::StartRecording).await
+}
+
+pub async fn stop_recording_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    control_stream(&state, &id, ControlCommand::StopRecording).await
+}
+
+// Saves the stream's instant-replay buffer to an MP4 clip in REPLAY_DIR; the
+// reply carries its path.
+pub async fn save_replay_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    control_stream(&state, &id, ControlCommand::SaveReplay).await
+}
+
+// WHEP playback of a capture source or a WHIP room: SDP offer in, SDP answer
+// out.
+pub async fn whep_offer_handler(
+    State(state): State<AppState>,
+    Path(source): Path<String>,
+    headers: HeaderMap,
+    sdp: String,
+) -> impl IntoResponse {
+    whep::offer(state, source, &headers, sdp).await
+}
+
+pub async fn whep_trickle_handler(
+    State(state): State<AppState>,
+    Path((source, resource_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    fragment: String,
+) -> impl IntoResponse {
+    whep::trickle(state, source, resource_id, &headers, fragment).await
+}
+
+pub async fn whep_delete_handler(
+    State(state): State<AppState>,
+    Path((source, resource_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    whep::delete(state, source, resource_id).await
+}
+
+// WHIP publish into a room: SDP offer in, SDP answer out.
+pub async fn whip_offer_handler(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    headers: HeaderMap,
+    sdp: String,
+) -> impl IntoResponse {
+    whip::offer(state, room, &headers, sdp).await
+}
+
+pub async fn whip_trickle_handler(
+    State(state): State<AppState>,
+    Path((room, resource_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    fragment: String,
+) -> impl IntoResponse {
+    whip::trickle(state, room, resource_id, &headers, fragment).await
+}
+
+pub async fn whip_delete_handler(
+    State(state): State<AppState>,
+    Path((room, resource_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    whip::delete(state, room, resource_id, &headers).await
+}
+
+async fn control_stream(
+    state: &AppState,
+    id: &str,
+    command: ControlCommand,
+) -> Result<(StatusCode, Json<ControlReply>), StatusCode> {
+    let reply = state
+        .media_bridge
+        .control_stream(id, command)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let status = if reply.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Ok((status, Json(reply)))
+}
+
 fn empty_poll() -> Json<Vec<SignalMessage>> {
     Json(Vec::new())
 }
@@ -37,8 +182,9 @@ where
 pub async fn join_handler(
     State(state): State<AppState>,
     Query(query): Query<SessionPeerQuery>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    join_session(state, query).await
+    join_session(state, query, bearer_token(&headers)).await
 }
 
 // Removes peer from session and informs remaining peers about disconnect.
@@ -49,18 +195,19 @@ pub async fn leave_handler(
     leave_session(state, query).await
 }
 
-// Receives one offer and routes it to the target peer inbox.
+// Receives one offer and routes it to the target peer inbox or the bot.
 pub async fn offer_handler(
     State(state): State<AppState>,
-    Query(query): Query<SessionPeerQuery>,
+    Query(query): Query<OfferQuery>,
     Json(payload): Json<SdpPayload>,
 ) -> impl IntoResponse {
-    route_payload(state, query, payload, |payload| SignalMessage::Offer {
+    let msg = SignalMessage::Offer {
         from: payload.from,
         to: payload.to,
         sdp: payload.sdp,
-    })
-    .await
+        resume_token: payload.resume_token,
+    };
+    route_offer(state, query, msg).await
 }
 
 // Receives one answer and routes it to the target peer inbox.
@@ -73,6 +220,7 @@ pub async fn answer_handler(
         from: payload.from,
         to: payload.to,
         sdp: payload.sdp,
+        resume_token: payload.resume_token,
     })
     .await
 }
@@ -91,6 +239,19 @@ pub async fn ice_candidate_handler(
     .await
 }
 
+// Receives the end-of-candidates marker and routes it to the target peer inbox.
+pub async fn end_of_candidates_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SessionPeerQuery>,
+    Json(payload): Json<EndOfCandidatesPayload>,
+) -> impl IntoResponse {
+    route_payload(state, query, payload, |payload| SignalMessage::EndOfCandidates {
+        from: payload.from,
+        to: payload.to,
+    })
+    .await
+}
+
 // Poll endpoint returns and drains all queued messages for a peer.
 pub async fn poll_handler(
     State(state): State<AppState>,
@@ -0,0 +1,49 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+
+use crate::state::AppState;
+
+pub fn has_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+// The token of an `Authorization: Bearer` header, as the join, WHIP and
+// admin endpoints take it.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// The ICE servers polling clients get from /signal/join, as the `Link`
+// headers WHEP and WHIP clients read them from.
+pub fn ice_server_links(
+    state: &AppState,
+    resource_id: &str,
+    headers: &HeaderMap,
+) -> Vec<HeaderValue> {
+    let mut links = Vec::new();
+    let servers = state
+        .media_bridge
+        .ice_servers_for(resource_id, bearer_token(headers));
+    for server in servers {
+        for url in &server.urls {
+            let mut link = format!("<{url}>; rel=\"ice-server\"");
+            if !server.username.is_empty() {
+                link.push_str(&format!(
+                    "; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                    server.username, server.credential
+                ));
+            }
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                links.push(link);
+            }
+        }
+    }
+    links
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{
+        broadcast::{self, error::RecvError},
+        RwLock,
+    },
+};
+use tracing::{info, warn};
+use webrtc::{
+    api::{
+        interceptor_registry::{configure_nack, configure_rtcp_reports},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+        APIBuilder,
+    },
+    interceptor::registry::Registry,
+    media::io::sample_builder::SampleBuilder,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp::codecs::h264::H264Packet,
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        rtp_receiver::RTCRtpReceiver,
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+        RTCRtpTransceiverInit,
+    },
+    track::track_remote::TrackRemote,
+};
+
+use crate::{
+    capture::{CaptureSource, CaptureStream},
+    encoder::{EncoderProfile, VideoCodec},
+    media_bridge,
+};
+
+pub const SOURCE_NAME: &str = "whip";
+// Frames and Opus packets kept for relay pipelines that fall behind.
+const INGEST_BUFFER: usize = 256;
+// Bytes of Annex-B a relay pipeline may have unread before the copy waits.
+const RELAY_PIPE_BYTES: usize = 1 << 20;
+// RTP packets a frame may wait for a reordered or retransmitted one.
+const MAX_LATE_PACKETS: u16 = 512;
+// How long the answer waits for ICE gathering so it carries every candidate.
+const ICE_GATHER_WAIT: Duration = Duration::from_secs(5);
+const AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xF0];
+// Packetization mode 1 in every profile a hardware or x264 encoder is likely
+// to publish; webrtc-rs matches on profile, not level.
+const H264_PROFILE_LEVEL_IDS: [&str; 4] = ["42001f", "42e01f", "4d001f", "640032"];
+
+// Streams published over WHIP, at most one per signaling session ("room").
+// While a room has one, its viewers are served the published stream instead
+// of a local capture.
+#[derive(Default)]
+pub struct IngestRegistry {
+    rooms: RwLock<HashMap<String, Arc<Ingest>>>,
+}
+
+// One publisher's peer connection and the media it sends: H264 access units
+// with an AUD in front, as the capture pipeline expects, and Opus packets.
+// Dropping the senders on close ends every relay reading from them.
+pub struct Ingest {
+    room: String,
+    resource_id: String,
+    peer_connection: Arc<RTCPeerConnection>,
+    video: StdMutex<Option<broadcast::Sender<Bytes>>>,
+    audio: StdMutex<Option<broadcast::Sender<Bytes>>>,
+    video_ssrc: AtomicU32,
+    offers_audio: bool,
+}
+
+impl IngestRegistry {
+    pub async fn get(&self, room: &str) -> Option<Arc<Ingest>> {
+        self.rooms.read().await.get(room).cloned()
+    }
+
+    // Answers a publisher's offer; the answer carries every candidate since
+    // WHIP clients get no trickle from the server.
+    pub async fn publish(
+        self: &Arc<Self>,
+        room: &str,
+        resource_id: &str,
+        offer_sdp: String,
+        setting_engine: SettingEngine,
+        configuration: RTCConfiguration,
+    ) -> Result<String, String> {
+        if self.get(room).await.is_some() {
+            return Err(format!("room {room} already has a WHIP publisher"));
+        }
+        let peer_connection = Arc::new(
+            ingest_api(setting_engine)?
+                .new_peer_connection(configuration)
+                .await
+                .map_err(|err| format!("new_peer_connection failed: {err}"))?,
+        );
+        let (video, _) = broadcast::channel(INGEST_BUFFER);
+        let (audio, _) = broadcast::channel(INGEST_BUFFER);
+        let ingest = Arc::new(Ingest {
+            room: room.to_owned(),
+            resource_id: resource_id.to_owned(),
+            peer_connection: peer_connection.clone(),
+            video: StdMutex::new(Some(video)),
+            audio: StdMutex::new(Some(audio)),
+            video_ssrc: AtomicU32::new(0),
+            offers_audio: offer_sdp.contains("m=audio"),
+        });
+        attach_handlers(self, &ingest);
+
+        let answer = match answer_offer(&peer_connection, offer_sdp).await {
+            Ok(answer) => answer,
+            Err(err) => {
+                let _ = peer_connection.close().await;
+                return Err(err);
+            }
+        };
+        // Another publisher may have got in while this one gathered.
+        let mut rooms = self.rooms.write().await;
+        if rooms.contains_key(room) {
+            drop(rooms);
+            ingest.close().await;
+            return Err(format!("room {room} already has a WHIP publisher"));
+        }
+        rooms.insert(room.to_owned(), ingest);
+        info!("whip_published room={room} resource={resource_id}");
+        Ok(answer)
+    }
+
+    // Returns false when the room has no publisher with that resource id.
+    pub async fn unpublish(&self, room: &str, resource_id: &str) -> bool {
+        let mut rooms = self.rooms.write().await;
+        let ingest = match rooms.get(room) {
+            Some(ingest) if ingest.resource_id == resource_id => ingest.clone(),
+            _ => return false,
+        };
+        rooms.remove(room);
+        drop(rooms);
+        ingest.close().await;
+        info!("whip_unpublished room={room} resource={resource_id}");
+        true
+    }
+
+    pub async fn find(&self, room: &str, resource_id: &str) -> Option<Arc<Ingest>> {
+        self.get(room)
+            .await
+            .filter(|ingest| ingest.resource_id == resource_id)
+    }
+
+    // Drops a publisher whose connection died, unless it was replaced.
+    async fn remove(&self, ingest: &Arc<Ingest>) {
+        let mut rooms = self.rooms.write().await;
+        if rooms
+            .get(&ingest.room)
+            .is_some_and(|current| Arc::ptr_eq(current, ingest))
+        {
+            rooms.remove(&ingest.room);
+            drop(rooms);
+            ingest.close().await;
+            info!("whip_publisher_gone room={}", ingest.room);
+        }
+    }
+}
+
+impl Ingest {
+    pub fn has_audio(&self) -> bool {
+        self.offers_audio
+    }
+
+    pub fn subscribe_audio(&self) -> Option<broadcast::Receiver<Bytes>> {
+        self.audio
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+    }
+
+    pub fn capture_source(self: &Arc<Self>) -> Arc<dyn CaptureSource> {
+        Arc::new(RelaySource {
+            ingest: self.clone(),
+        })
+    }
+
+    pub fn peer_connection(&self) -> &RTCPeerConnection {
+        &self.peer_connection
+    }
+
+    fn subscribe_video(&self) -> Option<broadcast::Receiver<Bytes>> {
+        self.video
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+    }
+
+    // Sending fails only while no relay is subscribed, which is fine.
+    fn publish(sender: &StdMutex<Option<broadcast::Sender<Bytes>>>, data: Bytes) {
+        if let Some(sender) = sender
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+        {
+            let _ = sender.send(data);
+        }
+    }
+
+    fn request_keyframe(&self) -> bool {
+        let media_ssrc = self.video_ssrc.load(Ordering::SeqCst);
+        if media_ssrc == 0 {
+            return false;
+        }
+        let peer_connection = self.peer_connection.clone();
+        tokio::spawn(async move {
+            let pli = PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            };
+            if let Err(err) = peer_connection.write_rtcp(&[Box::new(pli)]).await {
+                warn!("whip_pli failed error={err}");
+            }
+        });
+        true
+    }
+
+    async fn close(&self) {
+        self.video
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        self.audio
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        let _ = self.peer_connection.close().await;
+    }
+}
+
+fn ingest_api(setting_engine: SettingEngine) -> Result<webrtc::api::API, String> {
+    let mut media_engine = MediaEngine::default();
+    for (index, profile_level_id) in H264_PROFILE_LEVEL_IDS.into_iter().enumerate() {
+        media_engine
+            .register_codec(
+                RTCRtpCodecParameters {
+                    capability: RTCRtpCodecCapability {
+                        mime_type: "video/H264".to_owned(),
+                        clock_rate: 90_000,
+                        channels: 0,
+                        sdp_fmtp_line: format!(
+                            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={profile_level_id}"
+                        ),
+                        rtcp_feedback: media_bridge::video_rtcp_feedback(),
+                    },
+                    payload_type: 102 + index as u8,
+                    ..Default::default()
+                },
+                RTPCodecType::Video,
+            )
+            .map_err(|err| format!("register_codec video failed: {err}"))?;
+    }
+    media_engine
+        .register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: "audio/opus".to_owned(),
+                    clock_rate: 48_000,
+                    channels: 2,
+                    sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                    rtcp_feedback: vec![],
+                },
+                payload_type: 111,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )
+        .map_err(|err| format!("register_codec audio failed: {err}"))?;
+    let registry = configure_rtcp_reports(configure_nack(Registry::new(), &mut media_engine));
+    Ok(APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(setting_engine)
+        .build())
+}
+
+fn attach_handlers(registry: &Arc<IngestRegistry>, ingest: &Arc<Ingest>) {
+    let weak_ingest = Arc::downgrade(ingest);
+    ingest
+        .peer_connection
+        .on_track(Box::new(move |track, _: Arc<RTCRtpReceiver>, _| {
+            let weak_ingest = weak_ingest.clone();
+            Box::pin(async move {
+                let Some(ingest) = weak_ingest.upgrade() else {
+                    return;
+                };
+                match track.kind() {
+                    RTPCodecType::Video => {
+                        tokio::spawn(read_video(ingest, track));
+                    }
+                    RTPCodecType::Audio => {
+                        tokio::spawn(read_audio(ingest, track));
+                    }
+                    _ => {}
+                }
+            })
+        }));
+
+    let weak_registry: Weak<IngestRegistry> = Arc::downgrade(registry);
+    let weak_ingest = Arc::downgrade(ingest);
+    ingest
+        .peer_connection
+        .on_peer_connection_state_change(Box::new(move |state| {
+            let weak_registry = weak_registry.clone();
+            let weak_ingest = weak_ingest.clone();
+            Box::pin(async move {
+                info!("whip peer_connection_state={state:?}");
+                if !matches!(
+                    state,
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                ) {
+                    return;
+                }
+                if let (Some(registry), Some(ingest)) =
+                    (weak_registry.upgrade(), weak_ingest.upgrade())
+                {
+                    registry.remove(&ingest).await;
+                }
+            })
+        }));
+}
+
+async fn answer_offer(
+    peer_connection: &RTCPeerConnection,
+    offer_sdp: String,
+) -> Result<String, String> {
+    // Receive-only, so nothing is sent back but RTCP.
+    for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+        peer_connection
+            .add_transceiver_from_kind(
+                kind,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: vec![],
+                }),
+            )
+            .await
+            .map_err(|err| format!("add_transceiver failed: {err}"))?;
+    }
+    peer_connection
+        .set_remote_description(
+            RTCSessionDescription::offer(offer_sdp)
+                .map_err(|err| format!("offer sdp parse failed: {err}"))?,
+        )
+        .await
+        .map_err(|err| format!("set_remote_description failed: {err}"))?;
+    let answer = peer_connection
+        .create_answer(None)
+        .await
+        .map_err(|err| format!("create_answer failed: {err}"))?;
+    let mut gathered = peer_connection.gathering_complete_promise().await;
+    peer_connection
+        .set_local_description(answer.clone())
+        .await
+        .map_err(|err| format!("set_local_description failed: {err}"))?;
+    if tokio::time::timeout(ICE_GATHER_WAIT, gathered.recv())
+        .await
+        .is_err()
+    {
+        warn!("whip ice gathering incomplete, answering with the candidates so far");
+    }
+    Ok(peer_connection
+        .local_description()
+        .await
+        .unwrap_or(answer)
+        .sdp)
+}
+
+// Reassembles frames from RTP. A publisher that leaves out AUDs gets one in
+// front of every frame so the relay pipeline can split them again.
+async fn read_video(ingest: Arc<Ingest>, track: Arc<TrackRemote>) {
+    let mime_type = track.codec().capability.mime_type;
+    if !mime_type.eq_ignore_ascii_case("video/H264") {
+        warn!("whip_track_ignored room={} codec={mime_type}", ingest.room);
+        return;
+    }
+    ingest.video_ssrc.store(track.ssrc(), Ordering::SeqCst);
+    info!("whip_track_started room={} kind=video", ingest.room);
+    // Viewers may already be waiting for a picture.
+    ingest.request_keyframe();
+    let mut samples = SampleBuilder::new(MAX_LATE_PACKETS, H264Packet::default(), 90_000);
+    while let Ok((packet, _)) = track.read_rtp().await {
+        samples.push(packet);
+        while let Some(sample) = samples.pop() {
+            let starts_with_aud = sample.data.starts_with(&AUD[..4])
+                && sample
+                    .data
+                    .get(4)
+                    .is_some_and(|header| header & 0x1F == AUD[4]);
+            let frame = if starts_with_aud {
+                sample.data
+            } else {
+                let mut frame = BytesMut::with_capacity(AUD.len() + sample.data.len());
+                frame.extend_from_slice(&AUD);
+                frame.extend_from_slice(&sample.data);
+                frame.freeze()
+            };
+            Ingest::publish(&ingest.video, frame);
+        }
+    }
+    info!("whip_track_ended room={} kind=video", ingest.room);
+}
+
+// Each RTP payload is one Opus packet, which is what viewers' tracks take.
+async fn read_audio(ingest: Arc<Ingest>, track: Arc<TrackRemote>) {
+    info!("whip_track_started room={} kind=audio", ingest.room);
+    while let Ok((packet, _)) = track.read_rtp().await {
+        if !packet.payload.is_empty() {
+            Ingest::publish(&ingest.audio, packet.payload);
+        }
+    }
+    info!("whip_track_ended room={} kind=audio", ingest.room);
+}
+
+// A room's published video fed to a capture pipeline as if it came from
+// ffmpeg, so relayed viewers share pipelines, stats, recording and replay
+// with captured ones. Nothing is re-encoded: keyframe requests become PLIs
+// to the publisher and bitrate changes are not possible.
+struct RelaySource {
+    ingest: Arc<Ingest>,
+}
+
+impl CaptureSource for RelaySource {
+    fn name(&self) -> &'static str {
+        SOURCE_NAME
+    }
+
+    fn key(&self) -> String {
+        format!("{SOURCE_NAME}/{}", self.ingest.room)
+    }
+
+    fn start(&self, profile: &EncoderProfile) -> Result<CaptureStream, String> {
+        if profile.codec != VideoCodec::H264 {
+            return Err(format!(
+                "WHIP relay carries H264, not {}",
+                profile.codec.name()
+            ));
+        }
+        let Some(mut frames) = self.ingest.subscribe_video() else {
+            return Err(format!("WHIP publisher left room {}", self.ingest.room));
+        };
+        let (mut writer, reader) = tokio::io::duplex(RELAY_PIPE_BYTES);
+        let room = self.ingest.room.clone();
+        tokio::spawn(async move {
+            loop {
+                match frames.recv().await {
+                    Ok(frame) => {
+                        if writer.write_all(&frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("whip_relay lagged room={room} skipped={skipped}");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(CaptureStream::from_reader(reader))
+    }
+
+    fn encodes(&self) -> bool {
+        false
+    }
+
+    fn request_keyframe(&self) -> bool {
+        self.ingest.request_keyframe()
+    }
+
+    fn codec(&self) -> Option<VideoCodec> {
+        Some(VideoCodec::H264)
+    }
+}
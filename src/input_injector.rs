@@ -1,6 +1,7 @@
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(not(windows), allow(dead_code))]
 struct InputEvent {
     kind: String,
     #[serde(default)]
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BytesMut};
+
+use crate::h264::AccessUnit;
+
+const FILE_HEADER_MIN: usize = 32;
+const FRAME_HEADER_LEN: usize = 12;
+const MIN_FRAME_DURATION: Duration = Duration::from_millis(1);
+const AV1_OBU_SEQUENCE_HEADER: u8 = 1;
+const AV1_OBU_FRAME_HEADER: u8 = 3;
+const AV1_OBU_FRAME: u8 = 6;
+
+// Splits an IVF stream (VP8, VP9 or AV1) into frames. IVF prefixes every
+// frame with its size, so a frame is published as soon as its last byte
+// arrives. Its duration is the gap since the previous frame arrived, which
+// follows the capture cadence like the Annex-B assembler does without holding
+// a frame back; frames sharing a read reuse the last measured gap.
+#[derive(Default)]
+pub struct IvfDemuxer {
+    buffer: BytesMut,
+    fourcc: Option<[u8; 4]>,
+    last_arrival: Option<Instant>,
+    last_duration: Option<Duration>,
+}
+
+impl IvfDemuxer {
+    pub fn push(&mut self, data: &[u8], arrived_at: Instant) -> Result<Vec<AccessUnit>, String> {
+        self.buffer.extend_from_slice(data);
+        if self.fourcc.is_none() {
+            if self.buffer.len() < FILE_HEADER_MIN {
+                return Ok(Vec::new());
+            }
+            if &self.buffer[..4] != b"DKIF" {
+                return Err("ivf signature not found".to_owned());
+            }
+            let header_len = usize::from(u16::from_le_bytes([self.buffer[6], self.buffer[7]]));
+            if self.buffer.len() < header_len {
+                return Ok(Vec::new());
+            }
+            self.fourcc = Some([
+                self.buffer[8],
+                self.buffer[9],
+                self.buffer[10],
+                self.buffer[11],
+            ]);
+            self.buffer.advance(header_len.max(FILE_HEADER_MIN));
+        }
+
+        let mut frames = Vec::new();
+        while self.buffer.len() >= FRAME_HEADER_LEN {
+            let size = u32::from_le_bytes([
+                self.buffer[0],
+                self.buffer[1],
+                self.buffer[2],
+                self.buffer[3],
+            ]) as usize;
+            if self.buffer.len() < FRAME_HEADER_LEN + size {
+                break;
+            }
+            self.buffer.advance(FRAME_HEADER_LEN);
+            let data = self.buffer.split_to(size).freeze();
+            let measured = self
+                .last_arrival
+                .replace(arrived_at)
+                .map(|last| arrived_at.duration_since(last))
+                .filter(|gap| *gap >= MIN_FRAME_DURATION);
+            if measured.is_some() {
+                self.last_duration = measured;
+            }
+            let duration = self.last_duration.unwrap_or(MIN_FRAME_DURATION);
+            frames.push(AccessUnit {
+                keyframe: self.is_keyframe(&data),
+                data,
+                duration,
+            });
+        }
+        Ok(frames)
+    }
+
+    fn is_keyframe(&self, frame: &[u8]) -> bool {
+        match self.fourcc.as_ref() {
+            Some(b"VP80") => frame.first().is_some_and(|tag| tag & 0x01 == 0),
+            Some(b"VP90") => vp9_keyframe(frame),
+            Some(b"AV01") => av1_keyframe(frame),
+            _ => false,
+        }
+    }
+}
+
+// frame_marker(2) profile_low(1) profile_high(1) [reserved(1) for profile 3]
+// show_existing_frame(1) frame_type(1), where frame type 0 is a key frame.
+fn vp9_keyframe(frame: &[u8]) -> bool {
+    let Some(&header) = frame.first() else {
+        return false;
+    };
+    let profile = ((header >> 5) & 1) | (((header >> 4) & 1) << 1);
+    let show_existing_frame = if profile == 3 { 2 } else { 3 };
+    header & (1 << show_existing_frame) == 0 && header & (1 << (show_existing_frame - 1)) == 0
+}
+
+// Looks at the first frame header of a temporal unit: show_existing_frame(1)
+// then frame_type(2), where 0 is KEY_FRAME.
+fn av1_keyframe(temporal_unit: &[u8]) -> bool {
+    av1_obus(temporal_unit)
+        .into_iter()
+        .find(|obu| obu.obu_type == AV1_OBU_FRAME_HEADER || obu.obu_type == AV1_OBU_FRAME)
+        .is_some_and(|obu| obu.payload.first().is_some_and(|&bits| bits & 0xE0 == 0))
+}
+
+// The sequence header OBU of a temporal unit, whole and as its payload.
+pub fn av1_sequence_header(temporal_unit: &[u8]) -> Option<(&[u8], &[u8])> {
+    av1_obus(temporal_unit)
+        .into_iter()
+        .find(|obu| obu.obu_type == AV1_OBU_SEQUENCE_HEADER)
+        .map(|obu| (obu.bytes, obu.payload))
+}
+
+struct Obu<'a> {
+    obu_type: u8,
+    bytes: &'a [u8],
+    payload: &'a [u8],
+}
+
+// OBUs of a temporal unit, up to the first malformed one.
+fn av1_obus(mut temporal_unit: &[u8]) -> Vec<Obu<'_>> {
+    let mut obus = Vec::new();
+    while let Some(&header) = temporal_unit.first() {
+        let obu_type = (header >> 3) & 0x0F;
+        let mut offset = if header & 0x04 != 0 { 2 } else { 1 };
+        let size = if header & 0x02 != 0 {
+            let Some((size, len)) = temporal_unit.get(offset..).and_then(leb128) else {
+                break;
+            };
+            offset += len;
+            size
+        } else {
+            temporal_unit.len().saturating_sub(offset)
+        };
+        let Some(payload) = temporal_unit.get(offset..offset + size) else {
+            break;
+        };
+        obus.push(Obu {
+            obu_type,
+            bytes: &temporal_unit[..offset + size],
+            payload,
+        });
+        temporal_unit = &temporal_unit[offset + size..];
+    }
+    obus
+}
+
+fn leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0_usize;
+    for (index, &byte) in data.iter().take(8).enumerate() {
+        value |= usize::from(byte & 0x7F) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
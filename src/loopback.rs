@@ -0,0 +1,215 @@
+use tokio::sync::mpsc;
+
+// WASAPI loopback capture of a render endpoint: whatever the machine plays,
+// without a virtual cable or "Stereo Mix" device. The endpoint's mix format
+// goes out first as a WAV header so ffmpeg can read the PCM from stdin, then
+// the PCM itself. Resolves once the endpoint is open; capture runs on its own
+// thread until `pcm` closes or the endpoint goes away, which closes the
+// stream.
+#[cfg(windows)]
+pub async fn start(device: Option<String>, pcm: mpsc::Sender<Vec<u8>>) -> Result<(), String> {
+    let (opened, opened_rx) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("wasapi-loopback".to_owned())
+        .spawn(move || wasapi::run(device.as_deref(), &pcm, opened))
+        .map_err(|err| format!("loopback thread spawn failed: {err}"))?;
+    opened_rx
+        .await
+        .map_err(|_| "loopback thread exited before opening the endpoint".to_owned())?
+}
+
+#[cfg(not(windows))]
+pub async fn start(_device: Option<String>, _pcm: mpsc::Sender<Vec<u8>>) -> Result<(), String> {
+    Err("wasapi loopback is only supported on Windows".to_owned())
+}
+
+#[cfg(windows)]
+mod wasapi {
+    use std::{
+        ffi::c_void,
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::{mpsc, oneshot};
+    use tracing::{error, info};
+    use windows::{
+        core::HSTRING,
+        Win32::{
+            Media::Audio::{
+                eConsole, eRender, IAudioCaptureClient, IAudioClient, IMMDeviceEnumerator,
+                MMDeviceEnumerator, AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_LOOPBACK, WAVEFORMATEX,
+            },
+            System::Com::{
+                CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_ALL,
+                COINIT_MULTITHREADED,
+            },
+        },
+    };
+
+    // Shared-mode buffer, in 100 ns units.
+    const BUFFER_DURATION: i64 = 2_000_000;
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+    // Loopback delivers nothing while the endpoint is silent; after this long
+    // without a packet the gap is filled with silence so the Opus stream and
+    // its timestamps keep moving.
+    const SILENCE_AFTER: Duration = Duration::from_millis(40);
+
+    struct Endpoint {
+        client: IAudioClient,
+        capture: IAudioCaptureClient,
+        header: Vec<u8>,
+        block_align: usize,
+        sample_rate: u32,
+    }
+
+    pub fn run(
+        device: Option<&str>,
+        pcm: &mpsc::Sender<Vec<u8>>,
+        opened: oneshot::Sender<Result<(), String>>,
+    ) {
+        if let Err(err) = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.ok() {
+            let _ = opened.send(Err(format!("com init failed: {err}")));
+            return;
+        }
+        match unsafe { open(device) } {
+            Ok(endpoint) => {
+                let _ = opened.send(Ok(()));
+                info!(
+                    "wasapi_loopback started device={} sample_rate={}",
+                    device.unwrap_or("default"),
+                    endpoint.sample_rate
+                );
+                match unsafe { capture(&endpoint, pcm) } {
+                    Ok(()) => info!("wasapi_loopback stopped"),
+                    Err(err) => error!("wasapi_loopback failed error={err}"),
+                }
+                let _ = unsafe { endpoint.client.Stop() };
+            }
+            Err(err) => {
+                let _ = opened.send(Err(err));
+            }
+        }
+        unsafe { CoUninitialize() };
+    }
+
+    unsafe fn open(device: Option<&str>) -> Result<Endpoint, String> {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .map_err(|err| format!("device enumerator failed: {err}"))?;
+        let endpoint = match device {
+            Some(id) => enumerator.GetDevice(&HSTRING::from(id)),
+            None => enumerator.GetDefaultAudioEndpoint(eRender, eConsole),
+        }
+        .map_err(|err| {
+            format!(
+                "render endpoint {} not found: {err}",
+                device.unwrap_or("default")
+            )
+        })?;
+        let client: IAudioClient = endpoint
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|err| format!("audio client activation failed: {err}"))?;
+
+        let format = client
+            .GetMixFormat()
+            .map_err(|err| format!("mix format query failed: {err}"))?;
+        let initialized = client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_LOOPBACK,
+            BUFFER_DURATION,
+            0,
+            format,
+            None,
+        );
+        let mix = *format;
+        // WAVEFORMATEX is followed by `cbSize` bytes of extension, e.g. the
+        // WAVEFORMATEXTENSIBLE fields describing float samples.
+        let fmt = std::slice::from_raw_parts(
+            format.cast::<u8>(),
+            size_of::<WAVEFORMATEX>() + usize::from(mix.cbSize),
+        )
+        .to_vec();
+        CoTaskMemFree(Some(format as *const c_void));
+        initialized.map_err(|err| format!("loopback init failed: {err}"))?;
+
+        let capture: IAudioCaptureClient = client
+            .GetService()
+            .map_err(|err| format!("capture client failed: {err}"))?;
+        client
+            .Start()
+            .map_err(|err| format!("loopback start failed: {err}"))?;
+        Ok(Endpoint {
+            client,
+            capture,
+            header: wav_header(&fmt),
+            block_align: usize::from(mix.nBlockAlign),
+            sample_rate: mix.nSamplesPerSec,
+        })
+    }
+
+    unsafe fn capture(endpoint: &Endpoint, pcm: &mpsc::Sender<Vec<u8>>) -> Result<(), String> {
+        if pcm.blocking_send(endpoint.header.clone()).is_err() {
+            return Ok(());
+        }
+        let mut last_packet = Instant::now();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let mut chunk = Vec::new();
+            loop {
+                let frames = endpoint
+                    .capture
+                    .GetNextPacketSize()
+                    .map_err(|err| format!("packet size query failed: {err}"))?;
+                if frames == 0 {
+                    break;
+                }
+                let mut data = std::ptr::null_mut();
+                let mut frames = 0_u32;
+                let mut flags = 0_u32;
+                endpoint
+                    .capture
+                    .GetBuffer(&mut data, &mut frames, &mut flags, None, None)
+                    .map_err(|err| format!("capture buffer failed: {err}"))?;
+                let len = frames as usize * endpoint.block_align;
+                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                    chunk.resize(chunk.len() + len, 0);
+                } else {
+                    chunk.extend_from_slice(std::slice::from_raw_parts(data, len));
+                }
+                endpoint
+                    .capture
+                    .ReleaseBuffer(frames)
+                    .map_err(|err| format!("capture release failed: {err}"))?;
+            }
+
+            let now = Instant::now();
+            if chunk.is_empty() {
+                let gap = now.duration_since(last_packet);
+                if gap < SILENCE_AFTER {
+                    continue;
+                }
+                let frames = gap.as_secs_f64() * f64::from(endpoint.sample_rate);
+                chunk.resize(frames as usize * endpoint.block_align, 0);
+            }
+            last_packet = now;
+            if pcm.blocking_send(chunk).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    // RIFF/WAVE header with unknown lengths; ffmpeg reads it with
+    // `-ignore_length 1`.
+    fn wav_header(fmt: &[u8]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(28 + fmt.len());
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        header.extend_from_slice(fmt);
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header
+    }
+}
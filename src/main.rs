@@ -2,15 +2,39 @@ use std::env;
 
 use tracing::info;
 
+mod abr;
 mod app;
+mod audio;
+mod capture;
+mod codec_config;
+mod config;
+mod control;
+mod encoder;
+mod h264;
 mod handlers;
+mod http_util;
+mod ingest;
 mod input_injector;
+mod ivf;
+mod loopback;
 mod media_bridge;
+mod mkv;
 mod models;
+mod mp4;
+mod pipeline;
+mod recorder;
+mod replay;
+mod sdp;
 mod service;
 mod state;
+mod stats;
+mod turn_relay;
+mod video_track;
+mod whep;
+mod whip;
 
 use app::build_router;
+use config::BridgeConfig;
 use state::AppState;
 
 // Bootstraps routes for static client + HTTP-only signaling endpoints.
@@ -20,7 +44,9 @@ async fn main() {
         .with_env_filter("info,tower_http=info")
         .init();
 
-    let state = AppState::default();
+    let state = AppState::new(BridgeConfig::from_env())
+        .await
+        .expect("failed to initialize media bridge");
     let app = build_router(state);
 
     let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_owned());
@@ -1,120 +1,1249 @@
-use std::{collections::HashMap, process::Stdio, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    time::Duration,
+};
 
+use bytes::Bytes;
+
+use rand::distributions::{Alphanumeric, DistString};
 use tokio::{
-    io::AsyncReadExt,
-    process::{Child, Command},
-    sync::{Mutex, RwLock},
+    net::UdpSocket,
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex, Notify, RwLock,
+    },
+    time::Instant,
 };
 use tracing::{error, info, warn};
 use webrtc::{
-    api::{media_engine::MediaEngine, APIBuilder},
-    data_channel::data_channel_message::DataChannelMessage,
-    ice_transport::ice_candidate::RTCIceCandidateInit,
+    api::{
+        interceptor_registry::{configure_nack, configure_rtcp_reports},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+        APIBuilder,
+    },
+    data_channel::{
+        data_channel_message::DataChannelMessage, data_channel_state::RTCDataChannelState,
+        RTCDataChannel,
+    },
+    ice::{
+        udp_mux::{UDPMuxDefault, UDPMuxParams},
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{
+        ice_candidate::RTCIceCandidateInit, ice_candidate_type::RTCIceCandidateType,
+        ice_server::RTCIceServer,
+    },
+    interceptor::registry::Registry,
     media::Sample,
     peer_connection::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
-    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    rtcp::{
+        payload_feedbacks::{
+            full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+        },
+        receiver_report::ReceiverReport,
+    },
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        RTCPFeedback,
+    },
     track::track_local::track_local_static_sample::TrackLocalStaticSample,
 };
 
-use crate::{input_injector, models::SignalMessage, state::AppState};
+use crate::{
+    audio::{AudioPipeline, OPUS_FRAME_MS},
+    capture::{self, CaptureSource},
+    config::{BridgeConfig, CaptureConfig, IceServerConfig},
+    control::{ControlCommand, ControlReply, StreamSettings},
+    encoder::{EncoderKind, EncoderProfile, EncoderRegistry, FrameMemory, H264Profile, VideoCodec},
+    h264::{self, AccessUnit},
+    ingest::{self, IngestRegistry},
+    input_injector,
+    models::{SignalMessage, StreamRequest},
+    pipeline::{CapturePipeline, PipelineRegistry},
+    recorder::Recorder,
+    replay::ReplayBuffer,
+    sdp::{self, H264Format},
+    state::AppState,
+    stats::{StreamStats, StreamStatsSnapshot},
+    turn_relay::TurnRelay,
+    video_track::VideoTrack,
+};
 
 const BOT_PEER_ID: &str = "ffmpeg-bot";
+const MAX_PENDING_ICE: usize = 64;
+// Queued candidates are unauthenticated input, so the queue is bounded in
+// peers too and drops those whose offer never came.
+const MAX_PENDING_ICE_PEERS: usize = 256;
+const PENDING_ICE_TTL: Duration = Duration::from_secs(30);
+// How long a viewer waits for the encoder's natural IDR before forcing one.
+const KEYFRAME_WAIT: Duration = Duration::from_millis(500);
+// How long an offer waits for the encoder's SPS before answering with the
+// offered level instead.
+const PARAMETER_SETS_WAIT: Duration = Duration::from_secs(3);
+const STATS_PUSH_INTERVAL: Duration = Duration::from_secs(1);
+// How long a non-trickle answer waits for ICE gathering, e.g. slow STUN.
+const ICE_GATHER_WAIT: Duration = Duration::from_secs(5);
 
 type SessionPeerKey = String;
 
+// Where the bot's answer, candidates and failure notices go: the polling inbox
+// of a signaling session, or nowhere for WHEP, whose only channel back is the
+// answer itself.
+#[derive(Clone)]
+pub enum Signaling {
+    Polling(AppState),
+    Whep,
+}
+
+impl Signaling {
+    fn trickles(&self) -> bool {
+        matches!(self, Self::Polling(_))
+    }
+}
+type SessionMap = Arc<RwLock<HashMap<SessionPeerKey, Arc<StreamSession>>>>;
+
+// A viewer's subscription to a capture pipeline. It outlives a single peer
+// connection so a reconnecting client can reattach within the resume grace
+// window.
 struct StreamSession {
-    peer_connection: Arc<RTCPeerConnection>,
-    ffmpeg_child: Mutex<Child>,
+    pipeline: StdMutex<Arc<CapturePipeline>>,
+    setup: Mutex<StreamSetup>,
+    // Receiver of a pipeline the viewer moves to, picked up on `resync`.
+    pipeline_switch: StdMutex<Option<broadcast::Receiver<AccessUnit>>>,
+    video_paused: AtomicBool,
+    recorder: StdMutex<Option<Recorder>>,
+    replay: Option<StdMutex<ReplayBuffer>>,
+    stats: StdMutex<StreamStats>,
+    video_track: Arc<VideoTrack>,
+    audio_track: Option<Arc<TrackLocalStaticSample>>,
+    peer_connection: RwLock<Arc<RTCPeerConnection>>,
+    resume_token: String,
+    attachment: AtomicU64,
+    detached: AtomicBool,
+    // Bumped on every detach and reattach, so a grace timer can tell whether
+    // the detach it was started for is still the current one.
+    detach_generation: AtomicU64,
+    resync: Notify,
+    shutdown: Notify,
+}
+
+// What a session's pipeline is built from: the negotiated codec and encoder
+// on top of the server defaults, plus the viewer's requested settings.
+#[derive(Clone)]
+struct StreamSetup {
+    capture: Arc<dyn CaptureSource>,
+    profile: EncoderProfile,
+    request: StreamRequest,
+    // Bitrate ABR moved the viewer to, within the bounds of its request.
+    abr_kbps: Option<u32>,
+}
+
+impl StreamSession {
+    fn pipeline(&self) -> Arc<CapturePipeline> {
+        self.pipeline
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn settings(&self) -> StreamSettings {
+        StreamSettings::new(
+            &self.pipeline().profile(),
+            self.video_paused.load(Ordering::SeqCst),
+            self.recorder().as_ref().is_some_and(Recorder::is_active),
+        )
+    }
+
+    fn recorder(&self) -> std::sync::MutexGuard<'_, Option<Recorder>> {
+        self.recorder
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Recording starts on the next keyframe, so one is requested right away.
+    fn start_recording(&self, config: &BridgeConfig, id: &str) -> Result<(), String> {
+        let Some(recording) = &config.recording else {
+            return Err("recording is disabled, set RECORDING_DIR".to_owned());
+        };
+        let mut recorder = self.recorder();
+        if recorder.as_ref().is_some_and(Recorder::is_active) {
+            return Ok(());
+        }
+        let pipeline = self.pipeline();
+        *recorder = Some(Recorder::start(
+            recording,
+            id,
+            &pipeline.profile(),
+            self.audio_track.is_some(),
+        )?);
+        pipeline.request_keyframe();
+        Ok(())
+    }
+
+    fn stop_recording(&self) {
+        if let Some(recorder) = self.recorder().take() {
+            info!("recording_stopped path_prefix={}", recorder.path_prefix());
+        }
+    }
+
+    // Marks the viewer gone; returns the detach's generation unless it
+    // already was.
+    fn detach(&self) -> Option<u64> {
+        if self.detached.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(self.detach_generation.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn reattach(&self) {
+        self.detached.store(false, Ordering::SeqCst);
+        self.detach_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn replay(&self) -> Option<std::sync::MutexGuard<'_, ReplayBuffer>> {
+        self.replay.as_ref().map(|replay| {
+            replay
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        })
+    }
+
+    async fn save_replay(&self, config: &BridgeConfig, id: &str) -> Result<String, String> {
+        let (Some(replay_config), Some(snapshot)) = (
+            &config.replay,
+            self.replay().map(|replay| replay.snapshot()),
+        ) else {
+            return Err("replay is disabled, set REPLAY_DIR".to_owned());
+        };
+        if snapshot.is_empty() {
+            return Err("replay buffer is empty".to_owned());
+        }
+        snapshot
+            .save(replay_config, id, &self.pipeline().profile())
+            .await
+    }
+
+    // Runs one control command and reports the settings in effect after it.
+    async fn control(
+        &self,
+        command: ControlCommand,
+        pipelines: &Arc<PipelineRegistry>,
+        config: &BridgeConfig,
+        id: &str,
+    ) -> ControlReply {
+        info!("control_command key={id} command={command:?}");
+        match self.apply_control(command, pipelines, config, id).await {
+            Ok(clip) => ControlReply {
+                clip,
+                ..ControlReply::ok(self.settings())
+            },
+            Err(err) => ControlReply::error(err, Some(self.settings())),
+        }
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, StreamStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn stats_snapshot(&self, id: String) -> StreamStatsSnapshot {
+        let connection_state = self
+            .peer_connection
+            .read()
+            .await
+            .connection_state()
+            .to_string();
+        let source = self.pipeline().source_name();
+        let settings = self.settings();
+        self.stats()
+            .snapshot(id, source, settings, connection_state)
+    }
+
+    // Settings changes move the viewer to the pipeline matching them, which
+    // starts a fresh encoder unless another viewer already uses those
+    // settings. The forward loop swaps over on the next keyframe, so the
+    // picture never stalls on a restart. Returns the path of a saved replay.
+    async fn apply_control(
+        &self,
+        command: ControlCommand,
+        pipelines: &Arc<PipelineRegistry>,
+        config: &BridgeConfig,
+        id: &str,
+    ) -> Result<Option<String>, String> {
+        match command {
+            ControlCommand::RequestKeyframe => {
+                if !self.pipeline().request_keyframe() {
+                    return Err("no keyframe forced: the source takes no keyframe requests \
+                         or one was forced within the last second"
+                        .to_owned());
+                }
+            }
+            ControlCommand::PauseVideo => self.video_paused.store(true, Ordering::SeqCst),
+            ControlCommand::ResumeVideo => {
+                if self.video_paused.swap(false, Ordering::SeqCst) {
+                    self.resync.notify_one();
+                }
+            }
+            ControlCommand::StartRecording => self.start_recording(config, id)?,
+            ControlCommand::StopRecording => self.stop_recording(),
+            ControlCommand::SaveReplay => return self.save_replay(config, id).await.map(Some),
+            ControlCommand::GetSettings => {}
+            command => {
+                let mut setup = self.setup.lock().await;
+                let mut next = setup.clone();
+                command.update_request(&mut next.request);
+                // A new bitrate request starts ABR over from it.
+                if next.request.bitrate_kbps != setup.request.bitrate_kbps {
+                    next.abr_kbps = None;
+                }
+                self.switch_pipeline(&mut setup, next, pipelines, config)
+                    .await?;
+            }
+        }
+        Ok(None)
+    }
+
+    // Moves the viewer to the pipeline encoding at its pipeline's new ABR
+    // target. Every viewer of the old pipeline follows, so they end up
+    // sharing the new one, and its controller waits a full adjust interval
+    // before moving again.
+    async fn follow_abr(
+        &self,
+        bitrate_kbps: u32,
+        pipelines: &Arc<PipelineRegistry>,
+        config: &BridgeConfig,
+    ) -> Result<(), String> {
+        let mut setup = self.setup.lock().await;
+        let next = StreamSetup {
+            abr_kbps: Some(bitrate_kbps),
+            ..setup.clone()
+        };
+        self.switch_pipeline(&mut setup, next, pipelines, config)
+            .await
+    }
+
+    // Subscribes to the pipeline for `next` and hands it to the forward loop
+    // unless the viewer already watches it.
+    async fn switch_pipeline(
+        &self,
+        setup: &mut StreamSetup,
+        next: StreamSetup,
+        pipelines: &Arc<PipelineRegistry>,
+        config: &BridgeConfig,
+    ) -> Result<(), String> {
+        let (pipeline, access_units) = subscribe_stream(pipelines, &config.capture, &next).await?;
+        *setup = next;
+        let mut current = self
+            .pipeline
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if Arc::ptr_eq(&current, &pipeline) {
+            return Ok(());
+        }
+        *current = pipeline;
+        drop(current);
+        *self
+            .pipeline_switch
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(access_units);
+        self.resync.notify_one();
+        Ok(())
+    }
+}
+
+// Lets a viewer's `control` and `stats` data channels find its session.
+#[derive(Clone)]
+struct ChannelContext {
+    sessions: SessionMap,
+    session_key: SessionPeerKey,
+    pipelines: Arc<PipelineRegistry>,
+    config: Arc<BridgeConfig>,
+}
+
+impl ChannelContext {
+    async fn session(&self) -> Option<Arc<StreamSession>> {
+        self.sessions.read().await.get(&self.session_key).cloned()
+    }
+
+    async fn handle_control(&self, text: &str) -> ControlReply {
+        let command = match ControlCommand::parse(text) {
+            Ok(command) => command,
+            Err(err) => return ControlReply::error(err, None),
+        };
+        let Some(stream_session) = self.session().await else {
+            return ControlReply::error("no stream attached".to_owned(), None);
+        };
+        stream_session
+            .control(command, &self.pipelines, &self.config, &self.session_key)
+            .await
+    }
+}
+
+// Remote ICE received before the owning peer connection could accept it.
+struct PendingIce {
+    candidates: Vec<RTCIceCandidateInit>,
+    end_of_candidates: bool,
+    queued_at: Instant,
+}
+
+impl Default for PendingIce {
+    fn default() -> Self {
+        Self {
+            candidates: Vec::new(),
+            end_of_candidates: false,
+            queued_at: Instant::now(),
+        }
+    }
+}
+
+impl PendingIce {
+    async fn apply(self, peer_connection: &RTCPeerConnection) {
+        for candidate in self.candidates {
+            if let Err(err) = add_ice_candidate(peer_connection, Some(candidate)).await {
+                warn!("ffmpeg_bot queued ice failed error={err}");
+            }
+        }
+        if self.end_of_candidates {
+            if let Err(err) = add_ice_candidate(peer_connection, None).await {
+                warn!("ffmpeg_bot queued end_of_candidates failed error={err}");
+            }
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct MediaBridge {
-    sessions: Arc<RwLock<HashMap<SessionPeerKey, Arc<StreamSession>>>>,
+    config: Arc<BridgeConfig>,
+    udp_mux: Option<Arc<UDPMuxDefault>>,
+    turn_relay: Option<TurnRelay>,
+    encoders: EncoderRegistry,
+    pipelines: Arc<PipelineRegistry>,
+    audio: Arc<AudioPipeline>,
+    ingests: Arc<IngestRegistry>,
+    sessions: SessionMap,
+    pending_ice: Mutex<HashMap<SessionPeerKey, PendingIce>>,
+    next_attachment: AtomicU64,
 }
 
 impl MediaBridge {
+    pub async fn new(config: BridgeConfig) -> Result<Self, String> {
+        let udp_mux = match config.ice.udp_mux_port {
+            Some(port) => {
+                let socket = UdpSocket::bind(("0.0.0.0", port))
+                    .await
+                    .map_err(|err| format!("udp mux bind failed port={port}: {err}"))?;
+                info!("ice udp mux listening port={port}");
+                Some(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+            }
+            None => None,
+        };
+        let turn_relay = match config.turn.clone() {
+            Some(turn) => Some(TurnRelay::start(turn).await?),
+            None => None,
+        };
+        if config.whip_token.is_none() {
+            info!("WHIP_TOKEN unset, whip publishing disabled");
+        }
+        if config.admin_token.is_none() {
+            info!("ADMIN_TOKEN unset, admin api disabled");
+        }
+        let priority = &config.capture.encoder_priority;
+        let codecs = &config.capture.video_codecs;
+        let encoders = if config.capture.encoder_probe {
+            let memory = capture::select_source(&config.capture, None)
+                .await
+                .map_or(FrameMemory::System, |source| source.frame_memory());
+            EncoderRegistry::probe(codecs, priority, memory).await
+        } else {
+            EncoderRegistry::unprobed(codecs, priority)
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            udp_mux,
+            turn_relay,
+            encoders,
+            ..Default::default()
+        })
+    }
+
     pub fn is_bot_target(target_peer: &str) -> bool {
         target_peer == BOT_PEER_ID
     }
 
+    pub fn encoders(&self) -> &EncoderRegistry {
+        &self.encoders
+    }
+
+    // Streams are identified by `<session id>:<peer id>` of their viewer.
+    pub async fn stream_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.sessions.read().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub async fn has_peer(&self, session_id: &str, peer_id: &str) -> bool {
+        let key = session_peer_key(session_id, peer_id);
+        self.sessions.read().await.contains_key(&key)
+    }
+
+    // Applies a control command on behalf of the admin API; None when the
+    // stream does not exist.
+    pub async fn control_stream(&self, id: &str, command: ControlCommand) -> Option<ControlReply> {
+        let stream_session = self.sessions.read().await.get(id).cloned()?;
+        Some(
+            stream_session
+                .control(command, &self.pipelines, &self.config, id)
+                .await,
+        )
+    }
+
+    pub async fn stream_stats(&self, id: &str) -> Option<StreamStatsSnapshot> {
+        let stream_session = self.sessions.read().await.get(id).cloned()?;
+        Some(stream_session.stats_snapshot(id.to_owned()).await)
+    }
+
+    // Configured ICE servers plus short-lived embedded TURN credentials
+    // minted for the peer, unless TURN_CLIENT_TOKEN is set and not presented.
+    pub fn ice_servers_for(
+        &self,
+        peer_id: &str,
+        bearer_token: Option<&str>,
+    ) -> Vec<IceServerConfig> {
+        let mut servers = self.config.ice.servers.clone();
+        if let Some(turn_relay) = self
+            .turn_relay
+            .as_ref()
+            .filter(|turn_relay| turn_relay.authorizes_client(bearer_token))
+        {
+            servers.push(turn_relay.credentials_for(peer_id));
+        }
+        servers
+    }
+
+    // Returns the answer, which has also been queued for polling clients.
     pub async fn handle_offer(
         &self,
-        state: AppState,
+        signaling: Signaling,
         session_id: String,
         from_peer: String,
         offer_sdp: String,
-    ) -> Result<(), String> {
-        let mut media_engine = MediaEngine::default();
-        media_engine
-            .register_default_codecs()
-            .map_err(|err| format!("register_default_codecs failed: {err}"))?;
-        let api = APIBuilder::new().with_media_engine(media_engine).build();
-        let peer_connection = Arc::new(
-            api.new_peer_connection(RTCConfiguration::default())
-                .await
-                .map_err(|err| format!("new_peer_connection failed: {err}"))?,
-        );
+        resume_token: Option<String>,
+        request: StreamRequest,
+    ) -> Result<String, String> {
+        let session_key = session_peer_key(&session_id, &from_peer);
+        // Candidates arriving while the offer is applied are held back until the
+        // peer connection that will own them has its remote description.
+        self.pending_ice
+            .lock()
+            .await
+            .entry(session_key.clone())
+            .or_default();
+        let attached = self
+            .attach_offer(
+                signaling,
+                session_id,
+                from_peer,
+                offer_sdp,
+                resume_token,
+                request,
+            )
+            .await;
+        let pending = self.pending_ice.lock().await.remove(&session_key);
+        let (peer_connection, answer_sdp) = attached?;
+        if let Some(pending) = pending {
+            pending.apply(&peer_connection).await;
+        }
+        Ok(answer_sdp)
+    }
+
+    async fn attach_offer(
+        &self,
+        signaling: Signaling,
+        session_id: String,
+        from_peer: String,
+        offer_sdp: String,
+        resume_token: Option<String>,
+        request: StreamRequest,
+    ) -> Result<(Arc<RTCPeerConnection>, String), String> {
+        let session_key = session_peer_key(&session_id, &from_peer);
+        let existing = self.sessions.read().await.get(&session_key).cloned();
+        if let Some(existing) = existing {
+            let current = existing.peer_connection.read().await.clone();
+            if is_same_remote_peer(&current, &offer_sdp).await {
+                return self
+                    .renegotiate_stream_session(
+                        signaling, existing, current, session_id, from_peer, offer_sdp,
+                    )
+                    .await;
+            }
+            if resume_token.as_deref() == Some(existing.resume_token.as_str()) {
+                return self
+                    .resume_stream_session(signaling, existing, session_id, from_peer, offer_sdp)
+                    .await;
+            }
+            warn!("ffmpeg_bot replacing stream session key={session_key}");
+            existing.shutdown.notify_one();
+        }
+
+        // A room with a WHIP publisher serves it unless another source is
+        // asked for by name.
+        let room = request.room.as_deref().unwrap_or(&session_id);
+        let ingest = match request.source.as_deref() {
+            None | Some(ingest::SOURCE_NAME) => self.ingests.get(room).await,
+            Some(_) => None,
+        };
+        let capture = match &ingest {
+            Some(ingest) => ingest.capture_source(),
+            None if request.source.as_deref() == Some(ingest::SOURCE_NAME) => {
+                return Err(format!("room {room} has no WHIP publisher"));
+            }
+            None => capture::select_source(&self.config.capture, request.source.as_deref()).await?,
+        };
+        let mut profile = self.config.capture.profile.clone();
+        let (codec, encoder, h264_format) = match capture.codec() {
+            // A pass-through stream reaches the viewer as it was encoded; an
+            // H264 format is settled from the stream's SPS below.
+            Some(codec) if !sdp::offered_video_codecs(&offer_sdp).contains(&codec) => {
+                return Err(format!(
+                    "offer has no {}, the only codec source {} sends",
+                    codec.name(),
+                    capture.name()
+                ));
+            }
+            Some(codec) => (codec, profile.encoder, None),
+            None => self.choose_video_codec(&offer_sdp, profile.h264_profile)?,
+        };
+        profile.codec = codec;
+        profile.encoder = encoder;
+        profile.keyframe_control = self.encoders.keyframe_control;
+        if let Some(h264_profile) = h264_format.and_then(|format| format.profile()) {
+            profile.h264_profile = h264_profile;
+        }
+
+        // The encoder runs before negotiation so an H264 answer can carry the
+        // level it actually produces rather than a guess.
+        let setup = StreamSetup {
+            capture,
+            profile,
+            request,
+            abr_kbps: None,
+        };
+        let (pipeline, access_units) =
+            subscribe_stream(&self.pipelines, &self.config.capture, &setup).await?;
+        let sdp_fmtp_line = match h264_format {
+            None if codec == VideoCodec::H264 => relay_h264_fmtp(&pipeline, &offer_sdp).await?,
+            Some(format) => {
+                let level = pipeline
+                    .wait_parameter_sets(PARAMETER_SETS_WAIT)
+                    .await
+                    .and_then(|parameter_sets| h264::profile_level_id(&parameter_sets))
+                    .map_or(format.profile_level_id[2], |encoded| encoded[2]);
+                h264_fmtp([
+                    format.profile_level_id[0],
+                    format.profile_level_id[1],
+                    level,
+                ])
+            }
+            None => sdp::video_fmtp(codec).to_owned(),
+        };
 
-        let video_track = Arc::new(TrackLocalStaticSample::new(
+        let video_track = Arc::new(VideoTrack::new(
             RTCRtpCodecCapability {
-                mime_type: "video/H264".to_owned(),
+                mime_type: format!("video/{}", codec.sdp_name()),
                 clock_rate: 90_000,
                 channels: 0,
-                sdp_fmtp_line: "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
-                    .to_owned(),
-                rtcp_feedback: vec![],
+                sdp_fmtp_line,
+                rtcp_feedback: video_rtcp_feedback(),
             },
             "video".to_owned(),
             "ffmpeg".to_owned(),
         ));
 
+        let offers_audio = offer_sdp.contains("m=audio");
+        let audio_config = self
+            .config
+            .capture
+            .audio
+            .as_ref()
+            .filter(|_| offers_audio && ingest.is_none());
+        let relays_audio = offers_audio && ingest.as_ref().is_some_and(|ingest| ingest.has_audio());
+        let audio_track = (audio_config.is_some() || relays_audio).then(|| {
+            Arc::new(TrackLocalStaticSample::new(
+                RTCRtpCodecCapability {
+                    mime_type: "audio/opus".to_owned(),
+                    clock_rate: 48_000,
+                    channels: 2,
+                    sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                    rtcp_feedback: vec![],
+                },
+                "audio".to_owned(),
+                "ffmpeg".to_owned(),
+            ))
+        });
+
+        let attachment = self.next_attachment.fetch_add(1, Ordering::SeqCst);
+        let peer_connection = self
+            .build_peer_connection(
+                &signaling,
+                &session_id,
+                &from_peer,
+                &video_track,
+                audio_track.as_ref(),
+                attachment,
+            )
+            .await?;
+        let answer_sdp = negotiate_answer(
+            &peer_connection,
+            &video_track,
+            offer_sdp,
+            signaling.trickles(),
+        )
+        .await?;
+
+        let resume_token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        enqueue_message(
+            &signaling,
+            &session_id,
+            &from_peer,
+            SignalMessage::Answer {
+                from: BOT_PEER_ID.to_owned(),
+                to: from_peer.clone(),
+                sdp: answer_sdp.clone(),
+                resume_token: Some(resume_token.clone()),
+            },
+        )
+        .await;
+
+        // Audio is best effort: a missing loopback device leaves the track
+        // silent, but it is logged as an error since the viewer hears nothing.
+        let audio_packets = match (audio_config, &ingest) {
+            (Some(audio_config), _) => match self
+                .audio
+                .subscribe(audio_config, &self.config.capture.supervisor)
+                .await
+            {
+                Ok(audio_packets) => Some(audio_packets),
+                Err(err) => {
+                    error!(
+                        "ffmpeg_audio unavailable source={} error={err}",
+                        audio_config.source
+                    );
+                    None
+                }
+            },
+            (None, Some(ingest)) if relays_audio => ingest.subscribe_audio(),
+            (None, _) => None,
+        };
+        let source_name = pipeline.source_name();
+        let video_fmtp = video_track.codec().sdp_fmtp_line;
+        let stream_session = Arc::new(StreamSession {
+            pipeline: StdMutex::new(pipeline),
+            setup: Mutex::new(setup),
+            pipeline_switch: StdMutex::new(None),
+            video_paused: AtomicBool::new(false),
+            recorder: StdMutex::new(None),
+            replay: self
+                .config
+                .replay
+                .as_ref()
+                .map(|replay| StdMutex::new(ReplayBuffer::new(replay.window))),
+            stats: StdMutex::default(),
+            video_track,
+            audio_track,
+            peer_connection: RwLock::new(peer_connection.clone()),
+            resume_token,
+            attachment: AtomicU64::new(attachment),
+            detached: AtomicBool::new(false),
+            detach_generation: AtomicU64::new(0),
+            resync: Notify::new(),
+            shutdown: Notify::new(),
+        });
+        self.sessions
+            .write()
+            .await
+            .insert(session_key.clone(), stream_session.clone());
+
+        let sessions = self.sessions.clone();
+        let failure_target = (session_id.clone(), from_peer.clone());
+        let pipelines = self.pipelines.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = forward_to_track(
+                stream_session.clone(),
+                access_units,
+                audio_packets,
+                &pipelines,
+                &config,
+            )
+            .await
+            {
+                error!("ffmpeg_bot stream failed key={session_key} error={err}");
+                let (session_id, to_peer) = failure_target;
+                enqueue_message(
+                    &signaling,
+                    &session_id,
+                    &to_peer,
+                    SignalMessage::StreamFailed {
+                        from: BOT_PEER_ID.to_owned(),
+                        to: to_peer.clone(),
+                        reason: err,
+                    },
+                )
+                .await;
+            }
+            let peer_connection = stream_session.peer_connection.read().await.clone();
+            let _ = peer_connection.close().await;
+            let mut sessions = sessions.write().await;
+            if sessions
+                .get(&session_key)
+                .is_some_and(|current| Arc::ptr_eq(current, &stream_session))
+            {
+                sessions.remove(&session_key);
+            }
+            info!("ffmpeg_bot stream closed key={session_key}");
+        });
+
+        info!(
+            "ffmpeg_bot viewer attached session={session_id} to_peer={from_peer} source={source_name} codec={} fmtp={video_fmtp}",
+            codec.name()
+        );
+        Ok((peer_connection, answer_sdp))
+    }
+
+    pub async fn handle_remote_ice(
+        &self,
+        session_id: &str,
+        from_peer: &str,
+        candidate_json: &str,
+    ) -> Result<(), String> {
+        let candidate: RTCIceCandidateInit = serde_json::from_str(candidate_json)
+            .map_err(|err| format!("parse remote ice failed: {err}"))?;
+        self.add_remote_ice(session_id, from_peer, Some(candidate))
+            .await
+    }
+
+    pub async fn handle_remote_end_of_candidates(
+        &self,
+        session_id: &str,
+        from_peer: &str,
+    ) -> Result<(), String> {
+        self.add_remote_ice(session_id, from_peer, None).await
+    }
+
+    // Applies a candidate to the live peer connection, or queues it while an
+    // offer is in flight or has not reached the bridge yet.
+    async fn add_remote_ice(
+        &self,
+        session_id: &str,
+        from_peer: &str,
+        candidate: Option<RTCIceCandidateInit>,
+    ) -> Result<(), String> {
+        let key = session_peer_key(session_id, from_peer);
+        let mut pending_ice = self.pending_ice.lock().await;
+        let live = if pending_ice.contains_key(&key) {
+            None
+        } else {
+            match self.sessions.read().await.get(&key) {
+                Some(stream_session) => Some(stream_session.peer_connection.read().await.clone()),
+                None => None,
+            }
+        };
+        let Some(peer_connection) = live else {
+            pending_ice.retain(|_, pending| pending.queued_at.elapsed() < PENDING_ICE_TTL);
+            if !pending_ice.contains_key(&key) && pending_ice.len() >= MAX_PENDING_ICE_PEERS {
+                return Err("too many peers with pending ice candidates".to_owned());
+            }
+            let pending = pending_ice.entry(key.clone()).or_default();
+            match candidate {
+                Some(candidate) if pending.candidates.len() < MAX_PENDING_ICE => {
+                    pending.candidates.push(candidate)
+                }
+                Some(_) => return Err("too many pending ice candidates".to_owned()),
+                None => pending.end_of_candidates = true,
+            }
+            info!("ffmpeg_bot ice queued key={key}");
+            return Ok(());
+        };
+        drop(pending_ice);
+        add_ice_candidate(&peer_connection, candidate).await
+    }
+
+    // Candidates a WHEP client trickles as an SDP fragment instead of JSON.
+    pub async fn handle_remote_fragment(
+        &self,
+        session_id: &str,
+        from_peer: &str,
+        fragment: &str,
+    ) -> Result<(), String> {
+        for candidate in fragment_candidates(fragment) {
+            self.add_remote_ice(session_id, from_peer, candidate)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Starts relaying a WHIP publisher to the viewers of `room`, a signaling
+    // session id; viewers already watching keep their capture until they
+    // reconnect. Returns the answer.
+    pub async fn publish_ingest(
+        &self,
+        room: &str,
+        resource_id: &str,
+        offer_sdp: String,
+    ) -> Result<String, String> {
+        self.ingests
+            .publish(
+                room,
+                resource_id,
+                offer_sdp,
+                self.setting_engine()?,
+                self.rtc_configuration(),
+            )
+            .await
+    }
+
+    pub fn authorizes_admin(&self, bearer_token: Option<&str>) -> bool {
+        self.config
+            .admin_token
+            .as_deref()
+            .is_some_and(|token| bearer_token == Some(token))
+    }
+
+    // WHIP is disabled without WHIP_TOKEN.
+    pub fn accepts_publishers(&self) -> bool {
+        self.config.whip_token.is_some()
+    }
+
+    pub fn authorizes_publisher(&self, bearer_token: Option<&str>) -> bool {
+        self.config
+            .whip_token
+            .as_deref()
+            .is_some_and(|token| bearer_token == Some(token))
+    }
+
+    pub async fn is_publishing(&self, room: &str) -> bool {
+        self.ingests.get(room).await.is_some()
+    }
+
+    pub async fn has_ingest(&self, room: &str, resource_id: &str) -> bool {
+        self.ingests.find(room, resource_id).await.is_some()
+    }
+
+    // Candidates a WHIP publisher trickles after its offer was answered.
+    pub async fn trickle_ingest(
+        &self,
+        room: &str,
+        resource_id: &str,
+        fragment: &str,
+    ) -> Result<(), String> {
+        let Some(ingest) = self.ingests.find(room, resource_id).await else {
+            return Err(format!("room {room} has no publisher {resource_id}"));
+        };
+        for candidate in fragment_candidates(fragment) {
+            add_ice_candidate(ingest.peer_connection(), candidate).await?;
+        }
+        Ok(())
+    }
+
+    // Ends the publish; relayed viewers' streams end with it. Returns false
+    // when the room has no such publisher.
+    pub async fn unpublish_ingest(&self, room: &str, resource_id: &str) -> bool {
+        self.ingests.unpublish(room, resource_id).await
+    }
+
+    // Stops the capture for a peer that explicitly left; no grace window
+    // applies. Returns false when the peer had no stream.
+    pub async fn close_peer(&self, session_id: &str, peer_id: &str) -> bool {
+        let key = session_peer_key(session_id, peer_id);
+        self.pending_ice.lock().await.remove(&key);
+        let Some(stream_session) = self.sessions.read().await.get(&key).cloned() else {
+            return false;
+        };
+        stream_session.shutdown.notify_one();
+        true
+    }
+
+    // The first codec in our preference order that the offer carries and an
+    // encoder can produce. H264 additionally needs a payload type our
+    // packetizer and profile limit can serve.
+    fn choose_video_codec(
+        &self,
+        offer_sdp: &str,
+        max_h264_profile: H264Profile,
+    ) -> Result<(VideoCodec, EncoderKind, Option<H264Format>), String> {
+        let offered = sdp::offered_video_codecs(offer_sdp);
+        for &codec in &self.config.capture.video_codecs {
+            if !offered.contains(&codec) {
+                continue;
+            }
+            let Some(encoder) = self.encoders.encoder_for(codec) else {
+                continue;
+            };
+            if codec != VideoCodec::H264 {
+                return Ok((codec, encoder, None));
+            }
+            let h264_format = sdp::choose_h264(&sdp::offered_h264(offer_sdp), max_h264_profile);
+            if h264_format.is_some() {
+                return Ok((codec, encoder, h264_format));
+            }
+        }
+        let offered: Vec<&str> = offered.iter().map(|codec| codec.name()).collect();
+        Err(format!(
+            "no mutually supported video codec offered={}",
+            offered.join(",")
+        ))
+    }
+
+    // Applies a re-offer from the same client peer connection (ICE restart,
+    // added transceivers or data channels) without touching the capture.
+    async fn renegotiate_stream_session(
+        &self,
+        signaling: Signaling,
+        stream_session: Arc<StreamSession>,
+        peer_connection: Arc<RTCPeerConnection>,
+        session_id: String,
+        from_peer: String,
+        offer_sdp: String,
+    ) -> Result<(Arc<RTCPeerConnection>, String), String> {
+        let answer_sdp = negotiate_answer(
+            &peer_connection,
+            &stream_session.video_track,
+            offer_sdp,
+            signaling.trickles(),
+        )
+        .await?;
+        enqueue_message(
+            &signaling,
+            &session_id,
+            &from_peer,
+            SignalMessage::Answer {
+                from: BOT_PEER_ID.to_owned(),
+                to: from_peer.clone(),
+                sdp: answer_sdp.clone(),
+                resume_token: Some(stream_session.resume_token.clone()),
+            },
+        )
+        .await;
+
+        info!("ffmpeg_bot renegotiated session={session_id} to_peer={from_peer}");
+        Ok((peer_connection, answer_sdp))
+    }
+
+    // Attaches a fresh peer connection to a live subscription and resyncs it.
+    // The forward loop forces an IDR through the pipeline's encoder control,
+    // so the reconnecting client does not wait for the next GOP; a request
+    // dropped by the rate limit is retried after KEYFRAME_WAIT.
+    async fn resume_stream_session(
+        &self,
+        signaling: Signaling,
+        stream_session: Arc<StreamSession>,
+        session_id: String,
+        from_peer: String,
+        offer_sdp: String,
+    ) -> Result<(Arc<RTCPeerConnection>, String), String> {
+        let attachment = self.next_attachment.fetch_add(1, Ordering::SeqCst);
+        let peer_connection = self
+            .build_peer_connection(
+                &signaling,
+                &session_id,
+                &from_peer,
+                &stream_session.video_track,
+                stream_session.audio_track.as_ref(),
+                attachment,
+            )
+            .await?;
+        let answer_sdp = negotiate_answer(
+            &peer_connection,
+            &stream_session.video_track,
+            offer_sdp,
+            signaling.trickles(),
+        )
+        .await?;
+
+        stream_session
+            .attachment
+            .store(attachment, Ordering::SeqCst);
+        stream_session.reattach();
+        let previous = std::mem::replace(
+            &mut *stream_session.peer_connection.write().await,
+            peer_connection.clone(),
+        );
+        let _ = previous.close().await;
+        stream_session.resync.notify_one();
+
+        enqueue_message(
+            &signaling,
+            &session_id,
+            &from_peer,
+            SignalMessage::Answer {
+                from: BOT_PEER_ID.to_owned(),
+                to: from_peer.clone(),
+                sdp: answer_sdp.clone(),
+                resume_token: Some(stream_session.resume_token.clone()),
+            },
+        )
+        .await;
+
+        info!("ffmpeg_bot stream resumed session={session_id} to_peer={from_peer}");
+        Ok((peer_connection, answer_sdp))
+    }
+
+    async fn build_peer_connection(
+        &self,
+        signaling: &Signaling,
+        session_id: &str,
+        from_peer: &str,
+        video_track: &Arc<VideoTrack>,
+        audio_track: Option<&Arc<TrackLocalStaticSample>>,
+        attachment: u64,
+    ) -> Result<Arc<RTCPeerConnection>, String> {
+        // Only the codecs our tracks carry, so the answer cannot settle on a
+        // payload type the encoder does not produce.
+        let mut media_engine = MediaEngine::default();
+        media_engine
+            .register_codec(
+                RTCRtpCodecParameters {
+                    capability: video_track.codec(),
+                    payload_type: 96,
+                    ..Default::default()
+                },
+                RTPCodecType::Video,
+            )
+            .map_err(|err| format!("register_codec video failed: {err}"))?;
+        if let Some(audio_track) = audio_track {
+            media_engine
+                .register_codec(
+                    RTCRtpCodecParameters {
+                        capability: audio_track.codec(),
+                        payload_type: 111,
+                        ..Default::default()
+                    },
+                    RTPCodecType::Audio,
+                )
+                .map_err(|err| format!("register_codec audio failed: {err}"))?;
+        }
+        // REMB rather than transport-cc: webrtc-rs has no sender-side estimator
+        // to consume TWCC feedback, while browsers compute REMB themselves.
+        let registry = configure_rtcp_reports(configure_nack(Registry::new(), &mut media_engine));
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(self.setting_engine()?)
+            .build();
+        let peer_connection = Arc::new(
+            api.new_peer_connection(self.rtc_configuration())
+                .await
+                .map_err(|err| format!("new_peer_connection failed: {err}"))?,
+        );
+
         let sender = peer_connection
-            .add_track(video_track.clone())
+            .add_track(video_track.track_local())
             .await
             .map_err(|err| format!("add_track failed: {err}"))?;
+        if let Some(audio_track) = audio_track {
+            let audio_sender = peer_connection
+                .add_track(audio_track.clone())
+                .await
+                .map_err(|err| format!("add_track audio failed: {err}"))?;
+            tokio::spawn(async move {
+                let mut rtcp = vec![0_u8; 1500];
+                while audio_sender.read(&mut rtcp).await.is_ok() {}
+            });
+        }
 
+        let channels = ChannelContext {
+            sessions: self.sessions.clone(),
+            session_key: session_peer_key(session_id, from_peer),
+            pipelines: self.pipelines.clone(),
+            config: self.config.clone(),
+        };
         peer_connection.on_data_channel(Box::new(move |dc| {
+            let channels = channels.clone();
             Box::pin(async move {
-                if dc.label() != "input" {
-                    return;
+                match dc.label() {
+                    "input" => attach_input_channel(&dc),
+                    "control" => attach_control_channel(&dc, channels),
+                    "stats" => attach_stats_channel(&dc, channels),
+                    _ => {}
                 }
-                dc.on_open(Box::new(|| {
-                    Box::pin(async move {
-                        info!("input_channel_open");
-                    })
-                }));
-                dc.on_message(Box::new(move |msg: DataChannelMessage| {
-                    Box::pin(async move {
-                        let Ok(text) = String::from_utf8(msg.data.to_vec()) else {
-                            warn!("input_event_ignored invalid_utf8");
-                            return;
-                        };
-                        match input_injector::inject_from_json(&text) {
-                            Ok(()) => info!("input_event_received"),
-                            Err(err) => warn!("input_event_failed error={err}"),
-                        }
-                    })
-                }));
             })
         }));
 
+        // Viewer feedback drives the shared encoder: PLI/FIR ask for an IDR,
+        // REMB and receiver report loss feed the bitrate controller.
+        let sessions_for_rtcp = self.sessions.clone();
+        let key_for_rtcp = session_peer_key(session_id, from_peer);
         tokio::spawn(async move {
-            let mut rtcp = vec![0_u8; 1500];
-            while sender.read(&mut rtcp).await.is_ok() {}
+            // Receiver reports also carry blocks for the audio stream; only
+            // the video sender's own blocks say anything about the encoder.
+            let video_ssrc = sender
+                .get_parameters()
+                .await
+                .encodings
+                .first()
+                .map(|encoding| encoding.ssrc);
+            while let Ok((packets, _)) = sender.read_rtcp().await {
+                let Some(stream_session) =
+                    sessions_for_rtcp.read().await.get(&key_for_rtcp).cloned()
+                else {
+                    continue;
+                };
+                if stream_session.attachment.load(Ordering::SeqCst) != attachment {
+                    continue;
+                }
+                let pipeline = stream_session.pipeline();
+                for packet in &packets {
+                    let packet = packet.as_any();
+                    if packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>() {
+                        if pipeline.request_keyframe() {
+                            info!("ffmpeg_bot keyframe_requested reason=pli key={key_for_rtcp}");
+                        }
+                    } else if let Some(remb) =
+                        packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>()
+                    {
+                        pipeline.on_remb(attachment, remb.bitrate);
+                    } else if let Some(report) = packet.downcast_ref::<ReceiverReport>() {
+                        for reception in &report.reports {
+                            if Some(reception.ssrc) != video_ssrc {
+                                continue;
+                            }
+                            pipeline.on_loss(attachment, reception.fraction_lost);
+                            stream_session.stats().on_reception_report(reception);
+                        }
+                    }
+                }
+            }
         });
 
-        let state_for_ice = state.clone();
-        let session_for_ice = session_id.clone();
-        let from_for_ice = from_peer.clone();
+        let signaling_for_ice = signaling.clone();
+        let session_for_ice = session_id.to_owned();
+        let from_for_ice = from_peer.to_owned();
         peer_connection.on_ice_candidate(Box::new(move |candidate| {
-            let state_for_ice = state_for_ice.clone();
+            let signaling_for_ice = signaling_for_ice.clone();
             let session_for_ice = session_for_ice.clone();
             let from_for_ice = from_for_ice.clone();
             Box::pin(async move {
                 let Some(candidate) = candidate else {
+                    enqueue_message(
+                        &signaling_for_ice,
+                        &session_for_ice,
+                        &from_for_ice,
+                        SignalMessage::EndOfCandidates {
+                            from: BOT_PEER_ID.to_owned(),
+                            to: from_for_ice.clone(),
+                        },
+                    )
+                    .await;
                     return;
                 };
                 let Ok(json) = candidate.to_json() else {
@@ -124,7 +1253,7 @@ impl MediaBridge {
                     return;
                 };
                 enqueue_message(
-                    &state_for_ice,
+                    &signaling_for_ice,
                     &session_for_ice,
                     &from_for_ice,
                     SignalMessage::IceCandidate {
@@ -137,262 +1266,577 @@ impl MediaBridge {
             })
         }));
 
+        let sessions = self.sessions.clone();
+        let session_key = session_peer_key(session_id, from_peer);
+        let resume_grace = self.config.resume_grace;
         peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+            let sessions = sessions.clone();
+            let session_key = session_key.clone();
             Box::pin(async move {
                 info!("ffmpeg_bot peer_connection_state={state:?}");
                 if state == RTCPeerConnectionState::Failed {
                     warn!("ffmpeg_bot peer connection failed");
                 }
+                let Some(stream_session) = sessions.read().await.get(&session_key).cloned() else {
+                    return;
+                };
+                if stream_session.attachment.load(Ordering::SeqCst) != attachment {
+                    return;
+                }
+                match state {
+                    RTCPeerConnectionState::Connected => stream_session.reattach(),
+                    RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed => {
+                        if let Some(generation) = stream_session.detach() {
+                            schedule_detached_shutdown(
+                                stream_session,
+                                session_key,
+                                generation,
+                                resume_grace,
+                            );
+                        }
+                    }
+                    _ => {}
+                }
             })
         }));
 
-        peer_connection
-            .set_remote_description(
-                RTCSessionDescription::offer(offer_sdp)
-                    .map_err(|err| format!("offer sdp parse failed: {err}"))?,
-            )
-            .await
-            .map_err(|err| format!("set_remote_description failed: {err}"))?;
+        Ok(peer_connection)
+    }
 
-        let answer = peer_connection
-            .create_answer(None)
-            .await
-            .map_err(|err| format!("create_answer failed: {err}"))?;
-        peer_connection
-            .set_local_description(answer.clone())
-            .await
-            .map_err(|err| format!("set_local_description failed: {err}"))?;
+    fn rtc_configuration(&self) -> RTCConfiguration {
+        let mut servers = self.config.ice.servers.clone();
+        if let Some(turn_relay) = &self.turn_relay {
+            servers.push(turn_relay.credentials_for(BOT_PEER_ID));
+        }
+        RTCConfiguration {
+            ice_servers: servers
+                .into_iter()
+                .map(|server| RTCIceServer {
+                    urls: server.urls,
+                    username: server.username,
+                    credential: server.credential,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
 
-        enqueue_message(
-            &state,
-            &session_id,
-            &from_peer,
-            SignalMessage::Answer {
-                from: BOT_PEER_ID.to_owned(),
-                to: from_peer.clone(),
-                sdp: answer.sdp,
-            },
+    fn setting_engine(&self) -> Result<SettingEngine, String> {
+        let ice = &self.config.ice;
+        let mut setting_engine = SettingEngine::default();
+        setting_engine.set_lite(ice.lite);
+        if let Some(udp_mux) = &self.udp_mux {
+            setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux.clone()));
+        } else if let Some((port_min, port_max)) = ice.udp_port_range {
+            let ephemeral = EphemeralUDP::new(port_min, port_max)
+                .map_err(|err| format!("invalid udp port range {port_min}-{port_max}: {err}"))?;
+            setting_engine.set_udp_network(UDPNetwork::Ephemeral(ephemeral));
+        }
+        if !ice.nat_1to1_ips.is_empty() {
+            let candidate_type = if ice.nat_1to1_srflx {
+                RTCIceCandidateType::Srflx
+            } else {
+                RTCIceCandidateType::Host
+            };
+            setting_engine.set_nat_1to1_ips(ice.nat_1to1_ips.clone(), candidate_type);
+        }
+        if !ice.interfaces.is_empty() {
+            let interfaces = ice.interfaces.clone();
+            setting_engine.set_interface_filter(Box::new(move |name: &str| {
+                interfaces.iter().any(|allowed| allowed == name)
+            }));
+        }
+        Ok(setting_engine)
+    }
+}
+
+// Stops the capture once the grace window passes without a reconnect.
+fn schedule_detached_shutdown(
+    stream_session: Arc<StreamSession>,
+    session_key: SessionPeerKey,
+    generation: u64,
+    resume_grace: Duration,
+) {
+    info!(
+        "ffmpeg_bot stream detached key={session_key} grace_secs={}",
+        resume_grace.as_secs()
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(resume_grace).await;
+        // A reconnect, or a later detach with its own timer, took over.
+        if stream_session.detach_generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        info!("ffmpeg_bot resume grace expired key={session_key}");
+        stream_session.shutdown.notify_one();
+    });
+}
+
+// The answer keeps the offer's H264 fmtp, so the copy sent to the client is
+// rewritten to the level our encoder produces. The local description must
+// stay the one webrtc-rs generated. Without trickle the answer waits for ICE
+// gathering so it carries every candidate.
+async fn negotiate_answer(
+    peer_connection: &RTCPeerConnection,
+    video_track: &VideoTrack,
+    offer_sdp: String,
+    trickle: bool,
+) -> Result<String, String> {
+    peer_connection
+        .set_remote_description(
+            RTCSessionDescription::offer(offer_sdp)
+                .map_err(|err| format!("offer sdp parse failed: {err}"))?,
         )
-        .await;
+        .await
+        .map_err(|err| format!("set_remote_description failed: {err}"))?;
 
-        let ffmpeg_child = spawn_ffmpeg_process().await?;
-        let session_key = session_peer_key(&session_id, &from_peer);
-        let stream_session = Arc::new(StreamSession {
-            peer_connection: peer_connection.clone(),
-            ffmpeg_child: Mutex::new(ffmpeg_child),
-        });
-        self.sessions
-            .write()
+    let answer = peer_connection
+        .create_answer(None)
+        .await
+        .map_err(|err| format!("create_answer failed: {err}"))?;
+    let mut gathered = peer_connection.gathering_complete_promise().await;
+    peer_connection
+        .set_local_description(answer.clone())
+        .await
+        .map_err(|err| format!("set_local_description failed: {err}"))?;
+    let answer = if trickle {
+        answer
+    } else {
+        if tokio::time::timeout(ICE_GATHER_WAIT, gathered.recv())
             .await
-            .insert(session_key.clone(), stream_session.clone());
+            .is_err()
+        {
+            warn!("ffmpeg_bot ice gathering incomplete, answering with the candidates so far");
+        }
+        peer_connection.local_description().await.unwrap_or(answer)
+    };
+    let profile_level_id = sdp::fmtp_profile_level_id(&video_track.codec().sdp_fmtp_line);
+    Ok(match profile_level_id {
+        Some(profile_level_id) => sdp::advertise_profile_level_id(&answer.sdp, profile_level_id),
+        None => answer.sdp,
+    })
+}
 
-        tokio::spawn(async move {
-            if let Err(err) = pump_h264_to_track(stream_session.clone(), video_track).await {
-                error!("ffmpeg_bot stream failed key={session_key} error={err}");
-            }
-            let _ = stream_session.peer_connection.close().await;
-        });
+fn h264_fmtp(profile_level_id: [u8; 3]) -> String {
+    format!(
+        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={}",
+        sdp::format_profile_level_id(profile_level_id)
+    )
+}
 
-        info!("ffmpeg_spawned session={session_id} to_peer={from_peer}");
-        Ok(())
+// Pass-through sources (the WHIP relay, a replayed file) forward their H264
+// untouched, so the answer has to carry the profile and level of the
+// stream's SPS, and the viewer has to decode that profile. The PLI gets a
+// first SPS out of a publisher with a long GOP; later viewers find it cached.
+async fn relay_h264_fmtp(pipeline: &CapturePipeline, offer_sdp: &str) -> Result<String, String> {
+    let source = pipeline.source_name();
+    pipeline.request_keyframe();
+    let profile_level_id = pipeline
+        .wait_parameter_sets(PARAMETER_SETS_WAIT)
+        .await
+        .and_then(|parameter_sets| h264::profile_level_id(&parameter_sets))
+        .ok_or_else(|| format!("{source} source has not sent an H264 SPS yet"))?;
+    let Some(published) = H264Profile::from_profile_level_id(profile_level_id) else {
+        return Err(format!(
+            "{source} source uses unsupported H264 profile-level-id {}",
+            sdp::format_profile_level_id(profile_level_id)
+        ));
+    };
+    match sdp::choose_h264(&sdp::offered_h264(offer_sdp), published) {
+        Some(format) if format.profile() == Some(published) => Ok(h264_fmtp(profile_level_id)),
+        _ => Err(format!(
+            "offer has no H264 {} the {source} source can send",
+            published.name()
+        )),
     }
+}
 
-    pub async fn handle_remote_ice(
-        &self,
-        session_id: &str,
-        from_peer: &str,
-        candidate_json: &str,
-    ) -> Result<(), String> {
-        let key = session_peer_key(session_id, from_peer);
-        let sessions = self.sessions.read().await;
-        let Some(stream_session) = sessions.get(&key) else {
-            return Err("bot peer connection not found".to_owned());
-        };
-        let candidate: RTCIceCandidateInit = serde_json::from_str(candidate_json)
-            .map_err(|err| format!("parse remote ice failed: {err}"))?;
-        stream_session
-            .peer_connection
-            .add_ice_candidate(candidate)
-            .await
-            .map_err(|err| format!("add_ice_candidate failed: {err}"))?;
-        Ok(())
+pub fn video_rtcp_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect()
+}
+
+// `None` signals end-of-candidates, which webrtc-rs takes as an empty candidate.
+async fn add_ice_candidate(
+    peer_connection: &RTCPeerConnection,
+    candidate: Option<RTCIceCandidateInit>,
+) -> Result<(), String> {
+    peer_connection
+        .add_ice_candidate(candidate.unwrap_or_default())
+        .await
+        .map_err(|err| format!("add_ice_candidate failed: {err}"))
+}
+
+// An SDP fragment's candidates in order, with None for end-of-candidates.
+fn fragment_candidates(fragment: &str) -> Vec<Option<RTCIceCandidateInit>> {
+    let fragment = sdp::parse_trickle_fragment(fragment);
+    let mut candidates: Vec<_> = fragment
+        .candidates
+        .into_iter()
+        .map(|candidate| {
+            Some(RTCIceCandidateInit {
+                candidate: candidate.candidate,
+                sdp_mid: candidate.mid,
+                sdp_mline_index: None,
+                username_fragment: candidate.ufrag,
+            })
+        })
+        .collect();
+    if fragment.end_of_candidates {
+        candidates.push(None);
     }
+    candidates
+}
+
+// A re-offer belongs to the current peer connection when the connection is
+// still usable and the client kept its DTLS certificate; a rebuilt browser
+// RTCPeerConnection always presents a new fingerprint.
+async fn is_same_remote_peer(peer_connection: &RTCPeerConnection, offer_sdp: &str) -> bool {
+    if matches!(
+        peer_connection.connection_state(),
+        RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+    ) {
+        return false;
+    }
+    let Some(current) = peer_connection.remote_description().await else {
+        return false;
+    };
+    match (sdp_fingerprint(&current.sdp), sdp_fingerprint(offer_sdp)) {
+        (Some(current), Some(offered)) => current.eq_ignore_ascii_case(offered),
+        _ => false,
+    }
+}
+
+fn sdp_fingerprint(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+}
+
+fn attach_input_channel(dc: &Arc<RTCDataChannel>) {
+    dc.on_open(Box::new(|| {
+        Box::pin(async move {
+            info!("input_channel_open");
+        })
+    }));
+    dc.on_message(Box::new(move |msg: DataChannelMessage| {
+        Box::pin(async move {
+            let Ok(text) = String::from_utf8(msg.data.to_vec()) else {
+                warn!("input_event_ignored invalid_utf8");
+                return;
+            };
+            match input_injector::inject_from_json(&text) {
+                Ok(()) => info!("input_event_received"),
+                Err(err) => warn!("input_event_failed error={err}"),
+            }
+        })
+    }));
+}
+
+// Every command is answered on the same channel with the settings in effect.
+fn attach_control_channel(dc: &Arc<RTCDataChannel>, control: ChannelContext) {
+    let key = control.session_key.clone();
+    dc.on_open(Box::new(move || {
+        Box::pin(async move {
+            info!("control_channel_open key={key}");
+        })
+    }));
+    let channel: Weak<RTCDataChannel> = Arc::downgrade(dc);
+    dc.on_message(Box::new(move |msg: DataChannelMessage| {
+        let control = control.clone();
+        let channel = channel.clone();
+        Box::pin(async move {
+            let reply = match String::from_utf8(msg.data.to_vec()) {
+                Ok(text) => control.handle_control(&text).await,
+                Err(_) => ControlReply::error("control message is not utf-8".to_owned(), None),
+            };
+            if let Some(error) = &reply.error {
+                warn!(
+                    "control_command_failed key={} error={error}",
+                    control.session_key
+                );
+            }
+            let Some(channel) = channel.upgrade() else {
+                return;
+            };
+            let reply = match serde_json::to_string(&reply) {
+                Ok(reply) => reply,
+                Err(err) => {
+                    warn!("control_reply_encode_failed error={err}");
+                    return;
+                }
+            };
+            if let Err(err) = channel.send_text(reply).await {
+                warn!("control_reply_failed error={err}");
+            }
+        })
+    }));
+}
+
+// Pushes a stats snapshot every STATS_PUSH_INTERVAL while the channel is open.
+fn attach_stats_channel(dc: &Arc<RTCDataChannel>, channels: ChannelContext) {
+    let channel = Arc::downgrade(dc);
+    dc.on_open(Box::new(move || {
+        Box::pin(async move {
+            info!("stats_channel_open key={}", channels.session_key);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(STATS_PUSH_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    let Some(channel) = channel.upgrade() else {
+                        break;
+                    };
+                    if channel.ready_state() != RTCDataChannelState::Open {
+                        break;
+                    }
+                    let Some(stream_session) = channels.session().await else {
+                        break;
+                    };
+                    let snapshot = stream_session
+                        .stats_snapshot(channels.session_key.clone())
+                        .await;
+                    let Ok(snapshot) = serde_json::to_string(&snapshot) else {
+                        continue;
+                    };
+                    if let Err(err) = channel.send_text(snapshot).await {
+                        warn!("stats_push_failed error={err}");
+                        break;
+                    }
+                }
+            });
+        })
+    }));
 }
 
 fn session_peer_key(session_id: &str, peer_id: &str) -> String {
     format!("{session_id}:{peer_id}")
 }
 
-async fn spawn_ffmpeg_process() -> Result<Child, String> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.arg("-hide_banner")
-        .arg("-loglevel")
-        .arg("warning")
-        .arg("-init_hw_device")
-        .arg("d3d11va=dx")
-        .arg("-init_hw_device")
-        .arg("qsv=qs@dx")
-        .arg("-filter_hw_device")
-        .arg("dx")
-        .arg("-f")
-        .arg("lavfi")
-        .arg("-i")
-        .arg("ddagrab=framerate=60:output_idx=0:draw_mouse=1")
-        .arg("-vf")
-        .arg("hwmap=derive_device=qsv,format=qsv")
-        .arg("-an")
-        .arg("-c:v")
-        .arg("h264_qsv")
-        .arg("-profile:v")
-        .arg("baseline")
-        .arg("-preset")
-        .arg("veryfast")
-        .arg("-g")
-        .arg("60")
-        .arg("-keyint_min")
-        .arg("60")
-        .arg("-b:v")
-        .arg("5M")
-        .arg("-maxrate")
-        .arg("5M")
-        .arg("-bufsize")
-        .arg("5M")
-        .arg("-bf")
-        .arg("0")
-        .arg("-look_ahead")
-        .arg("0")
-        .arg("-async_depth")
-        .arg("1")
-        .arg("-bsf:v")
-        .arg("h264_metadata=aud=insert")
-        .arg("-f")
-        .arg("h264")
-        .arg("-")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-
-    cmd.spawn()
-        .map_err(|err| format!("ffmpeg spawn failed: {err}"))
+// Subscribes to the pipeline for `setup` once the viewer's request is clamped
+// to the server limits.
+async fn subscribe_stream(
+    pipelines: &Arc<PipelineRegistry>,
+    config: &CaptureConfig,
+    setup: &StreamSetup,
+) -> Result<(Arc<CapturePipeline>, broadcast::Receiver<AccessUnit>), String> {
+    let mut profile = setup.profile.clone();
+    config.limits.apply(&setup.request, &mut profile);
+    // ABR may lower the bitrate for congestion but not exceed the request.
+    let mut abr = config.abr.clone();
+    abr.max_kbps = abr.max_kbps.min(profile.bitrate_kbps);
+    abr.min_kbps = abr.min_kbps.min(abr.max_kbps);
+    if let Some(abr_kbps) = setup.abr_kbps {
+        profile.bitrate_kbps = abr_kbps.clamp(abr.min_kbps, abr.max_kbps);
+    }
+    pipelines
+        .subscribe(setup.capture.clone(), profile, &abr, &config.supervisor)
+        .await
 }
 
-async fn pump_h264_to_track(
+// Writes the shared pipelines' output to this viewer's tracks. Video starts,
+// and restarts after lagging or resuming, on a keyframe and asks the encoder
+// for one when the GOP does not deliver it soon enough. Video follows its
+// pipeline's ABR target to the pipeline encoding at it. Both tracks are
+// written as media is produced, so the sender reports interceptor maps them
+// onto the same wall clock and the browser keeps them in sync.
+async fn forward_to_track(
     stream_session: Arc<StreamSession>,
-    video_track: Arc<TrackLocalStaticSample>,
+    mut access_units: broadcast::Receiver<AccessUnit>,
+    mut audio_packets: Option<broadcast::Receiver<Bytes>>,
+    pipelines: &Arc<PipelineRegistry>,
+    config: &BridgeConfig,
 ) -> Result<(), String> {
-    let mut child = stream_session.ffmpeg_child.lock().await;
-    let Some(mut stdout) = child.stdout.take() else {
-        return Err("ffmpeg stdout not piped".to_owned());
-    };
-
-    let mut buf = [0_u8; 8192];
-    let mut annexb = Vec::<u8>::new();
+    let mut abr_target = stream_session.pipeline().abr_target();
     let mut sent_samples: u64 = 0;
-    let mut saw_first = false;
-    let mut current_access_unit = Vec::<u8>::new();
+    let mut awaiting_keyframe = true;
+    let keyframe_wait = tokio::time::sleep(KEYFRAME_WAIT);
+    tokio::pin!(keyframe_wait);
+    write_parameter_sets(&stream_session).await?;
 
     loop {
-        let read = stdout
-            .read(&mut buf)
-            .await
-            .map_err(|err| format!("ffmpeg stdout read failed: {err}"))?;
-        if read == 0 {
-            break;
-        }
-        annexb.extend_from_slice(&buf[..read]);
-        while let Some(nal_unit) = pop_annexb_nal_unit(&mut annexb) {
-            let nal_type = annexb_nal_type(&nal_unit);
-            if nal_type == Some(9) && !current_access_unit.is_empty() {
-                write_h264_sample(&video_track, &current_access_unit).await?;
-                current_access_unit.clear();
-                sent_samples += 1;
-                if !saw_first {
-                    saw_first = true;
-                    info!("first_frame_ingested");
+        let access_unit = tokio::select! {
+            _ = stream_session.shutdown.notified() => {
+                info!("ffmpeg_bot shutdown requested");
+                break;
+            }
+            _ = stream_session.resync.notified() => {
+                let switched = stream_session
+                    .pipeline_switch
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .take();
+                if let Some(switched) = switched {
+                    access_units = switched;
+                    let pipeline = stream_session.pipeline();
+                    abr_target = pipeline.abr_target();
+                    let rotation = stream_session
+                        .recorder()
+                        .as_ref()
+                        .map(|recorder| recorder.rotate(&pipeline.profile()));
+                    if let Some(rotation) = rotation {
+                        rotation.await;
+                    }
+                    if let Some(mut replay) = stream_session.replay() {
+                        replay.clear();
+                    }
                 }
-                if sent_samples % 120 == 0 {
-                    info!("track_active samples_sent={sent_samples}");
+                awaiting_keyframe = true;
+                write_parameter_sets(&stream_session).await?;
+                if stream_session.pipeline().request_keyframe() {
+                    info!("ffmpeg_bot keyframe_requested reason=resync");
+                }
+                keyframe_wait.as_mut().reset(Instant::now() + KEYFRAME_WAIT);
+                continue;
+            }
+            Ok(()) = abr_target.changed() => {
+                let target = *abr_target.borrow_and_update();
+                if let Some(bitrate_kbps) = target {
+                    if let Err(err) = stream_session
+                        .follow_abr(bitrate_kbps, pipelines, config)
+                        .await
+                    {
+                        warn!("ffmpeg_bot abr_switch failed to_kbps={bitrate_kbps} error={err}");
+                    }
+                }
+                continue;
+            }
+            _ = &mut keyframe_wait,
+                if awaiting_keyframe && !stream_session.video_paused.load(Ordering::SeqCst) =>
+            {
+                if stream_session.pipeline().request_keyframe() {
+                    info!("ffmpeg_bot keyframe_requested reason=no_idr_within_wait");
+                }
+                keyframe_wait.as_mut().reset(Instant::now() + KEYFRAME_WAIT);
+                continue;
+            }
+            received = access_units.recv() => match received {
+                Ok(access_unit) => access_unit,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("ffmpeg_bot viewer lagged skipped={skipped}");
+                    awaiting_keyframe = true;
+                    write_parameter_sets(&stream_session).await?;
+                    stream_session.pipeline().request_keyframe();
+                    keyframe_wait.as_mut().reset(Instant::now() + KEYFRAME_WAIT);
+                    continue;
+                }
+                Err(RecvError::Closed) => match stream_session.pipeline().failure() {
+                    Some(failure) => return Err(format!("capture failed: {failure}")),
+                    None => break,
+                },
+            },
+            received = recv_audio(&mut audio_packets), if audio_packets.is_some() => {
+                match received {
+                    Ok(packet) => {
+                        if let Some(audio_track) = &stream_session.audio_track {
+                            if let Some(recorder) = stream_session.recorder().as_ref() {
+                                recorder.audio(packet.clone());
+                            }
+                            if let Some(mut replay) = stream_session.replay() {
+                                replay.audio(packet.clone());
+                            }
+                            write_opus_sample(audio_track, packet).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("ffmpeg_bot audio lagged skipped={skipped}");
+                    }
+                    Err(RecvError::Closed) => {
+                        warn!("ffmpeg_bot audio ended");
+                        audio_packets = None;
+                    }
                 }
+                continue;
             }
-            current_access_unit.extend_from_slice(&nal_unit);
+        };
+
+        // Paused viewers stay subscribed so resuming only waits for a keyframe.
+        if stream_session.video_paused.load(Ordering::SeqCst) {
+            continue;
+        }
+        if awaiting_keyframe {
+            if !access_unit.keyframe {
+                continue;
+            }
+            awaiting_keyframe = false;
+        }
+        let frame_bytes = access_unit.data.len();
+        if let Some(recorder) = stream_session.recorder().as_ref() {
+            recorder.video(access_unit.data.clone(), access_unit.keyframe);
+        }
+        if let Some(mut replay) = stream_session.replay() {
+            replay.video(access_unit.data.clone(), access_unit.keyframe);
+        }
+        stream_session
+            .video_track
+            .write(access_unit.data, access_unit.duration)
+            .await?;
+        stream_session
+            .stats()
+            .on_frame(frame_bytes, access_unit.keyframe);
+        sent_samples += 1;
+        if sent_samples.is_multiple_of(120) {
+            info!("track_active samples_sent={sent_samples}");
         }
     }
+    Ok(())
+}
 
-    if !current_access_unit.is_empty() {
-        write_h264_sample(&video_track, &current_access_unit).await?;
-    }
+// Hands the cached SPS/PPS to the track's packetizer right away; it sends them
+// ahead of the next frame without advancing the RTP timestamp.
+async fn write_parameter_sets(stream_session: &StreamSession) -> Result<(), String> {
+    let Some(parameter_sets) = stream_session.pipeline().parameter_sets() else {
+        return Ok(());
+    };
+    stream_session
+        .video_track
+        .write(parameter_sets, Duration::ZERO)
+        .await
+        .map_err(|err| format!("parameter sets: {err}"))
+}
 
-    let _ = child.kill().await;
-    Ok(())
+async fn recv_audio(
+    audio_packets: &mut Option<broadcast::Receiver<Bytes>>,
+) -> Result<Bytes, RecvError> {
+    match audio_packets {
+        Some(audio_packets) => audio_packets.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
-async fn write_h264_sample(
-    video_track: &Arc<TrackLocalStaticSample>,
-    sample_bytes: &[u8],
+async fn write_opus_sample(
+    audio_track: &Arc<TrackLocalStaticSample>,
+    packet: Bytes,
 ) -> Result<(), String> {
-    video_track
+    audio_track
         .write_sample(&Sample {
-            data: sample_bytes.to_vec().into(),
-            duration: Duration::from_millis(33),
+            data: packet,
+            duration: Duration::from_millis(OPUS_FRAME_MS),
             ..Default::default()
         })
         .await
-        .map_err(|err| format!("write_sample failed: {err}"))
+        .map_err(|err| format!("write_sample audio failed: {err}"))
 }
 
-fn annexb_nal_type(nal_unit: &[u8]) -> Option<u8> {
-    let start_code_len = if nal_unit.len() >= 4
-        && nal_unit[0] == 0
-        && nal_unit[1] == 0
-        && nal_unit[2] == 0
-        && nal_unit[3] == 1
-    {
-        4
-    } else if nal_unit.len() >= 3 && nal_unit[0] == 0 && nal_unit[1] == 0 && nal_unit[2] == 1 {
-        3
-    } else {
-        return None;
+async fn enqueue_message(
+    signaling: &Signaling,
+    session_id: &str,
+    to_peer: &str,
+    msg: SignalMessage,
+) {
+    let Signaling::Polling(state) = signaling else {
+        return;
     };
-    nal_unit.get(start_code_len).map(|byte| byte & 0x1F)
-}
-
-fn pop_annexb_nal_unit(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
-    let first = find_start_code(buffer, 0)?;
-    let second = find_start_code(buffer, first + 3)?;
-    if first > 0 {
-        buffer.drain(0..first);
-        let second_shifted = second - first;
-        let sample = buffer[..second_shifted].to_vec();
-        buffer.drain(0..second_shifted);
-        return Some(sample);
-    }
-    let sample = buffer[..second].to_vec();
-    buffer.drain(0..second);
-    Some(sample)
-}
-
-fn find_start_code(buffer: &[u8], from: usize) -> Option<usize> {
-    if buffer.len() < 4 || from >= buffer.len().saturating_sub(3) {
-        return None;
-    }
-    let mut i = from;
-    while i + 3 < buffer.len() {
-        if buffer[i] == 0 && buffer[i + 1] == 0 && buffer[i + 2] == 1 {
-            return Some(i);
-        }
-        if i + 4 < buffer.len()
-            && buffer[i] == 0
-            && buffer[i + 1] == 0
-            && buffer[i + 2] == 0
-            && buffer[i + 3] == 1
-        {
-            return Some(i);
-        }
-        i += 1;
-    }
-    None
-}
-
-async fn enqueue_message(state: &AppState, session_id: &str, to_peer: &str, msg: SignalMessage) {
     let mut sessions = state.sessions.write().await;
     let Some(session) = sessions.get_mut(session_id) else {
         return;
@@ -0,0 +1,192 @@
+use crate::encoder::VideoCodec;
+
+// Matroska element IDs used by the writer.
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43_B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+// Size of a segment or cluster whose end is not known while writing live.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const OPUS_SAMPLE_RATE: u32 = 48_000;
+const OPUS_CHANNELS: u8 = 2;
+
+pub const VIDEO_TRACK: u8 = 1;
+pub const AUDIO_TRACK: u8 = 2;
+
+// What the tracks element needs to describe the video stream.
+pub struct VideoTrackInfo {
+    pub codec: VideoCodec,
+    pub resolution: Option<(u32, u32)>,
+    pub codec_private: Option<Vec<u8>>,
+}
+
+// EBML header, segment info and track list of a live Matroska file. The
+// segment has an unknown size and no cues, which players handle like a
+// recording that was cut off: it plays from the start, seeking by scanning.
+pub fn header(video: &VideoTrackInfo, audio: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut ebml = Vec::new();
+    uint_element(&mut ebml, EBML_VERSION, 1);
+    uint_element(&mut ebml, EBML_READ_VERSION, 1);
+    uint_element(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+    uint_element(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+    element(&mut ebml, DOC_TYPE, b"matroska");
+    uint_element(&mut ebml, DOC_TYPE_VERSION, 4);
+    uint_element(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+    element(&mut out, EBML, &ebml);
+
+    write_id(&mut out, SEGMENT);
+    out.extend_from_slice(&UNKNOWN_SIZE);
+
+    let mut info = Vec::new();
+    // Block timestamps are in milliseconds.
+    uint_element(&mut info, TIMESTAMP_SCALE, 1_000_000);
+    element(&mut info, MUXING_APP, env!("CARGO_PKG_NAME").as_bytes());
+    element(&mut info, WRITING_APP, env!("CARGO_PKG_NAME").as_bytes());
+    element(&mut out, INFO, &info);
+
+    let mut tracks = Vec::new();
+    let mut video_entry = Vec::new();
+    uint_element(&mut video_entry, TRACK_NUMBER, u64::from(VIDEO_TRACK));
+    uint_element(&mut video_entry, TRACK_UID, u64::from(VIDEO_TRACK));
+    uint_element(&mut video_entry, TRACK_TYPE, TRACK_TYPE_VIDEO);
+    uint_element(&mut video_entry, FLAG_LACING, 0);
+    element(
+        &mut video_entry,
+        CODEC_ID,
+        video_codec_id(video.codec).as_bytes(),
+    );
+    if let Some(codec_private) = &video.codec_private {
+        element(&mut video_entry, CODEC_PRIVATE, codec_private);
+    }
+    // Native captures leave the size to the decoder.
+    if let Some((width, height)) = video.resolution {
+        let mut dimensions = Vec::new();
+        uint_element(&mut dimensions, PIXEL_WIDTH, u64::from(width));
+        uint_element(&mut dimensions, PIXEL_HEIGHT, u64::from(height));
+        element(&mut video_entry, VIDEO, &dimensions);
+    }
+    element(&mut tracks, TRACK_ENTRY, &video_entry);
+
+    if audio {
+        let mut audio_entry = Vec::new();
+        uint_element(&mut audio_entry, TRACK_NUMBER, u64::from(AUDIO_TRACK));
+        uint_element(&mut audio_entry, TRACK_UID, u64::from(AUDIO_TRACK));
+        uint_element(&mut audio_entry, TRACK_TYPE, TRACK_TYPE_AUDIO);
+        uint_element(&mut audio_entry, FLAG_LACING, 0);
+        element(&mut audio_entry, CODEC_ID, b"A_OPUS");
+        element(&mut audio_entry, CODEC_PRIVATE, &opus_head());
+        let mut settings = Vec::new();
+        element(
+            &mut settings,
+            SAMPLING_FREQUENCY,
+            &f64::from(OPUS_SAMPLE_RATE).to_be_bytes(),
+        );
+        uint_element(&mut settings, CHANNELS, u64::from(OPUS_CHANNELS));
+        element(&mut audio_entry, AUDIO, &settings);
+        element(&mut tracks, TRACK_ENTRY, &audio_entry);
+    }
+    element(&mut out, TRACKS, &tracks);
+    out
+}
+
+// Opens a cluster; its blocks are timed relative to `timestamp_ms`.
+pub fn cluster(timestamp_ms: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_id(&mut out, CLUSTER);
+    out.extend_from_slice(&UNKNOWN_SIZE);
+    uint_element(&mut out, CLUSTER_TIMESTAMP, timestamp_ms);
+    out
+}
+
+pub fn simple_block(track: u8, relative_ms: i16, keyframe: bool, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len() + 4);
+    // Track numbers below 127 fit a one byte EBML varint.
+    body.push(0x80 | track);
+    body.extend_from_slice(&relative_ms.to_be_bytes());
+    body.push(if keyframe { 0x80 } else { 0x00 });
+    body.extend_from_slice(data);
+    let mut out = Vec::with_capacity(body.len() + 12);
+    element(&mut out, SIMPLE_BLOCK, &body);
+    out
+}
+
+fn video_codec_id(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 => "V_MPEG4/ISO/AVC",
+        VideoCodec::H265 => "V_MPEGH/ISO/HEVC",
+        VideoCodec::Vp8 => "V_VP8",
+        VideoCodec::Vp9 => "V_VP9",
+        VideoCodec::Av1 => "V_AV1",
+    }
+}
+
+fn opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(OPUS_CHANNELS);
+    // Pre-skip is left at zero: the packets come from a running encoder, so
+    // there is no priming at the start of a recording to hide.
+    head.extend_from_slice(&0_u16.to_le_bytes());
+    head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+    head.extend_from_slice(&0_i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+fn element(out: &mut Vec<u8>, id: u32, body: &[u8]) {
+    write_id(out, id);
+    write_size(out, body.len() as u64);
+    out.extend_from_slice(body);
+}
+
+fn uint_element(out: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = (value.leading_zeros() / 8).min(7) as usize;
+    element(out, id, &bytes[skip..]);
+}
+
+// IDs keep their length marker bits, so they are written as-is.
+fn write_id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8).min(3) as usize;
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+// Shortest EBML varint for `size`; all-ones values are reserved for unknown.
+fn write_size(out: &mut Vec<u8>, size: u64) {
+    let length = (1..8_u32)
+        .find(|length| size < (1 << (7 * length)) - 1)
+        .unwrap_or(8);
+    let marked = size | (1 << (7 * length));
+    out.extend_from_slice(&marked.to_be_bytes()[8 - length as usize..]);
+}
@@ -7,6 +7,58 @@ pub struct SessionPeerQuery {
     pub peer_id: String,
 }
 
+// Query used when sending an offer; the optional fields only matter when the
+// offer targets the bot.
+#[derive(Deserialize)]
+pub struct OfferQuery {
+    pub session_id: String,
+    pub peer_id: String,
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fps: Option<u32>,
+    #[serde(default)]
+    pub bitrate_kbps: Option<u32>,
+}
+
+impl OfferQuery {
+    pub fn session_peer(&self) -> SessionPeerQuery {
+        SessionPeerQuery {
+            session_id: self.session_id.clone(),
+            peer_id: self.peer_id.clone(),
+        }
+    }
+
+    pub fn stream_request(&self) -> StreamRequest {
+        StreamRequest {
+            source: self.source.clone(),
+            room: None,
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            bitrate_kbps: self.bitrate_kbps,
+        }
+    }
+}
+
+// Stream setup a client asks the bot for. Unset fields keep the server
+// defaults; the rest is clamped to the configured stream limits.
+#[derive(Clone, Debug, Default)]
+pub struct StreamRequest {
+    pub source: Option<String>,
+    // WHIP room whose publisher to relay, for viewers outside that room's
+    // signaling session; unset means the session's own room.
+    pub room: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<u32>,
+    pub bitrate_kbps: Option<u32>,
+}
+
 // Query used when polling pending signaling messages.
 #[derive(Deserialize)]
 pub struct PollQuery {
@@ -20,6 +72,8 @@ pub struct SdpPayload {
     pub from: String,
     pub to: String,
     pub sdp: String,
+    #[serde(default)]
+    pub resume_token: Option<String>,
 }
 
 // Body for ICE candidate signaling messages.
@@ -30,15 +84,53 @@ pub struct IceCandidatePayload {
     pub candidate: String,
 }
 
+// Body for the end-of-candidates signal sent once ICE gathering completes.
+#[derive(Deserialize)]
+pub struct EndOfCandidatesPayload {
+    pub from: String,
+    pub to: String,
+}
+
 // Signal protocol messages exchanged between browser peers via server relay.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum SignalMessage {
     Join { peer_id: String },
     Leave { peer_id: String },
-    Offer { from: String, to: String, sdp: String },
-    Answer { from: String, to: String, sdp: String },
+    Offer {
+        from: String,
+        to: String,
+        sdp: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    Answer {
+        from: String,
+        to: String,
+        sdp: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
     IceCandidate { from: String, to: String, candidate: String },
+    EndOfCandidates { from: String, to: String },
+    // The bot could not keep the capture running and closed the stream.
+    StreamFailed { from: String, to: String, reason: String },
+}
+
+// Join reply carrying the ICE servers the client should use.
+#[derive(Serialize)]
+pub struct JoinResponse {
+    pub ok: bool,
+    pub ice_servers: Vec<IceServer>,
+}
+
+#[derive(Serialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub credential: String,
 }
 
 // Generic API response for simple command endpoints.
@@ -0,0 +1,509 @@
+use bytes::Bytes;
+
+use crate::{codec_config, encoder::VideoCodec};
+
+const MOVIE_TIMESCALE: u32 = 1000;
+pub const VIDEO_TIMESCALE: u32 = 90_000;
+pub const AUDIO_TIMESCALE: u32 = 48_000;
+const OPUS_CHANNELS: u16 = 2;
+// ISO 639-2 "und" packed into three 5-bit letters.
+const LANGUAGE_UNDETERMINED: u16 = 0x55C4;
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+// One encoded frame; `duration` is in the track's timescale.
+pub struct Mp4Sample {
+    pub data: Bytes,
+    pub duration: u32,
+    pub keyframe: bool,
+}
+
+// Video of a clip in Annex-B/IVF form, starting on a keyframe.
+pub struct Mp4Video {
+    pub codec: VideoCodec,
+    pub resolution: Option<(u32, u32)>,
+    pub samples: Vec<Mp4Sample>,
+}
+
+enum TrackKind<'a> {
+    Video(&'a Mp4Video, Option<Vec<u8>>, (u32, u32)),
+    Audio,
+}
+
+// A complete (non-fragmented) MP4 file: `ftyp`, one `mdat` holding every
+// video sample followed by every Opus packet, and a `moov` with one chunk
+// per track. Video is stored without composition offsets since the encoders
+// run without B-frames.
+pub fn write_clip(video: &Mp4Video, audio: &[Mp4Sample]) -> Result<Vec<u8>, String> {
+    let Some(first) = video.samples.first().filter(|sample| sample.keyframe) else {
+        return Err("clip does not start on a keyframe".to_owned());
+    };
+    let config = codec_config::decoder_configuration(video.codec, &first.data)?;
+    // The bitstream is authoritative; the configured resolution is only a
+    // guess when the encoder scaled or the source picked its own size.
+    let dimensions = codec_config::frame_size(video.codec, &first.data)
+        .or(video.resolution)
+        .unwrap_or((0, 0));
+    let video_data: Vec<Bytes> = video
+        .samples
+        .iter()
+        .map(|sample| codec_config::length_prefixed(video.codec, sample.data.clone()))
+        .collect();
+
+    let mut out = Vec::new();
+    write_box(&mut out, b"ftyp", |ftyp| {
+        ftyp.extend_from_slice(b"isom");
+        ftyp.extend_from_slice(&512_u32.to_be_bytes());
+        for brand in [b"isom", b"iso2", b"mp41"] {
+            ftyp.extend_from_slice(brand);
+        }
+    });
+
+    let payload_len: usize = video_data.iter().map(Bytes::len).sum::<usize>()
+        + audio.iter().map(|sample| sample.data.len()).sum::<usize>();
+    let mdat_len = u32::try_from(payload_len + 8)
+        .map_err(|_| "clip exceeds 4 GiB, shorten the replay window".to_owned())?;
+    out.extend_from_slice(&mdat_len.to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    let video_offset = out.len() as u32;
+    for data in &video_data {
+        out.extend_from_slice(data);
+    }
+    let audio_offset = out.len() as u32;
+    for sample in audio {
+        out.extend_from_slice(&sample.data);
+    }
+
+    let video_sizes: Vec<u32> = video_data.iter().map(|data| data.len() as u32).collect();
+    let audio_sizes: Vec<u32> = audio
+        .iter()
+        .map(|sample| sample.data.len() as u32)
+        .collect();
+    let video_duration = track_duration(&video.samples);
+    let audio_duration = track_duration(audio);
+    let movie_duration = (video_duration * u64::from(MOVIE_TIMESCALE) / u64::from(VIDEO_TIMESCALE))
+        .max(audio_duration * u64::from(MOVIE_TIMESCALE) / u64::from(AUDIO_TIMESCALE));
+    let track_count = if audio.is_empty() { 1 } else { 2 };
+
+    write_box(&mut out, b"moov", |moov| {
+        write_full_box(moov, b"mvhd", 0, 0, |mvhd| {
+            mvhd.extend_from_slice(&[0; 8]);
+            mvhd.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+            mvhd.extend_from_slice(&(movie_duration as u32).to_be_bytes());
+            mvhd.extend_from_slice(&0x0001_0000_u32.to_be_bytes());
+            mvhd.extend_from_slice(&0x0100_u16.to_be_bytes());
+            mvhd.extend_from_slice(&[0; 10]);
+            write_matrix(mvhd);
+            mvhd.extend_from_slice(&[0; 24]);
+            mvhd.extend_from_slice(&(track_count + 1_u32).to_be_bytes());
+        });
+        write_track(
+            moov,
+            1,
+            TrackKind::Video(video, config, dimensions),
+            &video.samples,
+            &video_sizes,
+            video_offset,
+        );
+        if !audio.is_empty() {
+            write_track(moov, 2, TrackKind::Audio, audio, &audio_sizes, audio_offset);
+        }
+    });
+    Ok(out)
+}
+
+fn track_duration(samples: &[Mp4Sample]) -> u64 {
+    samples
+        .iter()
+        .map(|sample| u64::from(sample.duration))
+        .sum()
+}
+
+fn write_track(
+    moov: &mut Vec<u8>,
+    track_id: u32,
+    kind: TrackKind<'_>,
+    samples: &[Mp4Sample],
+    sizes: &[u32],
+    chunk_offset: u32,
+) {
+    let (timescale, handler, handler_name) = match kind {
+        TrackKind::Video(..) => (VIDEO_TIMESCALE, b"vide", "VideoHandler"),
+        TrackKind::Audio => (AUDIO_TIMESCALE, b"soun", "SoundHandler"),
+    };
+    let (width, height) = match &kind {
+        TrackKind::Video(_, _, dimensions) => *dimensions,
+        TrackKind::Audio => (0, 0),
+    };
+    let duration = track_duration(samples);
+    let movie_duration = duration * u64::from(MOVIE_TIMESCALE) / u64::from(timescale);
+
+    write_box(moov, b"trak", |trak| {
+        // Flags: enabled and in the movie.
+        write_full_box(trak, b"tkhd", 0, 3, |tkhd| {
+            tkhd.extend_from_slice(&[0; 8]);
+            tkhd.extend_from_slice(&track_id.to_be_bytes());
+            tkhd.extend_from_slice(&[0; 4]);
+            tkhd.extend_from_slice(&(movie_duration as u32).to_be_bytes());
+            tkhd.extend_from_slice(&[0; 12]);
+            let volume: u16 = if matches!(kind, TrackKind::Audio) {
+                0x0100
+            } else {
+                0
+            };
+            tkhd.extend_from_slice(&volume.to_be_bytes());
+            tkhd.extend_from_slice(&[0; 2]);
+            write_matrix(tkhd);
+            tkhd.extend_from_slice(&(width << 16).to_be_bytes());
+            tkhd.extend_from_slice(&(height << 16).to_be_bytes());
+        });
+        write_box(trak, b"mdia", |mdia| {
+            write_full_box(mdia, b"mdhd", 0, 0, |mdhd| {
+                mdhd.extend_from_slice(&[0; 8]);
+                mdhd.extend_from_slice(&timescale.to_be_bytes());
+                mdhd.extend_from_slice(&(duration as u32).to_be_bytes());
+                mdhd.extend_from_slice(&LANGUAGE_UNDETERMINED.to_be_bytes());
+                mdhd.extend_from_slice(&[0; 2]);
+            });
+            write_full_box(mdia, b"hdlr", 0, 0, |hdlr| {
+                hdlr.extend_from_slice(&[0; 4]);
+                hdlr.extend_from_slice(handler);
+                hdlr.extend_from_slice(&[0; 12]);
+                hdlr.extend_from_slice(handler_name.as_bytes());
+                hdlr.push(0);
+            });
+            write_box(mdia, b"minf", |minf| {
+                match kind {
+                    TrackKind::Video(..) => write_full_box(minf, b"vmhd", 0, 1, |vmhd| {
+                        vmhd.extend_from_slice(&[0; 8]);
+                    }),
+                    TrackKind::Audio => write_full_box(minf, b"smhd", 0, 0, |smhd| {
+                        smhd.extend_from_slice(&[0; 4]);
+                    }),
+                }
+                write_box(minf, b"dinf", |dinf| {
+                    write_full_box(dinf, b"dref", 0, 0, |dref| {
+                        dref.extend_from_slice(&1_u32.to_be_bytes());
+                        // Flag 1: media is in this file.
+                        write_full_box(dref, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(minf, b"stbl", |stbl| {
+                    write_full_box(stbl, b"stsd", 0, 0, |stsd| {
+                        stsd.extend_from_slice(&1_u32.to_be_bytes());
+                        match &kind {
+                            TrackKind::Video(video, config, dimensions) => {
+                                write_video_entry(stsd, video, config.as_deref(), *dimensions)
+                            }
+                            TrackKind::Audio => write_opus_entry(stsd),
+                        }
+                    });
+                    write_sample_tables(stbl, samples, sizes, chunk_offset, &kind);
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_tables(
+    stbl: &mut Vec<u8>,
+    samples: &[Mp4Sample],
+    sizes: &[u32],
+    chunk_offset: u32,
+    kind: &TrackKind<'_>,
+) {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for sample in samples {
+        match runs.last_mut() {
+            Some((count, duration)) if *duration == sample.duration => *count += 1,
+            _ => runs.push((1, sample.duration)),
+        }
+    }
+    write_full_box(stbl, b"stts", 0, 0, |stts| {
+        stts.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+        for (count, duration) in &runs {
+            stts.extend_from_slice(&count.to_be_bytes());
+            stts.extend_from_slice(&duration.to_be_bytes());
+        }
+    });
+    // Without stss every sample is a sync sample, which holds for Opus.
+    if matches!(kind, TrackKind::Video(..)) {
+        let keyframes: Vec<u32> = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, sample)| sample.keyframe)
+            .map(|(index, _)| index as u32 + 1)
+            .collect();
+        write_full_box(stbl, b"stss", 0, 0, |stss| {
+            stss.extend_from_slice(&(keyframes.len() as u32).to_be_bytes());
+            for number in &keyframes {
+                stss.extend_from_slice(&number.to_be_bytes());
+            }
+        });
+    }
+    write_full_box(stbl, b"stsz", 0, 0, |stsz| {
+        stsz.extend_from_slice(&[0; 4]);
+        stsz.extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+        for size in sizes {
+            stsz.extend_from_slice(&size.to_be_bytes());
+        }
+    });
+    write_full_box(stbl, b"stsc", 0, 0, |stsc| {
+        stsc.extend_from_slice(&1_u32.to_be_bytes());
+        stsc.extend_from_slice(&1_u32.to_be_bytes());
+        stsc.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        stsc.extend_from_slice(&1_u32.to_be_bytes());
+    });
+    write_full_box(stbl, b"stco", 0, 0, |stco| {
+        stco.extend_from_slice(&1_u32.to_be_bytes());
+        stco.extend_from_slice(&chunk_offset.to_be_bytes());
+    });
+}
+
+// hev1 rather than hvc1 because the keyframes repeat VPS/SPS/PPS in-band.
+fn write_video_entry(
+    stsd: &mut Vec<u8>,
+    video: &Mp4Video,
+    config: Option<&[u8]>,
+    (width, height): (u32, u32),
+) {
+    let kind = match video.codec {
+        VideoCodec::H264 => b"avc1",
+        VideoCodec::H265 => b"hev1",
+        VideoCodec::Vp8 => b"vp08",
+        VideoCodec::Vp9 => b"vp09",
+        VideoCodec::Av1 => b"av01",
+    };
+    write_box(stsd, kind, |entry| {
+        entry.extend_from_slice(&[0; 6]);
+        entry.extend_from_slice(&1_u16.to_be_bytes());
+        entry.extend_from_slice(&[0; 16]);
+        entry.extend_from_slice(&(width as u16).to_be_bytes());
+        entry.extend_from_slice(&(height as u16).to_be_bytes());
+        // 72 dpi in both directions.
+        entry.extend_from_slice(&0x0048_0000_u32.to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000_u32.to_be_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&1_u16.to_be_bytes());
+        entry.extend_from_slice(&[0; 32]);
+        entry.extend_from_slice(&0x0018_u16.to_be_bytes());
+        entry.extend_from_slice(&(-1_i16).to_be_bytes());
+        match (video.codec, config) {
+            (VideoCodec::H264, Some(config)) => write_box(entry, b"avcC", |avcc| {
+                avcc.extend_from_slice(config);
+            }),
+            (VideoCodec::H265, Some(config)) => write_box(entry, b"hvcC", |hvcc| {
+                hvcc.extend_from_slice(config);
+            }),
+            (VideoCodec::Av1, Some(config)) => write_box(entry, b"av1C", |av1c| {
+                av1c.extend_from_slice(config);
+            }),
+            // Profile 0, level left to the decoder, 8-bit 4:2:0 BT.709
+            // limited range, no codec initialization data.
+            (VideoCodec::Vp8 | VideoCodec::Vp9, _) => {
+                write_full_box(entry, b"vpcC", 1, 0, |vpcc| {
+                    vpcc.extend_from_slice(&[0, 0, 0x82, 1, 1, 1, 0, 0]);
+                })
+            }
+            _ => {}
+        }
+    });
+}
+
+fn write_opus_entry(stsd: &mut Vec<u8>) {
+    write_box(stsd, b"Opus", |entry| {
+        entry.extend_from_slice(&[0; 6]);
+        entry.extend_from_slice(&1_u16.to_be_bytes());
+        entry.extend_from_slice(&[0; 8]);
+        entry.extend_from_slice(&OPUS_CHANNELS.to_be_bytes());
+        entry.extend_from_slice(&16_u16.to_be_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&(AUDIO_TIMESCALE << 16).to_be_bytes());
+        // Opus in ISOBMFF: version, channels, pre-skip, input rate, gain and
+        // mapping family, big-endian unlike OpusHead.
+        write_box(entry, b"dOps", |dops| {
+            dops.push(0);
+            dops.push(OPUS_CHANNELS as u8);
+            dops.extend_from_slice(&0_u16.to_be_bytes());
+            dops.extend_from_slice(&AUDIO_TIMESCALE.to_be_bytes());
+            dops.extend_from_slice(&0_i16.to_be_bytes());
+            dops.push(0);
+        });
+    });
+}
+
+fn write_matrix(out: &mut Vec<u8>) {
+    for value in UNITY_MATRIX {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |full| {
+        full.extend_from_slice(&((u32::from(version) << 24) | flags).to_be_bytes());
+        body(full);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[
+        0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0xDA, 0x0A, 0x37, 0xE4, 0x84, 0x00, 0x00, 0x03, 0x00,
+        0x04, 0x00, 0x00, 0x03, 0x00, 0xF2, 0x10,
+    ];
+    const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80];
+    const IDR: &[u8] = &[0, 0, 1, 0x65, 0x88, 0x84, 0x21];
+    const P: &[u8] = &[0, 0, 1, 0x41, 0x9A, 0x02];
+
+    fn sample(data: &[u8], duration: u32, keyframe: bool) -> Mp4Sample {
+        Mp4Sample {
+            data: Bytes::copy_from_slice(data),
+            duration,
+            keyframe,
+        }
+    }
+
+    fn clip_video() -> Mp4Video {
+        Mp4Video {
+            codec: VideoCodec::H264,
+            resolution: Some((1920, 1080)),
+            samples: vec![
+                sample(&[SPS, PPS, IDR].concat(), 3000, true),
+                sample(P, 3000, false),
+                sample(P, 1500, false),
+            ],
+        }
+    }
+
+    // Boxes directly inside `data`, as (type, body).
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut found = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert!(size >= 8 && size <= data.len(), "bad box size {size}");
+            found.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        assert!(data.is_empty(), "trailing bytes after last box");
+        found
+    }
+
+    fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        boxes(data)
+            .into_iter()
+            .find(|(found, _)| found == kind)
+            .map(|(_, body)| body)
+            .unwrap_or_else(|| panic!("no {} box", String::from_utf8_lossy(kind)))
+    }
+
+    fn path<'a>(data: &'a [u8], kinds: &[&[u8; 4]]) -> &'a [u8] {
+        kinds.iter().fold(data, |data, kind| child(data, kind))
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn write_clip_lays_out_ftyp_mdat_moov() {
+        let video = clip_video();
+        let audio = [
+            sample(&[0xFC, 1], 960, true),
+            sample(&[0xFC, 2, 3], 960, true),
+        ];
+        let clip = write_clip(&video, &audio).unwrap();
+
+        let top: Vec<[u8; 4]> = boxes(&clip).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(top, [*b"ftyp", *b"mdat", *b"moov"]);
+
+        let mdat = child(&clip, b"mdat");
+        let expected: Vec<u8> = video
+            .samples
+            .iter()
+            .map(|sample| codec_config::length_prefixed(VideoCodec::H264, sample.data.clone()))
+            .flat_map(|data| data.to_vec())
+            .chain([0xFC, 1, 0xFC, 2, 3])
+            .collect();
+        assert_eq!(mdat, expected);
+
+        let moov = child(&clip, b"moov");
+        let traks: Vec<&[u8]> = boxes(moov)
+            .into_iter()
+            .filter(|(kind, _)| kind == b"trak")
+            .map(|(_, body)| body)
+            .collect();
+        assert_eq!(traks.len(), 2);
+        // next_track_ID follows the two tracks.
+        assert_eq!(u32_at(child(moov, b"mvhd"), 96), 3);
+        // Movie duration is the longer track, 7500/90000 s of video.
+        assert_eq!(u32_at(child(moov, b"mvhd"), 16), 83);
+    }
+
+    #[test]
+    fn write_clip_describes_video_samples() {
+        let video = clip_video();
+        let clip = write_clip(&video, &[]).unwrap();
+        let moov = child(&clip, b"moov");
+        let trak = child(moov, b"trak");
+        assert_eq!(boxes(moov).len(), 2);
+
+        // Dimensions come from the SPS, not the configured resolution.
+        let tkhd = child(trak, b"tkhd");
+        assert_eq!(u32_at(tkhd, 76), 160 << 16);
+        assert_eq!(u32_at(tkhd, 80), 90 << 16);
+
+        let stbl = path(trak, &[b"mdia", b"minf", b"stbl"]);
+        let stsd = child(stbl, b"stsd");
+        let entries = boxes(&stsd[8..]);
+        assert_eq!(entries[0].0, *b"avc1");
+        let avc1 = entries[0].1;
+        assert_eq!(u16::from_be_bytes([avc1[24], avc1[25]]), 160);
+        assert_eq!(u16::from_be_bytes([avc1[26], avc1[27]]), 90);
+        let avcc = child(&avc1[78..], b"avcC");
+        assert_eq!(
+            avcc,
+            codec_config::decoder_configuration(VideoCodec::H264, &video.samples[0].data)
+                .unwrap()
+                .unwrap()
+        );
+
+        // Two runs of durations, one sync sample, one chunk at the mdat.
+        let stts = child(stbl, b"stts");
+        assert_eq!(
+            stts[4..],
+            [0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0x0B, 0xB8, 0, 0, 0, 1, 0, 0, 0x05, 0xDC]
+        );
+        assert_eq!(child(stbl, b"stss")[4..], [0, 0, 0, 1, 0, 0, 0, 1]);
+        let stsz = child(stbl, b"stsz");
+        assert_eq!(u32_at(stsz, 8), 3);
+        let sizes: Vec<u32> = (0..3).map(|index| u32_at(stsz, 12 + 4 * index)).collect();
+        // SPS, PPS and IDR, then one P NAL, each behind a 4-byte length.
+        assert_eq!(sizes, [4 + 20 + 4 + 4 + 4 + 4, 4 + 3, 4 + 3]);
+        let stco = child(stbl, b"stco");
+        let mdat_payload = 8 + child(&clip, b"ftyp").len() + 8;
+        assert_eq!(u32_at(stco, 8) as usize, mdat_payload);
+    }
+
+    #[test]
+    fn write_clip_requires_a_leading_keyframe() {
+        let mut video = clip_video();
+        video.samples.remove(0);
+        assert!(write_clip(&video, &[]).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, watch, Mutex};
+use tracing::{error, info, warn};
+
+use crate::{
+    abr::AbrController,
+    capture::{CaptureSource, CaptureStream, EncoderControl},
+    config::{AbrConfig, SupervisorConfig},
+    encoder::{EncoderProfile, VideoCodec},
+    h264::{AccessUnit, AccessUnitAssembler},
+    ivf::IvfDemuxer,
+};
+
+// Access units a slow viewer may fall behind before it has to resync on the
+// next keyframe.
+const PIPELINE_BUFFER: usize = 64;
+// Lower bound between keyframe requests passed on to an encoder, so a lossy
+// viewer's PLI/FIR stream cannot turn it into an all-intra one.
+const KEYFRAME_MIN_INTERVAL: Duration = Duration::from_secs(1);
+// A capture that ran this long before failing starts a fresh retry budget.
+pub const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+
+// One capture/encode process per source. Its access units fan out to every
+// viewer watching that source.
+pub struct CapturePipeline {
+    capture: Arc<dyn CaptureSource>,
+    profile: StdMutex<EncoderProfile>,
+    abr: Option<StdMutex<AbrController>>,
+    // Bitrate the ABR controller wants, once it moved off the encoder's.
+    abr_target: watch::Sender<Option<u32>>,
+    last_keyframe_request: StdMutex<Option<Instant>>,
+    // The running encode's keyframe control; None between restarts and for
+    // encodes without it.
+    control: StdMutex<Option<EncoderControl>>,
+    parameter_sets: watch::Sender<Option<Bytes>>,
+    supervisor: SupervisorConfig,
+    failure: StdMutex<Option<String>>,
+}
+
+impl CapturePipeline {
+    pub fn source_name(&self) -> &'static str {
+        self.capture.name()
+    }
+
+    // Asks the encoder for an IDR: a local encode through its ffmpeg's
+    // command channel, a pass-through source through whoever encodes it.
+    // Returns false when the request went nowhere or was dropped by the rate
+    // limit; the stream then keyframes at its next GOP boundary.
+    pub fn request_keyframe(&self) -> bool {
+        let control = if self.capture.encodes() {
+            let Some(control) = self.control() else {
+                return false;
+            };
+            Some(control)
+        } else {
+            None
+        };
+        let now = Instant::now();
+        let mut last = self
+            .last_keyframe_request
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if last.is_some_and(|last| now.duration_since(last) < KEYFRAME_MIN_INTERVAL) {
+            return false;
+        }
+        *last = Some(now);
+        match control {
+            Some(control) => control.force_keyframe(),
+            None => self.capture.request_keyframe(),
+        }
+    }
+
+    fn control(&self) -> Option<EncoderControl> {
+        self.control
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn set_control(&self, control: Option<EncoderControl>) {
+        *self
+            .control
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = control;
+    }
+
+    pub fn on_remb(&self, viewer: u64, bitrate_bps: f32) {
+        self.adjust_bitrate(|abr| abr.on_remb(viewer, bitrate_bps));
+    }
+
+    pub fn on_loss(&self, viewer: u64, fraction_lost: u8) {
+        self.adjust_bitrate(|abr| abr.on_loss(viewer, fraction_lost));
+    }
+
+    // Changes of the ABR target; viewers move to the pipeline encoding at it.
+    pub fn abr_target(&self) -> watch::Receiver<Option<u32>> {
+        self.abr_target.subscribe()
+    }
+
+    // Feeds viewer feedback to the ABR controller. The ffmpeg CLI cannot
+    // change a running encoder's bitrate, so a moved target is published
+    // instead and the viewers switch to a pipeline keyed at it, which keeps
+    // this one streaming until they have.
+    fn adjust_bitrate(&self, feedback: impl FnOnce(&mut AbrController)) {
+        let Some(abr) = &self.abr else {
+            return;
+        };
+        let mut abr = abr.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        feedback(&mut abr);
+        let Some(bitrate_kbps) = abr.poll(Instant::now()) else {
+            return;
+        };
+        info!(
+            "abr_target_changed source={} from_kbps={} to_kbps={bitrate_kbps}",
+            self.capture.name(),
+            self.profile().bitrate_kbps
+        );
+        self.abr_target.send_replace(Some(bitrate_kbps));
+    }
+
+    // Latest SPS/PPS of the running encoder, for priming new subscribers.
+    pub fn parameter_sets(&self) -> Option<Bytes> {
+        self.parameter_sets.borrow().clone()
+    }
+
+    // Waits for the encoder's first SPS/PPS, e.g. to read its real level.
+    pub async fn wait_parameter_sets(&self, timeout: Duration) -> Option<Bytes> {
+        let mut parameter_sets = self.parameter_sets.subscribe();
+        let ready = async {
+            let ready = parameter_sets.wait_for(Option::is_some).await.ok()?;
+            ready.clone()
+        };
+        tokio::time::timeout(timeout, ready).await.ok().flatten()
+    }
+
+    // Why the pipeline stopped, once it gave up restarting ffmpeg.
+    pub fn failure(&self) -> Option<String> {
+        self.failure
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn profile(&self) -> EncoderProfile {
+        self.profile
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+struct RunningPipeline {
+    pipeline: Arc<CapturePipeline>,
+    access_units: broadcast::Sender<AccessUnit>,
+}
+
+// Running pipelines keyed by source and encoder settings, since viewers that
+// negotiated a different codec or asked for a different resolution, framerate
+// or bitrate cannot share one encoder. A pipeline starts with its first viewer
+// and stops once the last one unsubscribes.
+#[derive(Default)]
+pub struct PipelineRegistry {
+    running: Mutex<HashMap<String, RunningPipeline>>,
+}
+
+impl PipelineRegistry {
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        capture: Arc<dyn CaptureSource>,
+        mut profile: EncoderProfile,
+        abr: &AbrConfig,
+        supervisor: &SupervisorConfig,
+    ) -> Result<(Arc<CapturePipeline>, broadcast::Receiver<AccessUnit>), String> {
+        let key = pipeline_key(capture.as_ref(), &profile);
+        let mut running = self.running.lock().await;
+        if let Some(existing) = running.get(&key) {
+            let receiver = existing.access_units.subscribe();
+            info!(
+                "ffmpeg_pipeline joined source={key} viewers={}",
+                existing.access_units.receiver_count()
+            );
+            return Ok((existing.pipeline.clone(), receiver));
+        }
+
+        let abr = (abr.enabled && capture.encodes()).then(|| {
+            let controller = AbrController::new(abr.clone(), profile.bitrate_kbps);
+            profile.bitrate_kbps = controller.target_kbps();
+            StdMutex::new(controller)
+        });
+        let capture_stream = capture.start(&profile)?;
+        let encoder = profile
+            .encoder
+            .codec_name(profile.codec)
+            .unwrap_or_default();
+        let pipeline = Arc::new(CapturePipeline {
+            capture,
+            profile: StdMutex::new(profile),
+            abr,
+            abr_target: watch::channel(None).0,
+            last_keyframe_request: StdMutex::new(None),
+            control: StdMutex::new(None),
+            parameter_sets: watch::channel(None).0,
+            supervisor: supervisor.clone(),
+            failure: StdMutex::new(None),
+        });
+        let (access_units, receiver) = broadcast::channel(PIPELINE_BUFFER);
+        running.insert(
+            key.clone(),
+            RunningPipeline {
+                pipeline: pipeline.clone(),
+                access_units: access_units.clone(),
+            },
+        );
+
+        let registry = self.clone();
+        let spawned = pipeline.clone();
+        let spawned_key = key.clone();
+        tokio::spawn(async move {
+            if let Err(err) = registry
+                .supervise(&spawned_key, &spawned, capture_stream, access_units)
+                .await
+            {
+                error!("ffmpeg_pipeline failed source={spawned_key} error={err}");
+                *spawned
+                    .failure
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(err);
+            }
+            registry.remove(&spawned_key, &spawned).await;
+            info!("ffmpeg_pipeline closed source={spawned_key}");
+        });
+
+        info!("ffmpeg_spawned source={key} encoder={encoder}");
+        Ok((pipeline, receiver))
+    }
+
+    async fn remove(&self, key: &str, pipeline: &Arc<CapturePipeline>) {
+        let mut running = self.running.lock().await;
+        if running
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(&current.pipeline, pipeline))
+        {
+            running.remove(key);
+        }
+    }
+
+    // Returns false once the last viewer is gone. The check runs under the
+    // registry lock so a viewer subscribing concurrently keeps the pipeline.
+    async fn publish(
+        &self,
+        key: &str,
+        pipeline: &Arc<CapturePipeline>,
+        access_units: &broadcast::Sender<AccessUnit>,
+        access_unit: AccessUnit,
+    ) -> bool {
+        access_units.send(access_unit).is_ok()
+            || !self.release_if_idle(key, pipeline, access_units).await
+    }
+
+    // Unregisters the pipeline when nobody watches it any more.
+    async fn release_if_idle(
+        &self,
+        key: &str,
+        pipeline: &Arc<CapturePipeline>,
+        access_units: &broadcast::Sender<AccessUnit>,
+    ) -> bool {
+        let mut running = self.running.lock().await;
+        if access_units.receiver_count() > 0 {
+            return false;
+        }
+        if running
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(&current.pipeline, pipeline))
+        {
+            running.remove(key);
+        }
+        true
+    }
+
+    // Keeps the capture running for as long as anyone watches. A crashed or
+    // stalled ffmpeg is restarted with exponential backoff behind the same
+    // broadcast channel, so viewers' tracks stay up and pick the stream back
+    // up on the new encoder's first IDR. Fails once the retry budget is
+    // spent.
+    async fn supervise(
+        &self,
+        key: &str,
+        pipeline: &Arc<CapturePipeline>,
+        mut capture_stream: CaptureStream,
+        access_units: broadcast::Sender<AccessUnit>,
+    ) -> Result<(), String> {
+        let supervisor = &pipeline.supervisor;
+        let mut restarts = 0_u32;
+        loop {
+            let started_at = Instant::now();
+            let mut reason = match self
+                .pump(key, pipeline, capture_stream, &access_units)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(reason) => reason,
+            };
+            if started_at.elapsed() >= RESTART_RESET_AFTER {
+                restarts = 0;
+            }
+            capture_stream = loop {
+                if restarts >= supervisor.restart_attempts {
+                    return Err(format!("gave up after {restarts} restarts: {reason}"));
+                }
+                let delay = supervisor.backoff(restarts);
+                restarts += 1;
+                warn!(
+                    "ffmpeg_restart source={key} attempt={restarts} delay_ms={} reason={reason}",
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                if self.release_if_idle(key, pipeline, &access_units).await {
+                    info!("ffmpeg_pipeline idle source={key}");
+                    return Ok(());
+                }
+                match pipeline.capture.start(&pipeline.profile()) {
+                    Ok(next) => break next,
+                    Err(err) => reason = err,
+                }
+            };
+            pipeline.parameter_sets.send_replace(None);
+        }
+    }
+
+    // Reads one capture process until it ends or nobody watches; errors mean
+    // it crashed, stalled or produced garbage.
+    async fn pump(
+        &self,
+        key: &str,
+        pipeline: &Arc<CapturePipeline>,
+        mut capture_stream: CaptureStream,
+        access_units: &broadcast::Sender<AccessUnit>,
+    ) -> Result<(), String> {
+        pipeline.set_control(capture_stream.control());
+        let result = self
+            .pump_stream(key, pipeline, &mut capture_stream, access_units)
+            .await;
+        pipeline.set_control(None);
+        capture_stream.stop().await;
+        result
+    }
+
+    async fn pump_stream(
+        &self,
+        key: &str,
+        pipeline: &Arc<CapturePipeline>,
+        capture_stream: &mut CaptureStream,
+        access_units: &broadcast::Sender<AccessUnit>,
+    ) -> Result<(), String> {
+        let mut buf = [0_u8; 8192];
+        let mut assembler = FrameParser::new(pipeline.profile().codec);
+        let mut saw_first = false;
+        let stall_timeout = pipeline.supervisor.stall_timeout;
+        let stall = tokio::time::sleep(stall_timeout);
+        tokio::pin!(stall);
+
+        loop {
+            let completed = tokio::select! {
+                _ = &mut stall => {
+                    return Err(format!(
+                        "no access unit for {}s",
+                        stall_timeout.as_secs_f32()
+                    ));
+                }
+                read = capture_stream.read(&mut buf) => {
+                    let read = read.map_err(|err| format!("ffmpeg stdout read failed: {err}"))?;
+                    if read == 0 {
+                        if let Some(access_unit) = assembler.finish(Instant::now()) {
+                            self.publish(key, pipeline, access_units, access_unit).await;
+                        }
+                        return capture_stream.wait_exit().await;
+                    }
+                    assembler.push(&buf[..read], Instant::now())?
+                }
+            };
+
+            for access_unit in completed {
+                stall
+                    .as_mut()
+                    .reset((Instant::now() + stall_timeout).into());
+                if !saw_first {
+                    saw_first = true;
+                    info!("first_frame_ingested source={key}");
+                }
+                if access_unit.keyframe {
+                    pipeline
+                        .parameter_sets
+                        .send_replace(assembler.parameter_sets());
+                }
+                if !self.publish(key, pipeline, access_units, access_unit).await {
+                    info!("ffmpeg_pipeline idle source={key}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Encoding sources get one pipeline per output format. A pass-through source
+// has exactly one stream to give, so all its viewers share one pipeline
+// whatever they asked for.
+fn pipeline_key(capture: &dyn CaptureSource, profile: &EncoderProfile) -> String {
+    let source = capture.key();
+    if !capture.encodes() {
+        return source;
+    }
+    let format = match profile.codec {
+        VideoCodec::H264 => format!("h264-{}", profile.h264_profile.name()),
+        codec => codec.name().to_owned(),
+    };
+    let size = profile.resolution.map_or_else(
+        || "native".to_owned(),
+        |(width, height)| format!("{width}x{height}"),
+    );
+    format!(
+        "{source}:{format}:{size}@{}:{}k",
+        profile.framerate, profile.bitrate_kbps
+    )
+}
+
+// Splits a capture's output into frames according to its container.
+enum FrameParser {
+    AnnexB(AccessUnitAssembler),
+    Ivf(IvfDemuxer),
+}
+
+impl FrameParser {
+    fn new(codec: VideoCodec) -> Self {
+        match codec {
+            VideoCodec::H264 => Self::AnnexB(AccessUnitAssembler::default()),
+            VideoCodec::H265 => Self::AnnexB(AccessUnitAssembler::hevc()),
+            VideoCodec::Vp8 | VideoCodec::Vp9 | VideoCodec::Av1 => Self::Ivf(IvfDemuxer::default()),
+        }
+    }
+
+    fn push(&mut self, data: &[u8], arrived_at: Instant) -> Result<Vec<AccessUnit>, String> {
+        match self {
+            Self::AnnexB(assembler) => Ok(assembler.push(data, arrived_at)),
+            Self::Ivf(demuxer) => demuxer.push(data, arrived_at),
+        }
+    }
+
+    // IVF frames are complete on arrival, so only Annex-B holds one back.
+    fn finish(&mut self, ended_at: Instant) -> Option<AccessUnit> {
+        match self {
+            Self::AnnexB(assembler) => assembler.finish(ended_at),
+            Self::Ivf(_) => None,
+        }
+    }
+
+    fn parameter_sets(&self) -> Option<Bytes> {
+        match self {
+            Self::AnnexB(assembler) => assembler.parameter_sets(),
+            Self::Ivf(_) => None,
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    future::Future,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    codec_config,
+    config::RecordingConfig,
+    encoder::{EncoderProfile, VideoCodec},
+    mkv::{self, VideoTrackInfo},
+};
+
+// Frames a slow disk may fall behind before the recorder starts dropping.
+const RECORDER_BUFFER: usize = 256;
+// Cluster-relative block timestamps are 16-bit milliseconds.
+const MAX_CLUSTER_SPAN_MS: u64 = 30_000;
+
+// A session's recording: a writer task fed with copies of the frames sent to
+// the viewer. Dropping it closes the current file.
+pub struct Recorder {
+    frames: mpsc::Sender<RecorderInput>,
+    path_prefix: String,
+}
+
+enum RecorderInput {
+    Frame(RecordedFrame),
+    // The viewer moved to another pipeline: the current file's header no
+    // longer describes the stream, so the next keyframe opens a new one.
+    Rotate(Option<(u32, u32)>),
+}
+
+struct RecordedFrame {
+    track: u8,
+    data: Bytes,
+    keyframe: bool,
+    at: Instant,
+}
+
+impl Recorder {
+    pub fn start(
+        config: &RecordingConfig,
+        stream_id: &str,
+        profile: &EncoderProfile,
+        audio: bool,
+    ) -> Result<Self, String> {
+        std::fs::create_dir_all(&config.dir).map_err(|err| {
+            format!(
+                "recording dir create failed path={}: {err}",
+                config.dir.display()
+            )
+        })?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let stream: String = stream_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path_prefix = config
+            .dir
+            .join(format!("{stream}-{started}"))
+            .display()
+            .to_string();
+
+        let (frames, receiver) = mpsc::channel(RECORDER_BUFFER);
+        let writer = RecordingWriter {
+            config: config.clone(),
+            path_prefix: path_prefix.clone(),
+            codec: profile.codec,
+            resolution: profile.resolution,
+            audio,
+            file: None,
+            index: 0,
+        };
+        tokio::spawn(writer.run(receiver));
+        info!("recording_started stream={stream_id} path_prefix={path_prefix}");
+        Ok(Self {
+            frames,
+            path_prefix,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.frames.is_closed()
+    }
+
+    pub fn path_prefix(&self) -> &str {
+        &self.path_prefix
+    }
+
+    pub fn video(&self, data: Bytes, keyframe: bool) {
+        self.push(mkv::VIDEO_TRACK, data, keyframe);
+    }
+
+    pub fn audio(&self, packet: Bytes) {
+        self.push(mkv::AUDIO_TRACK, packet, true);
+    }
+
+    // Closes the current file once the frames queued before it are written.
+    // Unlike frames a rotation is never dropped, so the returned future waits
+    // for room in the queue; it does not borrow the recorder.
+    pub fn rotate(&self, profile: &EncoderProfile) -> impl Future<Output = ()> + Send + 'static {
+        let frames = self.frames.clone();
+        let resolution = profile.resolution;
+        async move {
+            let _ = frames.send(RecorderInput::Rotate(resolution)).await;
+        }
+    }
+
+    // Stamped here rather than in the writer so queueing does not skew the
+    // timeline; audio and video share the clock the viewer's tracks use.
+    fn push(&self, track: u8, data: Bytes, keyframe: bool) {
+        let frame = RecordedFrame {
+            track,
+            data,
+            keyframe,
+            at: Instant::now(),
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) =
+            self.frames.try_send(RecorderInput::Frame(frame))
+        {
+            warn!("recording_frame_dropped reason=writer_behind");
+        }
+    }
+}
+
+struct RecordingWriter {
+    config: RecordingConfig,
+    path_prefix: String,
+    codec: VideoCodec,
+    resolution: Option<(u32, u32)>,
+    audio: bool,
+    file: Option<RecordingFile>,
+    index: u32,
+}
+
+// The Matroska file being written. Its timeline starts at the keyframe that
+// opened it.
+struct RecordingFile {
+    path: String,
+    writer: BufWriter<File>,
+    started_at: Instant,
+    bytes: u64,
+    cluster_ms: Option<u64>,
+}
+
+impl RecordingWriter {
+    async fn run(mut self, mut inputs: mpsc::Receiver<RecorderInput>) {
+        while let Some(input) = inputs.recv().await {
+            let written = match input {
+                RecorderInput::Frame(frame) => self.write(frame).await,
+                RecorderInput::Rotate(resolution) => {
+                    self.resolution = resolution;
+                    self.close().await
+                }
+            };
+            if let Err(err) = written {
+                warn!(
+                    "recording_failed path_prefix={} error={err}",
+                    self.path_prefix
+                );
+                break;
+            }
+        }
+        if let Err(err) = self.close().await {
+            warn!("recording_close_failed error={err}");
+        }
+    }
+
+    async fn write(&mut self, frame: RecordedFrame) -> Result<(), String> {
+        let is_video = frame.track == mkv::VIDEO_TRACK;
+        if is_video && frame.keyframe && self.should_rotate(frame.at) {
+            self.close().await?;
+            self.open(&frame).await?;
+        }
+        let Some(file) = &mut self.file else {
+            // Nothing is written until video reaches a keyframe.
+            return Ok(());
+        };
+
+        let at_ms = frame
+            .at
+            .saturating_duration_since(file.started_at)
+            .as_millis() as u64;
+        // Every video keyframe starts a cluster so players can seek to it.
+        let cluster_ms = match file.cluster_ms {
+            Some(cluster_ms)
+                if !(is_video && frame.keyframe)
+                    && at_ms.saturating_sub(cluster_ms) < MAX_CLUSTER_SPAN_MS =>
+            {
+                cluster_ms
+            }
+            _ => {
+                file.write(&mkv::cluster(at_ms)).await?;
+                file.cluster_ms = Some(at_ms);
+                at_ms
+            }
+        };
+        let relative_ms = at_ms.saturating_sub(cluster_ms) as i16;
+        let data = if is_video {
+            codec_config::length_prefixed(self.codec, frame.data)
+        } else {
+            frame.data
+        };
+        file.write(&mkv::simple_block(
+            frame.track,
+            relative_ms,
+            frame.keyframe,
+            &data,
+        ))
+        .await
+    }
+
+    // Files are cut on keyframes so each one starts decodable.
+    fn should_rotate(&self, now: Instant) -> bool {
+        let Some(file) = &self.file else {
+            return true;
+        };
+        file.bytes >= self.config.max_bytes
+            || now.duration_since(file.started_at) >= self.config.max_duration
+    }
+
+    async fn open(&mut self, keyframe: &RecordedFrame) -> Result<(), String> {
+        let codec_private = codec_config::decoder_configuration(self.codec, &keyframe.data)?;
+        self.index += 1;
+        let path = format!("{}-{:03}.mkv", self.path_prefix, self.index);
+        let file = File::create(&path)
+            .await
+            .map_err(|err| format!("recording create failed path={path}: {err}"))?;
+        let mut file = RecordingFile {
+            path,
+            writer: BufWriter::new(file),
+            started_at: keyframe.at,
+            bytes: 0,
+            cluster_ms: None,
+        };
+        let video = VideoTrackInfo {
+            codec: self.codec,
+            resolution: codec_config::frame_size(self.codec, &keyframe.data).or(self.resolution),
+            codec_private,
+        };
+        file.write(&mkv::header(&video, self.audio)).await?;
+        info!("recording_file_opened path={}", file.path);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), String> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        file.writer
+            .shutdown()
+            .await
+            .map_err(|err| format!("recording flush failed path={}: {err}", file.path))?;
+        info!(
+            "recording_file_closed path={} bytes={} secs={}",
+            file.path,
+            file.bytes,
+            file.started_at.elapsed().as_secs()
+        );
+        Ok(())
+    }
+}
+
+impl RecordingFile {
+    async fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.writer
+            .write_all(data)
+            .await
+            .map_err(|err| format!("recording write failed path={}: {err}", self.path))?;
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tracing::info;
+
+use crate::{
+    config::ReplayConfig,
+    encoder::EncoderProfile,
+    mp4::{self, Mp4Sample, Mp4Video},
+};
+
+// Used for the last frame of each track, which has no successor to time it.
+const FALLBACK_VIDEO_FRAME: Duration = Duration::from_millis(33);
+const OPUS_FRAME: Duration = Duration::from_millis(20);
+
+// The last `window` of a session's stream, kept as the encoded frames the
+// viewer was sent. The front is always a video keyframe (or empty), so the
+// buffer can be saved at any moment without waiting for the next one.
+pub struct ReplayBuffer {
+    window: Duration,
+    frames: VecDeque<ReplayFrame>,
+}
+
+#[derive(Clone)]
+struct ReplayFrame {
+    video: bool,
+    data: Bytes,
+    keyframe: bool,
+    at: Instant,
+}
+
+impl ReplayBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn video(&mut self, data: Bytes, keyframe: bool) {
+        let at = Instant::now();
+        if keyframe {
+            self.trim(at);
+        } else if self.frames.is_empty() {
+            // Joined mid-GOP; wait for a keyframe to start from.
+            return;
+        }
+        self.frames.push_back(ReplayFrame {
+            video: true,
+            data,
+            keyframe,
+            at,
+        });
+    }
+
+    pub fn audio(&mut self, packet: Bytes) {
+        if self.frames.is_empty() {
+            return;
+        }
+        self.frames.push_back(ReplayFrame {
+            video: false,
+            data: packet,
+            keyframe: true,
+            at: Instant::now(),
+        });
+    }
+
+    // A resolution or codec change makes older frames undecodable with the
+    // new stream's configuration, so the replay starts over.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Drops whole GOPs from the front while the next keyframe is still old
+    // enough to cover the window on its own.
+    fn trim(&mut self, now: Instant) {
+        let Some(cutoff) = now.checked_sub(self.window) else {
+            return;
+        };
+        let start = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.video && frame.keyframe && frame.at <= cutoff);
+        if let Some(start) = start {
+            self.frames.drain(..start);
+        }
+    }
+
+    // A copy of the buffer to save, so the forward loop keeps filling the
+    // buffer while the clip is written.
+    pub fn snapshot(&self) -> ReplaySnapshot {
+        ReplaySnapshot {
+            frames: self.frames.iter().cloned().collect(),
+        }
+    }
+}
+
+pub struct ReplaySnapshot {
+    frames: Vec<ReplayFrame>,
+}
+
+impl ReplaySnapshot {
+    pub fn is_empty(&self) -> bool {
+        !self.frames.iter().any(|frame| frame.video)
+    }
+
+    // Writes the clip next to the other replays and returns its path.
+    pub async fn save(
+        self,
+        config: &ReplayConfig,
+        stream_id: &str,
+        profile: &EncoderProfile,
+    ) -> Result<String, String> {
+        let (video, audio): (Vec<_>, Vec<_>) =
+            self.frames.into_iter().partition(|frame| frame.video);
+        let secs = match (video.first(), video.last()) {
+            (Some(first), Some(last)) => last.at.duration_since(first.at).as_secs(),
+            _ => return Err("replay buffer is empty".to_owned()),
+        };
+        let video = Mp4Video {
+            codec: profile.codec,
+            resolution: profile.resolution,
+            samples: samples(video, mp4::VIDEO_TIMESCALE, FALLBACK_VIDEO_FRAME),
+        };
+        let audio = samples(audio, mp4::AUDIO_TIMESCALE, OPUS_FRAME);
+        let clip = tokio::task::spawn_blocking(move || mp4::write_clip(&video, &audio))
+            .await
+            .map_err(|err| format!("replay encode task failed: {err}"))??;
+
+        tokio::fs::create_dir_all(&config.dir)
+            .await
+            .map_err(|err| {
+                format!(
+                    "replay dir create failed path={}: {err}",
+                    config.dir.display()
+                )
+            })?;
+        let saved = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let stream: String = stream_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = config
+            .dir
+            .join(format!("{stream}-replay-{saved}.mp4"))
+            .display()
+            .to_string();
+        tokio::fs::write(&path, &clip)
+            .await
+            .map_err(|err| format!("replay write failed path={path}: {err}"))?;
+        info!(
+            "replay_saved stream={stream_id} path={path} secs={secs} bytes={}",
+            clip.len()
+        );
+        Ok(path)
+    }
+}
+
+// Each frame lasts until the next one on its track arrived.
+fn samples(frames: Vec<ReplayFrame>, timescale: u32, last: Duration) -> Vec<Mp4Sample> {
+    let ticks = |duration: Duration| {
+        ((duration.as_secs_f64() * f64::from(timescale)).round() as u32).max(1)
+    };
+    let next_at: Vec<Option<Instant>> = frames
+        .iter()
+        .skip(1)
+        .map(|frame| Some(frame.at))
+        .chain([None])
+        .collect();
+    frames
+        .into_iter()
+        .zip(next_at)
+        .map(|(frame, next_at)| Mp4Sample {
+            duration: ticks(next_at.map_or(last, |next_at| next_at.duration_since(frame.at))),
+            data: frame.data,
+            keyframe: frame.keyframe,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(window: Duration, start: Instant, frames: &[(u64, bool, bool)]) -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(window);
+        for &(ms, video, keyframe) in frames {
+            buffer.frames.push_back(ReplayFrame {
+                video,
+                data: Bytes::new(),
+                keyframe,
+                at: start + Duration::from_millis(ms),
+            });
+        }
+        buffer
+    }
+
+    fn times(buffer: &ReplayBuffer, start: Instant) -> Vec<u64> {
+        buffer
+            .frames
+            .iter()
+            .map(|frame| frame.at.duration_since(start).as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn trim_keeps_the_last_keyframe_covering_the_window() {
+        let start = Instant::now();
+        let mut replay = buffer(
+            Duration::from_millis(2000),
+            start,
+            &[
+                (0, true, true),
+                (500, true, false),
+                (1000, true, true),
+                (1010, false, true),
+                (1500, true, false),
+                (2000, true, true),
+                (2500, true, false),
+            ],
+        );
+        replay.trim(start + Duration::from_millis(3200));
+        assert_eq!(times(&replay, start), [1000, 1010, 1500, 2000, 2500]);
+        assert!(replay.frames[0].video && replay.frames[0].keyframe);
+    }
+
+    #[test]
+    fn trim_keeps_everything_until_a_later_keyframe_covers_the_window() {
+        let start = Instant::now();
+        let mut replay = buffer(
+            Duration::from_millis(2000),
+            start,
+            &[(0, true, true), (1500, true, true), (2500, true, false)],
+        );
+        replay.trim(start + Duration::from_millis(3000));
+        assert_eq!(times(&replay, start), [0, 1500, 2500]);
+    }
+
+    #[test]
+    fn trim_ignores_audio_frames_as_cut_points() {
+        let start = Instant::now();
+        let mut replay = buffer(
+            Duration::from_millis(1000),
+            start,
+            &[(0, true, true), (900, false, true), (1500, true, false)],
+        );
+        replay.trim(start + Duration::from_millis(2000));
+        assert_eq!(times(&replay, start), [0, 900, 1500]);
+    }
+
+    #[test]
+    fn waits_for_a_keyframe_before_buffering() {
+        let mut replay = ReplayBuffer::new(Duration::from_secs(10));
+        replay.audio(Bytes::from_static(b"opus"));
+        replay.video(Bytes::from_static(b"p"), false);
+        assert!(replay.snapshot().is_empty());
+
+        replay.video(Bytes::from_static(b"idr"), true);
+        replay.audio(Bytes::from_static(b"opus"));
+        let snapshot = replay.snapshot();
+        assert!(!snapshot.is_empty());
+        assert_eq!(snapshot.frames.len(), 2);
+    }
+
+    #[test]
+    fn samples_last_until_the_next_frame() {
+        let start = Instant::now();
+        let replay = buffer(
+            Duration::from_secs(10),
+            start,
+            &[(0, true, true), (40, true, false), (60, true, false)],
+        );
+        let frames: Vec<_> = replay.frames.into_iter().collect();
+        let durations: Vec<u32> = samples(frames, mp4::VIDEO_TIMESCALE, FALLBACK_VIDEO_FRAME)
+            .iter()
+            .map(|sample| sample.duration)
+            .collect();
+        assert_eq!(durations, [3600, 1800, 2970]);
+    }
+}
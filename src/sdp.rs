@@ -0,0 +1,331 @@
+use crate::encoder::{H264Profile, VideoCodec};
+
+// RFC 6184 defaults for an H264 payload without the parameter.
+const DEFAULT_PROFILE_LEVEL_ID: [u8; 3] = [0x42, 0x00, 0x10];
+const DEFAULT_PACKETIZATION_MODE: u8 = 0;
+
+// One H264 payload type of a remote offer.
+#[derive(Clone, Copy, Debug)]
+pub struct H264Format {
+    pub payload_type: u8,
+    pub profile_level_id: [u8; 3],
+    pub packetization_mode: u8,
+}
+
+impl H264Format {
+    pub fn profile(&self) -> Option<H264Profile> {
+        H264Profile::from_profile_level_id(self.profile_level_id)
+    }
+}
+
+// Video codecs the offer carries, each once, in offer order.
+pub fn offered_video_codecs(sdp: &str) -> Vec<VideoCodec> {
+    let mut codecs = Vec::new();
+    for line in sdp.lines() {
+        let Some((_, encoding)) = attribute(line, "a=rtpmap:") else {
+            continue;
+        };
+        let codec = encoding.split('/').next().and_then(VideoCodec::from_name);
+        if let Some(codec) = codec.filter(|codec| !codecs.contains(codec)) {
+            codecs.push(codec);
+        }
+    }
+    codecs
+}
+
+// fmtp of our track for codecs other than H264, which is built from the offer.
+// The encoders produce 8-bit 4:2:0, i.e. the base profile of each codec.
+pub fn video_fmtp(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 | VideoCodec::Vp8 => "",
+        VideoCodec::H265 => "profile-id=1",
+        VideoCodec::Vp9 => "profile-id=0",
+        VideoCodec::Av1 => "profile=0",
+    }
+}
+
+// H264 payload types of the offer's video sections, in offer (preference)
+// order.
+pub fn offered_h264(sdp: &str) -> Vec<H264Format> {
+    let mut formats: Vec<H264Format> = sdp
+        .lines()
+        .filter_map(|line| {
+            let (payload_type, encoding) = attribute(line, "a=rtpmap:")?;
+            encoding
+                .split('/')
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("H264"))
+                .then_some(H264Format {
+                    payload_type,
+                    profile_level_id: DEFAULT_PROFILE_LEVEL_ID,
+                    packetization_mode: DEFAULT_PACKETIZATION_MODE,
+                })
+        })
+        .collect();
+
+    for line in sdp.lines() {
+        let Some((payload_type, parameters)) = attribute(line, "a=fmtp:") else {
+            continue;
+        };
+        let Some(format) = formats
+            .iter_mut()
+            .find(|format| format.payload_type == payload_type)
+        else {
+            continue;
+        };
+        if let Some(profile_level_id) = fmtp_profile_level_id(parameters) {
+            format.profile_level_id = profile_level_id;
+        }
+        if let Some(mode) =
+            fmtp_parameter(parameters, "packetization-mode").and_then(|mode| mode.parse().ok())
+        {
+            format.packetization_mode = mode;
+        }
+    }
+    formats
+}
+
+// The highest profile both sides support, up to `max_profile`. Only
+// non-interleaved mode is usable since the payloader emits FU-A/STAP-A. Ties
+// go to the client's preference.
+pub fn choose_h264(offered: &[H264Format], max_profile: H264Profile) -> Option<H264Format> {
+    offered
+        .iter()
+        .filter(|format| format.packetization_mode == 1)
+        .filter(|format| {
+            format
+                .profile()
+                .is_some_and(|profile| profile <= max_profile)
+        })
+        .rev()
+        .max_by_key(|format| format.profile())
+        .copied()
+}
+
+// Rewrites the level of the H264 fmtp lines matching our profile so the
+// answer advertises what the encoder really produces. The answerer may
+// declare a different level than the offer when level-asymmetry-allowed is
+// set, which is what lets a browser offering 3.1 receive 1080p60.
+pub fn advertise_profile_level_id(answer_sdp: &str, profile_level_id: [u8; 3]) -> String {
+    let ours = format_profile_level_id(profile_level_id);
+    let h264_payload_types: Vec<u8> = offered_h264(answer_sdp)
+        .iter()
+        .map(|format| format.payload_type)
+        .collect();
+
+    let mut rewritten = String::with_capacity(answer_sdp.len());
+    for line in answer_sdp.split_inclusive('\n') {
+        let is_h264_fmtp = attribute(line, "a=fmtp:")
+            .is_some_and(|(payload_type, _)| h264_payload_types.contains(&payload_type));
+        if !is_h264_fmtp {
+            rewritten.push_str(line);
+            continue;
+        }
+        let Some(start) = line.find("profile-level-id=").map(|start| start + 17) else {
+            rewritten.push_str(line);
+            continue;
+        };
+        let same_profile = line
+            .get(start..start + 4)
+            .is_some_and(|profile| profile.eq_ignore_ascii_case(&ours[..4]));
+        if !same_profile || line.get(start..start + 6).is_none() {
+            rewritten.push_str(line);
+            continue;
+        }
+        rewritten.push_str(&line[..start]);
+        rewritten.push_str(&ours);
+        rewritten.push_str(&line[start + 6..]);
+    }
+    rewritten
+}
+
+// Candidates a WHEP/WHIP client trickles in an
+// `application/trickle-ice-sdpfrag` body (RFC 8840).
+#[derive(Debug, Default)]
+pub struct TrickleFragment {
+    pub candidates: Vec<TrickleCandidate>,
+    pub end_of_candidates: bool,
+}
+
+#[derive(Debug)]
+pub struct TrickleCandidate {
+    // Without the `a=` prefix, as RTCIceCandidateInit wants it.
+    pub candidate: String,
+    pub mid: Option<String>,
+    pub ufrag: Option<String>,
+}
+
+// The fragment's m= lines only group candidates by mid; their order says
+// nothing about the session's m-line indexes, so only the mid is kept.
+pub fn parse_trickle_fragment(fragment: &str) -> TrickleFragment {
+    let mut parsed = TrickleFragment::default();
+    let mut mid = None;
+    let mut ufrag = None;
+    for line in fragment.lines().map(str::trim) {
+        if line.starts_with("m=") {
+            mid = None;
+        } else if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_owned());
+        } else if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag = Some(value.to_owned());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            if candidate.starts_with("candidate:") {
+                parsed.candidates.push(TrickleCandidate {
+                    candidate: candidate.to_owned(),
+                    mid: mid.clone(),
+                    ufrag: ufrag.clone(),
+                });
+            } else if candidate == "end-of-candidates" {
+                parsed.end_of_candidates = true;
+            }
+        }
+    }
+    parsed
+}
+
+pub fn format_profile_level_id(profile_level_id: [u8; 3]) -> String {
+    format!(
+        "{:02x}{:02x}{:02x}",
+        profile_level_id[0], profile_level_id[1], profile_level_id[2]
+    )
+}
+
+// Splits `a=<name>:<payload type> <value>`.
+fn attribute<'a>(line: &'a str, prefix: &str) -> Option<(u8, &'a str)> {
+    let rest = line.trim().strip_prefix(prefix)?;
+    let (payload_type, value) = rest.split_once(' ')?;
+    Some((payload_type.parse().ok()?, value.trim()))
+}
+
+fn fmtp_parameter<'a>(parameters: &'a str, name: &str) -> Option<&'a str> {
+    parameters.split(';').find_map(|parameter| {
+        let (key, value) = parameter.split_once('=')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+pub fn fmtp_profile_level_id(parameters: &str) -> Option<[u8; 3]> {
+    let value = fmtp_parameter(parameters, "profile-level-id")?;
+    if value.len() != 6 {
+        return None;
+    }
+    let mut profile_level_id = [0_u8; 3];
+    for (index, byte) in profile_level_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(profile_level_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 102 104 106 108 45\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtpmap:102 H264/90000\r\n\
+        a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n\
+        a=rtpmap:104 H264/90000\r\n\
+        a=fmtp:104 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42001f\r\n\
+        a=rtpmap:106 H264/90000\r\n\
+        a=fmtp:106 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n\
+        a=rtpmap:108 h264/90000\r\n\
+        a=fmtp:108 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=4d001f\r\n\
+        a=rtpmap:45 AV1/90000\r\n";
+
+    fn format(payload_type: u8, profile_level_id: [u8; 3], packetization_mode: u8) -> H264Format {
+        H264Format {
+            payload_type,
+            profile_level_id,
+            packetization_mode,
+        }
+    }
+
+    #[test]
+    fn offered_h264_reads_fmtp_in_offer_order() {
+        let offered = offered_h264(OFFER);
+        let summary: Vec<_> = offered
+            .iter()
+            .map(|format| {
+                (
+                    format.payload_type,
+                    format.profile_level_id,
+                    format.packetization_mode,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (102, [0x42, 0x00, 0x1f], 1),
+                (104, [0x42, 0x00, 0x1f], 0),
+                (106, [0x42, 0xe0, 0x1f], 1),
+                (108, [0x4d, 0x00, 0x1f], 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn offered_h264_defaults_without_fmtp() {
+        let offered = offered_h264("a=rtpmap:97 H264/90000\r\n");
+        assert_eq!(offered.len(), 1);
+        assert_eq!(offered[0].profile_level_id, DEFAULT_PROFILE_LEVEL_ID);
+        assert_eq!(offered[0].packetization_mode, DEFAULT_PACKETIZATION_MODE);
+    }
+
+    #[test]
+    fn choose_h264_takes_the_highest_profile_allowed() {
+        let offered = offered_h264(OFFER);
+        let chosen = choose_h264(&offered, H264Profile::High).unwrap();
+        assert_eq!(chosen.payload_type, 108);
+
+        let chosen = choose_h264(&offered, H264Profile::ConstrainedBaseline).unwrap();
+        assert_eq!(chosen.payload_type, 102);
+    }
+
+    #[test]
+    fn choose_h264_skips_single_nal_mode_and_unknown_profiles() {
+        let offered = [
+            format(100, [0x64, 0x00, 0x1f], 0),
+            format(101, [0x58, 0x00, 0x1f], 1),
+        ];
+        assert!(choose_h264(&offered, H264Profile::High).is_none());
+    }
+
+    #[test]
+    fn advertise_profile_level_id_rewrites_matching_profile_only() {
+        let answer = "a=rtpmap:102 H264/90000\r\n\
+            a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n\
+            a=rtpmap:108 H264/90000\r\n\
+            a=fmtp:108 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=4d001f\r\n\
+            a=rtpmap:96 VP8/90000\r\n\
+            a=fmtp:96 profile-level-id=42e01f\r\n";
+        let rewritten = advertise_profile_level_id(answer, [0x42, 0xe0, 0x2a]);
+
+        assert!(rewritten.contains(
+            "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e02a\r\n"
+        ));
+        assert!(rewritten.contains("profile-level-id=4d001f\r\n"));
+        assert!(rewritten.contains("a=fmtp:96 profile-level-id=42e01f\r\n"));
+        assert_eq!(rewritten.len(), answer.len());
+    }
+
+    #[test]
+    fn fmtp_profile_level_id_rejects_malformed_values() {
+        assert_eq!(
+            fmtp_profile_level_id("profile-level-id=64002A"),
+            Some([0x64, 0x00, 0x2a])
+        );
+        assert_eq!(fmtp_profile_level_id("profile-level-id=64002"), None);
+        assert_eq!(fmtp_profile_level_id("profile-level-id=zz002a"), None);
+        assert_eq!(fmtp_profile_level_id("packetization-mode=1"), None);
+    }
+
+    #[test]
+    fn offered_video_codecs_lists_each_codec_once() {
+        assert_eq!(
+            offered_video_codecs(OFFER),
+            [VideoCodec::Vp8, VideoCodec::H264, VideoCodec::Av1]
+        );
+    }
+}
@@ -2,15 +2,18 @@ use axum::{http::StatusCode, Json};
 use tracing::{info, warn};
 
 use crate::{
-    media_bridge::MediaBridge,
-    models::{ApiResponse, SessionPeerQuery, SignalMessage},
+    media_bridge::{MediaBridge, Signaling},
+    models::{ApiResponse, IceServer, JoinResponse, OfferQuery, SessionPeerQuery, SignalMessage},
     state::{AppState, SessionState},
 };
 
+// The response carries TURN credentials minted for the joining peer; with
+// TURN_CLIENT_TOKEN set only callers presenting it as a bearer token get them.
 pub async fn join_session(
     state: AppState,
     query: SessionPeerQuery,
-) -> (StatusCode, Json<ApiResponse>) {
+    bearer_token: Option<&str>,
+) -> (StatusCode, Json<JoinResponse>) {
     let mut sessions = state.sessions.write().await;
     let session = sessions.entry(query.session_id.clone()).or_default();
     session.peers.insert(query.peer_id.clone());
@@ -28,8 +31,25 @@ pub async fn join_session(
         }
     }
 
+    let ice_servers = state
+        .media_bridge
+        .ice_servers_for(&query.peer_id, bearer_token)
+        .into_iter()
+        .map(|server| IceServer {
+            urls: server.urls,
+            username: server.username,
+            credential: server.credential,
+        })
+        .collect();
+
     info!("join session={} peer={}", query.session_id, query.peer_id);
-    api_ok()
+    (
+        StatusCode::OK,
+        Json(JoinResponse {
+            ok: true,
+            ice_servers,
+        }),
+    )
 }
 
 pub async fn leave_session(
@@ -39,6 +59,10 @@ pub async fn leave_session(
     let mut sessions = state.sessions.write().await;
     if let Some(session) = sessions.get_mut(&query.session_id) {
         session.peers.remove(&query.peer_id);
+        state
+            .media_bridge
+            .close_peer(&query.session_id, &query.peer_id)
+            .await;
         session.inboxes.remove(&query.peer_id);
 
         let leave_msg = SignalMessage::Leave {
@@ -54,25 +78,33 @@ pub async fn leave_session(
     api_ok()
 }
 
-// Shared routing logic for all signaling message handlers.
-pub async fn route_signal_message(
+// Offers aimed at the bot start a stream; all others are relayed as usual.
+pub async fn route_offer(
     state: AppState,
-    query: SessionPeerQuery,
+    query: OfferQuery,
     msg: SignalMessage,
 ) -> (StatusCode, Json<ApiResponse>) {
-    if let SignalMessage::Offer { from, to, sdp } = &msg {
+    if let SignalMessage::Offer {
+        from,
+        to,
+        sdp,
+        resume_token,
+    } = &msg
+    {
         if MediaBridge::is_bot_target(to) {
             return match state
                 .media_bridge
                 .handle_offer(
-                    state.clone(),
+                    Signaling::Polling(state.clone()),
                     query.session_id.clone(),
                     from.clone(),
                     sdp.clone(),
+                    resume_token.clone(),
+                    query.stream_request(),
                 )
                 .await
             {
-                Ok(()) => api_ok(),
+                Ok(_) => api_ok(),
                 Err(err) => {
                     warn!("ffmpeg_bot offer failed session={} error={err}", query.session_id);
                     api_error(StatusCode::BAD_REQUEST)
@@ -80,7 +112,15 @@ pub async fn route_signal_message(
             };
         }
     }
+    route_signal_message(state, query.session_peer(), msg).await
+}
 
+// Shared routing logic for all signaling message handlers.
+pub async fn route_signal_message(
+    state: AppState,
+    query: SessionPeerQuery,
+    msg: SignalMessage,
+) -> (StatusCode, Json<ApiResponse>) {
     if let SignalMessage::IceCandidate {
         from,
         to,
@@ -102,6 +142,25 @@ pub async fn route_signal_message(
         }
     }
 
+    if let SignalMessage::EndOfCandidates { from, to } = &msg {
+        if MediaBridge::is_bot_target(to) {
+            return match state
+                .media_bridge
+                .handle_remote_end_of_candidates(&query.session_id, from)
+                .await
+            {
+                Ok(()) => api_ok(),
+                Err(err) => {
+                    warn!(
+                        "ffmpeg_bot end_of_candidates failed session={} error={err}",
+                        query.session_id
+                    );
+                    api_error(StatusCode::BAD_REQUEST)
+                }
+            };
+        }
+    }
+
     let mut sessions = state.sessions.write().await;
     let Some(session) = sessions.get_mut(&query.session_id) else {
         return api_error(StatusCode::NOT_FOUND);
@@ -134,6 +193,12 @@ pub fn log_signal(session_id: &str, msg: &SignalMessage) {
         SignalMessage::IceCandidate { from, to, .. } => {
             info!("ice_candidate session={session_id} from={from} to={to}")
         }
+        SignalMessage::EndOfCandidates { from, to } => {
+            info!("end_of_candidates session={session_id} from={from} to={to}")
+        }
+        SignalMessage::StreamFailed { from, to, reason } => {
+            info!("stream_failed session={session_id} from={from} to={to} reason={reason}")
+        }
         SignalMessage::Join { peer_id } => info!("join_event session={session_id} peer={peer_id}"),
         SignalMessage::Leave { peer_id } => {
             info!("leave_event session={session_id} peer={peer_id}")
@@ -146,7 +211,9 @@ pub fn target_peer(msg: &SignalMessage) -> Option<&str> {
     match msg {
         SignalMessage::Offer { to, .. }
         | SignalMessage::Answer { to, .. }
-        | SignalMessage::IceCandidate { to, .. } => Some(to.as_str()),
+        | SignalMessage::IceCandidate { to, .. }
+        | SignalMessage::EndOfCandidates { to, .. }
+        | SignalMessage::StreamFailed { to, .. } => Some(to.as_str()),
         SignalMessage::Join { .. } | SignalMessage::Leave { .. } => None,
     }
 }
@@ -5,7 +5,7 @@ use std::{
 
 use tokio::sync::RwLock;
 
-use crate::{media_bridge::MediaBridge, models::SignalMessage};
+use crate::{config::BridgeConfig, media_bridge::MediaBridge, models::SignalMessage};
 
 // Global in-memory signaling state keyed by session ID.
 #[derive(Clone, Default)]
@@ -14,6 +14,15 @@ pub struct AppState {
     pub media_bridge: Arc<MediaBridge>,
 }
 
+impl AppState {
+    pub async fn new(config: BridgeConfig) -> Result<Self, String> {
+        Ok(Self {
+            sessions: Arc::default(),
+            media_bridge: Arc::new(MediaBridge::new(config).await?),
+        })
+    }
+}
+
 // Per-session peer registry and inbox queues used by HTTP polling.
 #[derive(Default)]
 pub struct SessionState {
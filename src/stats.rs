@@ -0,0 +1,175 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use webrtc::rtcp::reception_report::ReceptionReport;
+
+use crate::control::StreamSettings;
+
+// Rates and frame sizes are averaged over this trailing window.
+const STATS_WINDOW: Duration = Duration::from_secs(2);
+const VIDEO_CLOCK_RATE: f64 = 90_000.0;
+// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+// What one viewer's stream has been sending and what its receiver reports
+// back. Updated from the forward loop and the RTCP reader.
+pub struct StreamStats {
+    started_at: Instant,
+    recent: VecDeque<SentFrame>,
+    frames_sent: u64,
+    keyframes_sent: u64,
+    bytes_sent: u64,
+    last_keyframe: Option<Instant>,
+    keyframe_interval: Option<Duration>,
+    rtt: Option<Duration>,
+    jitter_ms: Option<f64>,
+    fraction_lost: Option<f64>,
+    packets_lost: Option<u32>,
+}
+
+struct SentFrame {
+    at: Instant,
+    bytes: usize,
+}
+
+// Point-in-time view served by the admin API and the `stats` data channel.
+#[derive(Debug, Serialize)]
+pub struct StreamStatsSnapshot {
+    pub id: String,
+    pub source: &'static str,
+    pub settings: StreamSettings,
+    pub connection_state: String,
+    pub uptime_secs: u64,
+    pub fps: f64,
+    pub bitrate_kbps: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyframe_interval_ms: Option<u64>,
+    pub avg_frame_bytes: u64,
+    pub max_frame_bytes: u64,
+    pub frames_sent: u64,
+    pub keyframes_sent: u64,
+    pub bytes_sent: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loss_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packets_lost: Option<u32>,
+}
+
+impl Default for StreamStats {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            recent: VecDeque::new(),
+            frames_sent: 0,
+            keyframes_sent: 0,
+            bytes_sent: 0,
+            last_keyframe: None,
+            keyframe_interval: None,
+            rtt: None,
+            jitter_ms: None,
+            fraction_lost: None,
+            packets_lost: None,
+        }
+    }
+}
+
+impl StreamStats {
+    pub fn on_frame(&mut self, bytes: usize, keyframe: bool) {
+        let now = Instant::now();
+        self.frames_sent += 1;
+        self.bytes_sent += bytes as u64;
+        if keyframe {
+            self.keyframes_sent += 1;
+            if let Some(last) = self.last_keyframe.replace(now) {
+                self.keyframe_interval = Some(now.duration_since(last));
+            }
+        }
+        self.recent.push_back(SentFrame { at: now, bytes });
+        self.expire(now);
+    }
+
+    // Takes the video stream's report block only, so jitter arrives in 90 kHz
+    // RTP timestamp units; RTT is derived from the echoed sender report time
+    // as in RFC 3550 section 6.4.1.
+    pub fn on_reception_report(&mut self, report: &ReceptionReport) {
+        self.jitter_ms = Some(f64::from(report.jitter) * 1000.0 / VIDEO_CLOCK_RATE);
+        self.fraction_lost = Some(f64::from(report.fraction_lost) / 256.0);
+        self.packets_lost = Some(report.total_lost);
+        if report.last_sender_report != 0 {
+            let elapsed = ntp_compact_now()
+                .wrapping_sub(report.last_sender_report)
+                .wrapping_sub(report.delay);
+            // A wrapped result means clock skew, not a huge RTT.
+            if elapsed < 1 << 31 {
+                self.rtt = Some(Duration::from_secs_f64(f64::from(elapsed) / 65_536.0));
+            }
+        }
+    }
+
+    pub fn snapshot(
+        &mut self,
+        id: String,
+        source: &'static str,
+        settings: StreamSettings,
+        connection_state: String,
+    ) -> StreamStatsSnapshot {
+        self.expire(Instant::now());
+        let window_bytes: usize = self.recent.iter().map(|frame| frame.bytes).sum();
+        let window_secs = STATS_WINDOW.as_secs_f64();
+        let frames = self.recent.len();
+        StreamStatsSnapshot {
+            id,
+            source,
+            settings,
+            connection_state,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            fps: frames as f64 / window_secs,
+            bitrate_kbps: window_bytes as f64 * 8.0 / 1000.0 / window_secs,
+            keyframe_interval_ms: self
+                .keyframe_interval
+                .map(|interval| interval.as_millis() as u64),
+            avg_frame_bytes: window_bytes.checked_div(frames).unwrap_or(0) as u64,
+            max_frame_bytes: self
+                .recent
+                .iter()
+                .map(|frame| frame.bytes)
+                .max()
+                .unwrap_or(0) as u64,
+            frames_sent: self.frames_sent,
+            keyframes_sent: self.keyframes_sent,
+            bytes_sent: self.bytes_sent,
+            rtt_ms: self.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            jitter_ms: self.jitter_ms,
+            loss_percent: self.fraction_lost.map(|fraction| fraction * 100.0),
+            packets_lost: self.packets_lost,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|frame| now.duration_since(frame.at) > STATS_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+// Middle 32 bits of the current NTP timestamp, the unit RTCP uses for
+// LSR/DLSR.
+fn ntp_compact_now() -> u32 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_unix.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = (u64::from(since_unix.subsec_nanos()) << 32) / 1_000_000_000;
+    ((seconds << 16) as u32) | ((fraction >> 16) as u32)
+}
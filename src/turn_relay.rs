@@ -0,0 +1,311 @@
+use std::{
+    any::Any,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ring::hmac;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+use webrtc::{
+    stun::{
+        attributes::ATTR_XOR_PEER_ADDRESS,
+        error_code::{ErrorCodeAttribute, CODE_FORBIDDEN},
+        message::{
+            is_message, Message, MessageType, CLASS_ERROR_RESPONSE, CLASS_REQUEST, MAGIC_COOKIE,
+            METHOD_CHANNEL_BIND, METHOD_CREATE_PERMISSION,
+        },
+    },
+    turn::{
+        auth::{generate_auth_key, AuthHandler},
+        relay::{
+            relay_range::RelayAddressGeneratorRanges, relay_static::RelayAddressGeneratorStatic,
+            RelayAddressGenerator,
+        },
+        server::{
+            config::{ConnConfig, ServerConfig},
+            Server,
+        },
+        Error as TurnError,
+    },
+    util::{vnet::net::Net, Conn},
+};
+
+use crate::config::{IceServerConfig, TurnConfig};
+
+// Embedded TURN server that relays media when both sides sit behind NAT.
+pub struct TurnRelay {
+    config: TurnConfig,
+    // Held so the listener lives as long as the bridge.
+    _server: Server,
+}
+
+impl TurnRelay {
+    pub async fn start(config: TurnConfig) -> Result<Self, String> {
+        let conn = UdpSocket::bind(("0.0.0.0", config.port))
+            .await
+            .map_err(|err| format!("turn bind failed port={}: {err}", config.port))?;
+        let relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync> =
+            match config.relay_port_range {
+                Some((min_port, max_port)) => Box::new(RelayAddressGeneratorRanges {
+                    relay_address: config.public_ip,
+                    min_port,
+                    max_port,
+                    max_retries: 10,
+                    address: "0.0.0.0".to_owned(),
+                    net: Arc::new(Net::new(None)),
+                }),
+                None => Box::new(RelayAddressGeneratorStatic {
+                    relay_address: config.public_ip,
+                    address: "0.0.0.0".to_owned(),
+                    net: Arc::new(Net::new(None)),
+                }),
+            };
+
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn: Arc::new(PeerFilterConn {
+                    inner: Arc::new(conn),
+                }),
+                relay_addr_generator,
+            }],
+            realm: config.realm.clone(),
+            auth_handler: Arc::new(TimeLimitedAuthHandler {
+                shared_secret: config.shared_secret.clone(),
+            }),
+            channel_bind_timeout: Duration::from_secs(0),
+            alloc_close_notify: None,
+        })
+        .await
+        .map_err(|err| format!("turn server start failed: {err}"))?;
+
+        info!(
+            "turn relay listening port={} public_ip={}",
+            config.port, config.public_ip
+        );
+        if config.client_token.is_some() {
+            info!("TURN_CLIENT_TOKEN set, turn credentials require a bearer token");
+        }
+        Ok(Self {
+            config,
+            _server: server,
+        })
+    }
+
+    // Every client gets credentials unless TURN_CLIENT_TOKEN restricts them
+    // to callers presenting it.
+    pub fn authorizes_client(&self, bearer_token: Option<&str>) -> bool {
+        self.config
+            .client_token
+            .as_deref()
+            .is_none_or(|token| bearer_token == Some(token))
+    }
+
+    // Mints TURN REST style credentials (`<expiry>:<peer>` + HMAC-SHA1) that
+    // stop working once the configured TTL elapses.
+    pub fn credentials_for(&self, peer_id: &str) -> IceServerConfig {
+        let expiry = unix_now() + self.config.credential_ttl.as_secs();
+        let username = format!("{expiry}:{peer_id}");
+        let credential = turn_password(&self.config.shared_secret, &username);
+        IceServerConfig {
+            urls: vec![format!(
+                "turn:{}:{}?transport=udp",
+                self.config.public_ip, self.config.port
+            )],
+            username,
+            credential,
+        }
+    }
+}
+
+struct TimeLimitedAuthHandler {
+    shared_secret: String,
+}
+
+impl AuthHandler for TimeLimitedAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, TurnError> {
+        let expiry = username
+            .split(':')
+            .next()
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| TurnError::Other(format!("malformed turn username {username}")))?;
+        if expiry < unix_now() {
+            return Err(TurnError::Other(format!(
+                "expired turn username {username}"
+            )));
+        }
+        let password = turn_password(&self.shared_secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+// The TURN listener with CreatePermission and ChannelBind requests for
+// private, loopback and other non-public peers answered 403 before the server
+// sees them. Without a permission no data is relayed to or from such a peer,
+// so credentials cannot be used to reach into the host's network.
+struct PeerFilterConn {
+    inner: Arc<UdpSocket>,
+}
+
+#[async_trait]
+impl Conn for PeerFilterConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc::util::Result<()> {
+        Conn::connect(&*self.inner, addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Conn::recv(&*self.inner, buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, src) = Conn::recv_from(&*self.inner, buf).await?;
+            let Some(request) = forbidden_peer_request(&buf[..len]) else {
+                return Ok((len, src));
+            };
+            warn!("turn peer rejected src={src} method={}", request.typ.method);
+            let mut response = Message::new();
+            let built = response.build(&[
+                Box::new(request.transaction_id),
+                Box::new(MessageType::new(request.typ.method, CLASS_ERROR_RESPONSE)),
+                Box::new(ErrorCodeAttribute {
+                    code: CODE_FORBIDDEN,
+                    reason: b"Forbidden".to_vec(),
+                }),
+            ]);
+            if built.is_ok() {
+                let _ = Conn::send_to(&*self.inner, &response.raw, src).await;
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc::util::Result<usize> {
+        Conn::send(&*self.inner, buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        Conn::send_to(&*self.inner, buf, target).await
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        Conn::local_addr(&*self.inner)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Conn::remote_addr(&*self.inner)
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        Conn::close(&*self.inner).await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+// The request, when `packet` asks for a permission or channel to a peer that
+// is not publicly routable.
+fn forbidden_peer_request(packet: &[u8]) -> Option<Message> {
+    if !is_message(packet) {
+        return None;
+    }
+    let mut message = Message::new();
+    message.raw = packet.to_vec();
+    message.decode().ok()?;
+    let method = message.typ.method;
+    if message.typ.class != CLASS_REQUEST
+        || (method != METHOD_CREATE_PERMISSION && method != METHOD_CHANNEL_BIND)
+    {
+        return None;
+    }
+    let forbidden = message
+        .attributes
+        .0
+        .iter()
+        .filter(|attribute| attribute.typ == ATTR_XOR_PEER_ADDRESS)
+        .any(|attribute| {
+            xor_peer_ip(&attribute.value, &message.transaction_id.0).is_none_or(|ip| !is_public(ip))
+        });
+    forbidden.then_some(message)
+}
+
+// XOR-PEER-ADDRESS: reserved byte, family, port, then the address XORed with
+// the magic cookie (IPv4) or the cookie followed by the transaction id (IPv6).
+fn xor_peer_ip(value: &[u8], transaction_id: &[u8; 12]) -> Option<IpAddr> {
+    let mut key = [0_u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(transaction_id);
+    let unxor = |address: &[u8]| -> Vec<u8> {
+        address
+            .iter()
+            .zip(key)
+            .map(|(byte, key)| byte ^ key)
+            .collect()
+    };
+    match (value.get(1), value.get(4..)) {
+        (Some(0x01), Some(address)) if address.len() == 4 => {
+            let octets: [u8; 4] = unxor(address).try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (Some(0x02), Some(address)) if address.len() == 16 => {
+            let octets: [u8; 16] = unxor(address).try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let shared = first == 100 && (64..128).contains(&second);
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || shared
+                || first == 0)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = first & 0xFE00 == 0xFC00;
+                let link_local = first & 0xFFC0 == 0xFE80;
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || unique_local
+                    || link_local)
+            }
+        },
+    }
+}
+
+fn turn_password(shared_secret: &str, username: &str) -> String {
+    let key = hmac::Key::new(
+        hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        shared_secret.as_bytes(),
+    );
+    BASE64_STANDARD.encode(hmac::sign(&key, username.as_bytes()).as_ref())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use webrtc::{
+    media::Sample,
+    rtp::{
+        codecs::h265::HevcPayloader,
+        packetizer::{new_packetizer, Packetizer},
+        sequence::new_random_sequencer,
+    },
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::{
+        track_local_static_rtp::TrackLocalStaticRTP,
+        track_local_static_sample::TrackLocalStaticSample, TrackLocal, TrackLocalWriter,
+    },
+};
+
+// Matches webrtc-rs's outbound MTU for sample tracks.
+const RTP_MTU: usize = 1200;
+
+// A viewer's outbound video track. webrtc-rs picks the payloader from the
+// negotiated MIME type, but only knows HEVC as `video/HEVC` while browsers
+// offer `H265`, so that codec is packetized here and written as RTP.
+pub enum VideoTrack {
+    Sample(Arc<TrackLocalStaticSample>),
+    Rtp {
+        track: Arc<TrackLocalStaticRTP>,
+        packetizer: StdMutex<Box<dyn Packetizer + Send + Sync>>,
+    },
+}
+
+impl VideoTrack {
+    pub fn new(codec: RTCRtpCodecCapability, id: String, stream_id: String) -> Self {
+        if !codec.mime_type.eq_ignore_ascii_case("video/H265") {
+            return Self::Sample(Arc::new(TrackLocalStaticSample::new(codec, id, stream_id)));
+        }
+        // Payload type and SSRC are rewritten per binding by the track.
+        let packetizer = new_packetizer(
+            RTP_MTU,
+            0,
+            0,
+            Box::<HevcPayloader>::default(),
+            Box::new(new_random_sequencer()),
+            codec.clock_rate,
+        );
+        Self::Rtp {
+            track: Arc::new(TrackLocalStaticRTP::new(codec, id, stream_id)),
+            packetizer: StdMutex::new(Box::new(packetizer)),
+        }
+    }
+
+    pub fn codec(&self) -> RTCRtpCodecCapability {
+        match self {
+            Self::Sample(track) => track.codec(),
+            Self::Rtp { track, .. } => track.codec(),
+        }
+    }
+
+    pub fn track_local(&self) -> Arc<dyn TrackLocal + Send + Sync> {
+        match self {
+            Self::Sample(track) => track.clone(),
+            Self::Rtp { track, .. } => track.clone(),
+        }
+    }
+
+    // Sends one frame; `duration` advances the RTP timestamp after it.
+    pub async fn write(&self, data: Bytes, duration: Duration) -> Result<(), String> {
+        match self {
+            Self::Sample(track) => track
+                .write_sample(&Sample {
+                    data,
+                    duration,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|err| format!("write_sample failed: {err}")),
+            Self::Rtp { track, packetizer } => {
+                let samples = (duration.as_secs_f64() * f64::from(track.codec().clock_rate)) as u32;
+                let packets = packetizer
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .packetize(&data, samples)
+                    .map_err(|err| format!("packetize failed: {err}"))?;
+                for packet in packets {
+                    track
+                        .write_rtp(&packet)
+                        .await
+                        .map_err(|err| format!("write_rtp failed: {err}"))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
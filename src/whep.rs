@@ -0,0 +1,126 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{info, warn};
+
+use crate::{
+    http_util::{has_content_type, ice_server_links},
+    ingest,
+    media_bridge::Signaling,
+    models::StreamRequest,
+    state::AppState,
+};
+
+// WHEP viewers are filed under one signaling session per `{source}`,
+// `whep:<source>`, with the resource id as peer id, so admin stream ids read
+// `whep:<source>:<resource id>`. A resource is only reachable through the
+// source it was created for, and a room named after a source is a session
+// of its own rather than where that source's WHEP viewers live.
+const WHEP_SESSION_PREFIX: &str = "whep:";
+pub const SDP_CONTENT_TYPE: &str = "application/sdp";
+pub const TRICKLE_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+// WHEP (WebRTC-HTTP Egress Protocol) viewers get the same stream setup as
+// polling clients. `source` names a WHIP room to play its publisher, or else
+// picks the capture; a publishing room shadows a capture source of the same
+// name. The answer carries all of the bot's candidates since WHEP has no way
+// to trickle them later; the `Location` header names the resource for
+// trickle (PATCH) and teardown (DELETE).
+pub async fn offer(state: AppState, source: String, headers: &HeaderMap, sdp: String) -> Response {
+    if !has_content_type(headers, SDP_CONTENT_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let resource_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 24);
+    let request = if state.media_bridge.is_publishing(&source).await {
+        StreamRequest {
+            source: Some(ingest::SOURCE_NAME.to_owned()),
+            room: Some(source.clone()),
+            ..StreamRequest::default()
+        }
+    } else {
+        StreamRequest {
+            source: Some(source.clone()),
+            ..StreamRequest::default()
+        }
+    };
+    let answer = state
+        .media_bridge
+        .handle_offer(
+            Signaling::Whep,
+            whep_session(&source),
+            resource_id.clone(),
+            sdp,
+            None,
+            request,
+        )
+        .await;
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(err) => {
+            warn!("whep offer failed source={source} error={err}");
+            return (StatusCode::BAD_REQUEST, err).into_response();
+        }
+    };
+    info!("whep viewer created source={source} resource={resource_id}");
+
+    let mut response = (StatusCode::CREATED, answer).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(SDP_CONTENT_TYPE),
+    );
+    if let Ok(location) = HeaderValue::from_str(&format!("/whep/{source}/{resource_id}")) {
+        response_headers.insert(header::LOCATION, location);
+    }
+    for link in ice_server_links(&state, &resource_id, headers) {
+        response_headers.append(header::LINK, link);
+    }
+    response
+}
+
+// Remote candidates arrive as an SDP fragment. ICE restarts are not
+// supported; a client wanting one starts a new resource instead.
+pub async fn trickle(
+    state: AppState,
+    source: String,
+    resource_id: String,
+    headers: &HeaderMap,
+    fragment: String,
+) -> Response {
+    if !has_content_type(headers, TRICKLE_CONTENT_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let session = whep_session(&source);
+    if !state.media_bridge.has_peer(&session, &resource_id).await {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match state
+        .media_bridge
+        .handle_remote_fragment(&session, &resource_id, &fragment)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            warn!("whep trickle failed source={source} resource={resource_id} error={err}");
+            (StatusCode::BAD_REQUEST, err).into_response()
+        }
+    }
+}
+
+pub async fn delete(state: AppState, source: String, resource_id: String) -> StatusCode {
+    if !state
+        .media_bridge
+        .close_peer(&whep_session(&source), &resource_id)
+        .await
+    {
+        return StatusCode::NOT_FOUND;
+    }
+    info!("whep viewer deleted source={source} resource={resource_id}");
+    StatusCode::OK
+}
+
+fn whep_session(source: &str) -> String {
+    format!("{WHEP_SESSION_PREFIX}{source}")
+}
@@ -0,0 +1,119 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{info, warn};
+
+use crate::{
+    http_util::{bearer_token, has_content_type, ice_server_links},
+    state::AppState,
+    whep::{SDP_CONTENT_TYPE, TRICKLE_CONTENT_TYPE},
+};
+
+// WHIP (WebRTC-HTTP Ingestion Protocol) lets an external encoder such as OBS
+// publish into a room, i.e. a signaling session; the room's viewers are then
+// sent the published stream instead of a local capture, and WHEP clients
+// play it at `/whep/<room>`. A room takes one publisher at a time.
+pub async fn offer(state: AppState, room: String, headers: &HeaderMap, sdp: String) -> Response {
+    if !authorized(&state, headers) {
+        return unauthorized();
+    }
+    if !has_content_type(headers, SDP_CONTENT_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    if state.media_bridge.is_publishing(&room).await {
+        return StatusCode::CONFLICT.into_response();
+    }
+    let resource_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 24);
+    let answer = match state
+        .media_bridge
+        .publish_ingest(&room, &resource_id, sdp)
+        .await
+    {
+        Ok(answer) => answer,
+        Err(err) => {
+            warn!("whip offer failed room={room} error={err}");
+            return (StatusCode::BAD_REQUEST, err).into_response();
+        }
+    };
+    info!("whip publisher created room={room} resource={resource_id}");
+
+    let mut response = (StatusCode::CREATED, answer).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(SDP_CONTENT_TYPE),
+    );
+    if let Ok(location) = HeaderValue::from_str(&format!("/whip/{room}/{resource_id}")) {
+        response_headers.insert(header::LOCATION, location);
+    }
+    for link in ice_server_links(&state, &resource_id, headers) {
+        response_headers.append(header::LINK, link);
+    }
+    response
+}
+
+pub async fn trickle(
+    state: AppState,
+    room: String,
+    resource_id: String,
+    headers: &HeaderMap,
+    fragment: String,
+) -> Response {
+    if !authorized(&state, headers) {
+        return unauthorized();
+    }
+    if !has_content_type(headers, TRICKLE_CONTENT_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    if !state.media_bridge.has_ingest(&room, &resource_id).await {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match state
+        .media_bridge
+        .trickle_ingest(&room, &resource_id, &fragment)
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            warn!("whip trickle failed room={room} resource={resource_id} error={err}");
+            (StatusCode::BAD_REQUEST, err).into_response()
+        }
+    }
+}
+
+pub async fn delete(
+    state: AppState,
+    room: String,
+    resource_id: String,
+    headers: &HeaderMap,
+) -> Response {
+    if !authorized(&state, headers) {
+        return unauthorized();
+    }
+    if !state
+        .media_bridge
+        .unpublish_ingest(&room, &resource_id)
+        .await
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    info!("whip publisher deleted room={room} resource={resource_id}");
+    StatusCode::OK.into_response()
+}
+
+// WHIP clients authenticate with `Authorization: Bearer <WHIP_TOKEN>`.
+fn authorized(state: &AppState, headers: &HeaderMap) -> bool {
+    state
+        .media_bridge
+        .authorizes_publisher(bearer_token(headers))
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+    )
+        .into_response()
+}